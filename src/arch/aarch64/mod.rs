@@ -2,35 +2,122 @@
 
 pub use armv8::aarch64::vm::granule4k::{IOAddr, PAddr, VAddr};
 
-use crate::pci::PCIAddress;
+use crate::pci::ecam::{decode_conf_address, EcamWindow, SharedEcamWindow};
+use crate::pci::{ConfigAccess, PCIAddress};
+
+/// The ECAM window used for config space accesses through [`PciInterface`].
+static ECAM_WINDOW: SharedEcamWindow = SharedEcamWindow::new();
+
+/// Configures the ECAM window used by [`PciInterface`] (and therefore
+/// `pci::scan_bus` and `PciDevice`), e.g., from the MCFG table or device tree.
+///
+/// Until a window is configured all functions read as absent.
+///
+/// # Safety
+/// - See [`EcamWindow::new`], the window must stay mapped forever.
+/// - Must not race with another call to `set_ecam_window`.
+pub unsafe fn set_ecam_window(window: EcamWindow) {
+    ECAM_WINDOW.set(window)
+}
+
+/// The ECAM window configured with [`set_ecam_window`].
+pub fn ecam_window() -> Option<EcamWindow> {
+    ECAM_WINDOW.get()
+}
 
 pub trait MsrInterface {
-    unsafe fn write(&mut self, msr: u32, value: u64) {
-        panic!("NYI!");
+    /// There are no MSRs on aarch64 (system registers are addressed by
+    /// instruction encoding, not by number).
+    unsafe fn write(&mut self, _msr: u32, _value: u64) {
+        panic!("MSRs are not available on aarch64");
     }
-    unsafe fn read(&mut self, msr: u32) -> u64 {
-        panic!("NYI!");
+
+    /// See [`MsrInterface::write`].
+    unsafe fn read(&mut self, _msr: u32) -> u64 {
+        panic!("MSRs are not available on aarch64");
     }
 }
 
+/// Config space access through the ECAM window configured with
+/// [`set_ecam_window`].
+///
+/// `addr` is a legacy configuration address (see [`PCIAddress::addr`]),
+/// dword accesses ignore its low two bits like the port IO mechanism. Every
+/// access is a single load or store to the window, so unlike the port IO
+/// mechanism on x86 no serialization is needed.
 pub trait PciInterface {
     const PCI_CONF_ADDR: u16 = 0xcf8;
     const PCI_CONF_DATA: u16 = 0xcfc;
 
     fn read(&self, addr: u32) -> u32 {
-        panic!("NYI!");
+        let (function, offset) = decode_conf_address(addr);
+        ecam_window().map_or(u32::MAX, |w| w.read(function, offset & !0b11))
     }
+
     fn write(&mut self, addr: u32, value: u32) {
-        panic!("NYI!");
+        let (function, offset) = decode_conf_address(addr);
+        if let Some(w) = ecam_window() {
+            w.write(function, offset & !0b11, value)
+        }
+    }
+
+    fn read_u8(&self, addr: u32) -> u8 {
+        let (function, offset) = decode_conf_address(addr);
+        ecam_window().map_or(u8::MAX, |w| w.read_u8(function, offset))
+    }
+
+    /// Reads the word at `addr` (which must be 2-byte aligned).
+    fn read_u16(&self, addr: u32) -> u16 {
+        let (function, offset) = decode_conf_address(addr);
+        ecam_window().map_or(u16::MAX, |w| w.read_u16(function, offset))
+    }
+
+    fn write_u8(&mut self, addr: u32, value: u8) {
+        let (function, offset) = decode_conf_address(addr);
+        if let Some(w) = ecam_window() {
+            w.write_u8(function, offset, value)
+        }
+    }
+
+    /// Writes the word at `addr` (which must be 2-byte aligned).
+    fn write_u16(&mut self, addr: u32, value: u16) {
+        let (function, offset) = decode_conf_address(addr);
+        if let Some(w) = ecam_window() {
+            w.write_u16(function, offset, value)
+        }
     }
 }
 
+/// Accesses the config space of the function, `offset` is the register
+/// offset instead of the full configuration address.
 impl PciInterface for PCIAddress {
     fn read(&self, offset: u32) -> u32 {
-        panic!("NYI!");
+        ecam_window().map_or(u32::MAX, |w| w.read(*self, offset & !0b11))
     }
 
     fn write(&mut self, offset: u32, value: u32) {
-        panic!("NYI!");
+        if let Some(w) = ecam_window() {
+            w.write(*self, offset & !0b11, value)
+        }
+    }
+
+    fn read_u8(&self, offset: u32) -> u8 {
+        ecam_window().map_or(u8::MAX, |w| w.read_u8(*self, offset))
+    }
+
+    fn read_u16(&self, offset: u32) -> u16 {
+        ecam_window().map_or(u16::MAX, |w| w.read_u16(*self, offset))
+    }
+
+    fn write_u8(&mut self, offset: u32, value: u8) {
+        if let Some(w) = ecam_window() {
+            w.write_u8(*self, offset, value)
+        }
+    }
+
+    fn write_u16(&mut self, offset: u32, value: u16) {
+        if let Some(w) = ecam_window() {
+            w.write_u16(*self, offset, value)
+        }
     }
 }
@@ -4,6 +4,11 @@ extern crate x86;
 
 pub use x86::current::paging::{IOAddr, PAddr, VAddr};
 
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use spin::Mutex;
+
 use crate::pci::PCIAddress;
 
 pub trait MsrInterface {
@@ -26,41 +31,315 @@ pub trait MsrInterface {
     }
 }
 
+/// Port of the legacy configuration mechanism's address register.
+const PCI_CONF_ADDR: u16 = 0xcf8;
+/// Port of the legacy configuration mechanism's data register.
+const PCI_CONF_DATA: u16 = 0xcfc;
+
+/// Serializes accesses to the address/data port pair: another CPU writing
+/// `PCI_CONF_ADDR` between our write and the data access would redirect it
+/// to a different register.
+static CONF_LOCK: Mutex<()> = Mutex::new(());
+
+static CONF_LOCK_HOOKS: AtomicPtr<ConfLockHooks> = AtomicPtr::new(ptr::null_mut());
+
+/// Functions called around every config space access with the lock held in
+/// between.
+///
+/// Kernels that access config space from interrupt handlers should disable
+/// interrupts in `enter` (and restore them in `exit`), otherwise a handler
+/// interrupting an access spins forever on the lock.
+#[derive(Debug)]
+pub struct ConfLockHooks {
+    /// Called before the lock is taken, the result is passed to `exit`.
+    pub enter: fn() -> usize,
+    /// Called after the lock is released.
+    pub exit: fn(usize),
+}
+
+/// Installs `hooks` for all subsequent config space accesses through
+/// [`PciInterface`].
+pub fn set_conf_lock_hooks(hooks: &'static ConfLockHooks) {
+    CONF_LOCK_HOOKS.store(hooks as *const _ as *mut _, Ordering::Release);
+}
+
+/// The port IO instructions used by [`ConfMechanism`] (replaced by a fake in
+/// tests).
+trait PortIo {
+    fn inb(&self, port: u16) -> u8;
+    fn inw(&self, port: u16) -> u16;
+    fn inl(&self, port: u16) -> u32;
+    fn outb(&self, port: u16, value: u8);
+    fn outw(&self, port: u16, value: u16);
+    fn outl(&self, port: u16, value: u32);
+}
+
+/// The CPU's IO ports.
+struct CpuPorts;
+
+// Safety (for all of them): Only used on the config address/data ports.
+impl PortIo for CpuPorts {
+    fn inb(&self, port: u16) -> u8 {
+        unsafe { x86::io::inb(port) }
+    }
+
+    fn inw(&self, port: u16) -> u16 {
+        unsafe { x86::io::inw(port) }
+    }
+
+    fn inl(&self, port: u16) -> u32 {
+        unsafe { x86::io::inl(port) }
+    }
+
+    fn outb(&self, port: u16, value: u8) {
+        unsafe { x86::io::outb(port, value) }
+    }
+
+    fn outw(&self, port: u16, value: u16) {
+        unsafe { x86::io::outw(port, value) }
+    }
+
+    fn outl(&self, port: u16, value: u32) {
+        unsafe { x86::io::outl(port, value) }
+    }
+}
+
+/// The legacy configuration mechanism on the address/data port pair
+/// `addr_port`/`data_port`.
+///
+/// Dword accesses use `data_port` (the dword containing `addr`), sub-dword
+/// accesses use the port of the addressed bytes (`data_port + (addr & 3)`),
+/// so they neither require nor disturb the rest of the dword.
+struct ConfMechanism<P> {
+    ports: P,
+    addr_port: u16,
+    data_port: u16,
+}
+
+impl<P: PortIo> ConfMechanism<P> {
+    /// Selects the dword containing `addr` and runs `f` on the ports, all
+    /// while holding the config lock.
+    fn with_conf_addr<R>(&self, addr: u32, f: impl FnOnce(&P) -> R) -> R {
+        // Safety: Only ever set from a `&'static`.
+        let hooks = unsafe { CONF_LOCK_HOOKS.load(Ordering::Acquire).as_ref() };
+        let state = hooks.map(|h| (h.enter)());
+
+        let result = {
+            let _guard = CONF_LOCK.lock();
+            self.ports.outl(self.addr_port, addr & !0b11);
+            f(&self.ports)
+        };
+
+        if let (Some(hooks), Some(state)) = (hooks, state) {
+            (hooks.exit)(state);
+        }
+        result
+    }
+
+    /// The data port of the bytes at `addr`.
+    fn byte_port(&self, addr: u32) -> u16 {
+        self.data_port + (addr & 0b11) as u16
+    }
+
+    fn read(&self, addr: u32) -> u32 {
+        self.with_conf_addr(addr, |io| io.inl(self.data_port))
+    }
+
+    fn write(&self, addr: u32, value: u32) {
+        self.with_conf_addr(addr, |io| io.outl(self.data_port, value))
+    }
+
+    fn read_u8(&self, addr: u32) -> u8 {
+        self.with_conf_addr(addr, |io| io.inb(self.byte_port(addr)))
+    }
+
+    fn read_u16(&self, addr: u32) -> u16 {
+        self.with_conf_addr(addr, |io| io.inw(self.byte_port(addr)))
+    }
+
+    fn write_u8(&self, addr: u32, value: u8) {
+        self.with_conf_addr(addr, |io| io.outb(self.byte_port(addr), value))
+    }
+
+    fn write_u16(&self, addr: u32, value: u16) {
+        self.with_conf_addr(addr, |io| io.outw(self.byte_port(addr), value))
+    }
+}
+
+/// Config space access with the legacy configuration mechanism (port IO) on
+/// the ports `PCI_CONF_ADDR`/`PCI_CONF_DATA`.
+///
+/// `addr` is a configuration address (see [`PCIAddress::addr`]) including
+/// the register offset. Dword accesses ignore the low two bits of `addr`,
+/// sub-dword accesses use the data port of the addressed bytes
+/// (`PCI_CONF_DATA + (addr & 3)`).
 pub trait PciInterface {
-    const PCI_CONF_ADDR: u16 = 0xcf8;
-    const PCI_CONF_DATA: u16 = 0xcfc;
+    const PCI_CONF_ADDR: u16 = PCI_CONF_ADDR;
+    const PCI_CONF_DATA: u16 = PCI_CONF_DATA;
 
     fn read(&self, addr: u32) -> u32 {
-        unsafe {
-            x86::io::outl(Self::PCI_CONF_ADDR, addr);
-            x86::io::inl(Self::PCI_CONF_DATA)
-        }
+        conf_mechanism::<Self>().read(addr)
     }
 
     fn write(&mut self, addr: u32, value: u32) {
-        unsafe {
-            x86::io::outl(Self::PCI_CONF_ADDR, addr);
-            x86::io::outl(Self::PCI_CONF_DATA, value);
-        }
+        conf_mechanism::<Self>().write(addr, value)
+    }
+
+    fn read_u8(&self, addr: u32) -> u8 {
+        conf_mechanism::<Self>().read_u8(addr)
+    }
+
+    /// Reads the word at `addr` (which must be 2-byte aligned).
+    fn read_u16(&self, addr: u32) -> u16 {
+        conf_mechanism::<Self>().read_u16(addr)
+    }
+
+    fn write_u8(&mut self, addr: u32, value: u8) {
+        conf_mechanism::<Self>().write_u8(addr, value)
+    }
+
+    /// Writes the word at `addr` (which must be 2-byte aligned).
+    fn write_u16(&mut self, addr: u32, value: u16) {
+        conf_mechanism::<Self>().write_u16(addr, value)
     }
 }
 
+/// The configuration mechanism on the ports of `T`.
+fn conf_mechanism<T: PciInterface + ?Sized>() -> ConfMechanism<CpuPorts> {
+    ConfMechanism {
+        ports: CpuPorts,
+        addr_port: T::PCI_CONF_ADDR,
+        data_port: T::PCI_CONF_DATA,
+    }
+}
+
+/// Accesses the config space of the function, `offset` is the register
+/// offset instead of the full configuration address.
 impl PciInterface for PCIAddress {
     fn read(&self, offset: u32) -> u32 {
-        let addr = self.addr() | offset;
+        conf_mechanism::<Self>().read(self.addr() | offset)
+    }
+
+    fn write(&mut self, offset: u32, value: u32) {
+        conf_mechanism::<Self>().write(self.addr() | offset, value)
+    }
+
+    fn read_u8(&self, offset: u32) -> u8 {
+        conf_mechanism::<Self>().read_u8(self.addr() | offset)
+    }
+
+    fn read_u16(&self, offset: u32) -> u16 {
+        conf_mechanism::<Self>().read_u16(self.addr() | offset)
+    }
+
+    fn write_u8(&mut self, offset: u32, value: u8) {
+        conf_mechanism::<Self>().write_u8(self.addr() | offset, value)
+    }
+
+    fn write_u16(&mut self, offset: u32, value: u16) {
+        conf_mechanism::<Self>().write_u16(self.addr() | offset, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+    use core::sync::atomic::AtomicUsize;
+
+    /// Records `(port, value)` of every port access (reads record zero) and
+    /// whether the config lock was held.
+    #[derive(Default)]
+    struct FakePorts {
+        accesses: RefCell<Vec<(u16, u32)>>,
+        unlocked: RefCell<bool>,
+    }
 
-        unsafe {
-            x86::io::outl(<Self as PciInterface>::PCI_CONF_ADDR, addr);
-            x86::io::inl(<Self as PciInterface>::PCI_CONF_DATA)
+    impl FakePorts {
+        fn record(&self, port: u16, value: u32) {
+            *self.unlocked.borrow_mut() |= !CONF_LOCK.is_locked();
+            self.accesses.borrow_mut().push((port, value));
         }
     }
 
-    fn write(&mut self, offset: u32, value: u32) {
-        let addr = self.addr() | offset;
+    impl PortIo for FakePorts {
+        fn inb(&self, port: u16) -> u8 {
+            self.record(port, 0);
+            0xab
+        }
 
-        unsafe {
-            x86::io::outl(<Self as PciInterface>::PCI_CONF_ADDR, addr);
-            x86::io::outl(<Self as PciInterface>::PCI_CONF_DATA, value);
+        fn inw(&self, port: u16) -> u16 {
+            self.record(port, 0);
+            0xabcd
         }
+
+        fn inl(&self, port: u16) -> u32 {
+            self.record(port, 0);
+            0x1234_5678
+        }
+
+        fn outb(&self, port: u16, value: u8) {
+            self.record(port, value.into())
+        }
+
+        fn outw(&self, port: u16, value: u16) {
+            self.record(port, value.into())
+        }
+
+        fn outl(&self, port: u16, value: u32) {
+            self.record(port, value)
+        }
+    }
+
+    static ENTERED: AtomicUsize = AtomicUsize::new(0);
+    static EXITED: AtomicUsize = AtomicUsize::new(0);
+
+    static HOOKS: ConfLockHooks = ConfLockHooks {
+        enter: || ENTERED.fetch_add(1, Ordering::Relaxed) + 42,
+        exit: |state| {
+            assert_eq!(state, EXITED.fetch_add(1, Ordering::Relaxed) + 42);
+            assert!(!CONF_LOCK.is_locked());
+        },
+    };
+
+    #[test]
+    fn port_selection() {
+        set_conf_lock_hooks(&HOOKS);
+        let conf = ConfMechanism {
+            ports: FakePorts::default(),
+            addr_port: PCI_CONF_ADDR,
+            data_port: PCI_CONF_DATA,
+        };
+        let addr = PCIAddress::new(1, 2, 3).unwrap().addr();
+
+        assert_eq!(conf.read(addr | 0x3e), 0x1234_5678);
+        conf.write(addr | 0x11, 7);
+        assert_eq!(conf.read_u8(addr | 0x3d), 0xab);
+        assert_eq!(conf.read_u16(addr | 0x06), 0xabcd);
+        conf.write_u8(addr | 0x3c, 0xb);
+        conf.write_u16(addr | 0x06, 0xffff);
+
+        assert_eq!(
+            *conf.ports.accesses.borrow(),
+            vec![
+                (0xcf8, addr | 0x3c),
+                (0xcfc, 0),
+                (0xcf8, addr | 0x10),
+                (0xcfc, 7),
+                (0xcf8, addr | 0x3c),
+                (0xcfd, 0),
+                (0xcf8, addr | 0x04),
+                (0xcfe, 0),
+                (0xcf8, addr | 0x3c),
+                (0xcfc, 0xb),
+                (0xcf8, addr | 0x04),
+                (0xcfe, 0xffff),
+            ]
+        );
+        assert!(!*conf.ports.unlocked.borrow());
+        assert_eq!(ENTERED.load(Ordering::Relaxed), 6);
+        assert_eq!(EXITED.load(Ordering::Relaxed), 6);
     }
 }
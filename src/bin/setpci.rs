@@ -0,0 +1,443 @@
+//! Reads and writes PCI config space registers, similar to `setpci`.
+//!
+//! Config space is accessed through Linux sysfs (writes and reads beyond the
+//! first 64 bytes require root) or a dump file in the format of `lspci -x`.
+
+extern crate driverkit;
+
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::Arc;
+
+use driverkit::pci::dump::ConfigDump;
+use driverkit::pci::{CapabilityId, ConfigAccessRef, PCIAddress, PciDevice};
+use driverkit::sysfs::SysfsAccess;
+
+const USAGE: &str = "\
+Usage: setpci [OPTIONS] (-s SLOT | -d [VENDOR]:[DEVICE]) OPERATION...
+
+Reads or writes config space registers of all matching devices.
+
+Options:
+  -s SLOT        Select devices by [[[DOMAIN]:]BUS]:][DEV][.[FUN]] (hex, empty or * matches any)
+  -d [V]:[D]     Select devices by vendor and/or device id (hex)
+  -v             Print the device and register for every access
+  -D             Dry run, don't write anything
+  --dump F       Use config space from F (output of `lspci -x`) instead of sysfs
+  --sysfs D      Read devices from sysfs directory D (default: /sys/bus/pci/devices)
+  -h, --help     Print this help
+
+Operations:
+  REG[+OFF][.W]                  Read a register
+  REG[+OFF][.W]=VALUE[:MASK]     Write a register (only the bits set in MASK)
+
+  REG is a register name (e.g., COMMAND, STATUS, BAR0, INTERRUPT_LINE), a hex
+  offset, CAP_<NAME> (e.g., CAP_EXP, CAP_MSIX) or CAP<ID> for the start of a
+  capability. The width W is B (byte), W (word) or L (dword), it is required
+  unless REG is a named register. All numbers are in hex.";
+
+/// Named registers: name, offset and width in bytes.
+const REGISTERS: &[(&str, u32, u32)] = &[
+    ("VENDOR_ID", 0x00, 2),
+    ("DEVICE_ID", 0x02, 2),
+    ("COMMAND", 0x04, 2),
+    ("STATUS", 0x06, 2),
+    ("REVISION", 0x08, 1),
+    ("CLASS_PROG", 0x09, 1),
+    ("CLASS_DEVICE", 0x0a, 2),
+    ("CACHE_LINE_SIZE", 0x0c, 1),
+    ("LATENCY_TIMER", 0x0d, 1),
+    ("HEADER_TYPE", 0x0e, 1),
+    ("BIST", 0x0f, 1),
+    ("BASE_ADDRESS_0", 0x10, 4),
+    ("BASE_ADDRESS_1", 0x14, 4),
+    ("BASE_ADDRESS_2", 0x18, 4),
+    ("BASE_ADDRESS_3", 0x1c, 4),
+    ("BASE_ADDRESS_4", 0x20, 4),
+    ("BASE_ADDRESS_5", 0x24, 4),
+    ("BAR0", 0x10, 4),
+    ("BAR1", 0x14, 4),
+    ("BAR2", 0x18, 4),
+    ("BAR3", 0x1c, 4),
+    ("BAR4", 0x20, 4),
+    ("BAR5", 0x24, 4),
+    ("CARDBUS_CIS", 0x28, 4),
+    ("SUBSYSTEM_VENDOR_ID", 0x2c, 2),
+    ("SUBSYSTEM_ID", 0x2e, 2),
+    ("ROM_ADDRESS", 0x30, 4),
+    ("CAPABILITIES", 0x34, 1),
+    ("INTERRUPT_LINE", 0x3c, 1),
+    ("INTERRUPT_PIN", 0x3d, 1),
+    ("MIN_GNT", 0x3e, 1),
+    ("MAX_LAT", 0x3f, 1),
+    // Type 1 (bridge) header
+    ("PRIMARY_BUS", 0x18, 1),
+    ("SECONDARY_BUS", 0x19, 1),
+    ("SUBORDINATE_BUS", 0x1a, 1),
+    ("SEC_LATENCY_TIMER", 0x1b, 1),
+    ("IO_BASE", 0x1c, 1),
+    ("IO_LIMIT", 0x1d, 1),
+    ("SEC_STATUS", 0x1e, 2),
+    ("MEMORY_BASE", 0x20, 2),
+    ("MEMORY_LIMIT", 0x22, 2),
+    ("PREF_MEMORY_BASE", 0x24, 2),
+    ("PREF_MEMORY_LIMIT", 0x26, 2),
+    ("PREF_BASE_UPPER32", 0x28, 4),
+    ("PREF_LIMIT_UPPER32", 0x2c, 4),
+    ("IO_BASE_UPPER16", 0x30, 2),
+    ("IO_LIMIT_UPPER16", 0x32, 2),
+    ("BRIDGE_ROM_ADDRESS", 0x38, 4),
+    ("BRIDGE_CONTROL", 0x3e, 2),
+];
+
+/// Capability names (as used by pciutils) and their ID.
+const CAPABILITIES: &[(&str, u8)] = &[
+    ("PM", 0x01),
+    ("AGP", 0x02),
+    ("VPD", 0x03),
+    ("SLOTID", 0x04),
+    ("MSI", 0x05),
+    ("CHSWP", 0x06),
+    ("PCIX", 0x07),
+    ("HT", 0x08),
+    ("VNDR", 0x09),
+    ("DBG", 0x0a),
+    ("CCRC", 0x0b),
+    ("HOTPLUG", 0x0c),
+    ("SSVID", 0x0d),
+    ("AGP3", 0x0e),
+    ("SECURE", 0x0f),
+    ("EXP", 0x10),
+    ("MSIX", 0x11),
+    ("SATA", 0x12),
+    ("AF", 0x13),
+    ("EA", 0x14),
+    ("FPB", 0x15),
+];
+
+fn parse_hex<T: TryFrom<u64>>(s: &str, what: &str) -> Result<T, String> {
+    let digits = s.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(digits, 16)
+        .ok()
+        .and_then(|v| T::try_from(v).ok())
+        .ok_or_else(|| format!("invalid {} '{}'", what, s))
+}
+
+/// Parses an optional hex field, empty or `*` means any value.
+fn parse_wildcard<T: TryFrom<u64>>(s: &str, what: &str) -> Result<Option<T>, String> {
+    if s.is_empty() || s == "*" {
+        Ok(None)
+    } else {
+        parse_hex(s, what).map(Some)
+    }
+}
+
+/// Device selection, `None` fields match any device.
+#[derive(Debug, Default)]
+struct Selector {
+    domain: Option<u16>,
+    bus: Option<u8>,
+    dev: Option<u8>,
+    fun: Option<u8>,
+    vendor_id: Option<u16>,
+    device_id: Option<u16>,
+}
+
+impl Selector {
+    /// Parses `[[[[DOMAIN]:]BUS]:][DEV][.[FUN]]`.
+    fn parse_slot(&mut self, s: &str) -> Result<(), String> {
+        let (rest, fun) = match s.split_once('.') {
+            Some((rest, fun)) => (rest, fun),
+            None => (s, ""),
+        };
+        self.fun = parse_wildcard(fun, "function")?;
+
+        let mut parts = rest.rsplit(':');
+        self.dev = parse_wildcard(parts.next().unwrap_or(""), "device")?;
+        self.bus = parse_wildcard(parts.next().unwrap_or(""), "bus")?;
+        self.domain = parse_wildcard(parts.next().unwrap_or(""), "domain")?;
+        if parts.next().is_some() {
+            return Err(format!("invalid slot '{}'", s));
+        }
+        Ok(())
+    }
+
+    /// Parses `[VENDOR]:[DEVICE]`.
+    fn parse_ids(&mut self, s: &str) -> Result<(), String> {
+        let (vendor, device) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid vendor:device '{}'", s))?;
+        self.vendor_id = parse_wildcard(vendor, "vendor id")?;
+        self.device_id = parse_wildcard(device, "device id")?;
+        Ok(())
+    }
+
+    fn matches(&self, dev: &PciDevice) -> bool {
+        let addr = dev.pci_address();
+        self.domain.map_or(true, |d| d == addr.segment)
+            && self.bus.map_or(true, |b| b == addr.bus)
+            && self.dev.map_or(true, |d| d == addr.dev)
+            && self.fun.map_or(true, |f| f == addr.fun)
+            && self.vendor_id.map_or(true, |v| v == dev.vendor_id())
+            && self.device_id.map_or(true, |d| d == dev.device_id())
+    }
+}
+
+/// Where a register is located.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Base {
+    /// Offset from the start of config space.
+    Header(u32),
+    /// Offset from the start of the first capability with the given ID.
+    Capability(u8),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Operation {
+    base: Base,
+    offset: u32,
+    width: u32,
+    /// Value and mask to write, `None` for reads.
+    write: Option<(u32, u32)>,
+}
+
+impl Operation {
+    fn parse(s: &str) -> Result<Operation, String> {
+        let (reg, write) = match s.split_once('=') {
+            Some((reg, value)) => (reg, Some(value)),
+            None => (s, None),
+        };
+
+        let (reg, width) = match reg.rsplit_once('.') {
+            Some((reg, width)) => {
+                let width = match width {
+                    "B" | "b" => 1,
+                    "W" | "w" => 2,
+                    "L" | "l" => 4,
+                    _ => return Err(format!("invalid width '{}'", width)),
+                };
+                (reg, Some(width))
+            }
+            None => (reg, None),
+        };
+
+        let (name, offset) = match reg.split_once('+') {
+            Some((name, offset)) => (name, parse_hex(offset, "offset")?),
+            None => (reg, 0),
+        };
+
+        let upper = name.to_ascii_uppercase();
+        let (base, named_width) = if let Some(&(_, off, w)) =
+            REGISTERS.iter().find(|(n, _, _)| *n == upper)
+        {
+            (Base::Header(off), Some(w))
+        } else if let Some(cap) = upper.strip_prefix("CAP_") {
+            let id = CAPABILITIES
+                .iter()
+                .find(|(n, _)| *n == cap)
+                .map(|(_, id)| *id)
+                .ok_or_else(|| format!("unknown capability '{}'", name))?;
+            (Base::Capability(id), None)
+        } else if let Some(id) = upper.strip_prefix("CAP") {
+            (Base::Capability(parse_hex(id, "capability id")?), None)
+        } else {
+            (Base::Header(parse_hex(name, "register")?), None)
+        };
+
+        // A named register only implies its width when accessed as a whole.
+        let implied_width = if offset == 0 { named_width } else { None };
+        let width = width
+            .or(implied_width)
+            .ok_or_else(|| format!("missing width for '{}'", reg))?;
+
+        let write = match write {
+            Some(value) => {
+                let (value, mask) = match value.split_once(':') {
+                    Some((value, mask)) => (value, parse_hex(mask, "mask")?),
+                    None => (value, u32::MAX),
+                };
+                let value: u32 = parse_hex(value, "value")?;
+                if width < 4 && value >> (width * 8) != 0 {
+                    return Err(format!("value '{:x}' is too large for width {}", value, width));
+                }
+                Some((value, mask))
+            }
+            None => None,
+        };
+
+        Ok(Operation {
+            base,
+            offset,
+            width,
+            write,
+        })
+    }
+
+    /// Resolves the offset in config space of `dev`.
+    fn resolve(&self, dev: &PciDevice) -> Result<u32, String> {
+        let start = match self.base {
+            Base::Header(offset) => offset,
+            Base::Capability(id) => dev
+                .capabilities()
+                .find(|cap| cap.id == CapabilityId::from(id))
+                .map(|cap| cap.offset as u32)
+                .ok_or_else(|| format!("capability {:#04x} not found", id))?,
+        };
+
+        let offset = start
+            .checked_add(self.offset)
+            .ok_or_else(|| format!("offset {:#x} is outside config space", self.offset))?;
+        if offset % self.width != 0 {
+            return Err(format!("unaligned access at {:#x}", offset));
+        }
+        if offset > 4096 - self.width {
+            return Err(format!("offset {:#x} is outside config space", offset));
+        }
+        Ok(offset)
+    }
+}
+
+fn read(dev: &PciDevice, offset: u32, width: u32) -> u32 {
+    match width {
+        1 => dev.read_config_u8(offset) as u32,
+        2 => dev.read_config_u16(offset) as u32,
+        _ => dev.read_config(offset),
+    }
+}
+
+fn write(dev: &mut PciDevice, offset: u32, width: u32, value: u32) {
+    match width {
+        1 => dev.write_config_u8(offset, value as u8),
+        2 => dev.write_config_u16(offset, value as u16),
+        _ => dev.write_config(offset, value),
+    }
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    selector: Selector,
+    selected: bool,
+    verbose: bool,
+    dry_run: bool,
+    dump: Option<String>,
+    sysfs: Option<String>,
+    operations: Vec<Operation>,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut opts = Options::default();
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("option {} requires an argument", name))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                "-s" => {
+                    opts.selector.parse_slot(&value(&arg)?)?;
+                    opts.selected = true;
+                }
+                "-d" => {
+                    opts.selector.parse_ids(&value(&arg)?)?;
+                    opts.selected = true;
+                }
+                "-v" => opts.verbose = true,
+                "-D" => opts.dry_run = true,
+                "--dump" => opts.dump = Some(value(&arg)?),
+                "--sysfs" => opts.sysfs = Some(value(&arg)?),
+                op if !op.starts_with('-') => opts.operations.push(Operation::parse(op)?),
+                _ => return Err(format!("unknown option {}", arg)),
+            }
+        }
+
+        if !opts.selected {
+            return Err(String::from("no devices selected (use -s or -d)"));
+        }
+        if opts.operations.is_empty() {
+            return Err(String::from("no operations given"));
+        }
+        Ok(opts)
+    }
+}
+
+fn devices(opts: &Options) -> Result<Vec<PciDevice>, String> {
+    let (access, addresses): (ConfigAccessRef, Vec<PCIAddress>) = match &opts.dump {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            let dump = ConfigDump::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+            let addresses = dump.addresses().collect();
+            (Arc::new(dump), addresses)
+        }
+        None => {
+            let root = opts.sysfs.as_deref().unwrap_or(SysfsAccess::DEFAULT_ROOT);
+            let sysfs = SysfsAccess::new(root);
+            let addresses = sysfs.addresses().map_err(|e| format!("{}: {}", root, e))?;
+            (Arc::new(sysfs), addresses)
+        }
+    };
+
+    Ok(addresses
+        .into_iter()
+        .filter_map(|addr| PciDevice::with_access(addr, access.clone()))
+        .filter(|dev| opts.selector.matches(dev))
+        .collect())
+}
+
+fn run() -> Result<(), String> {
+    let opts = Options::parse()?;
+    let mut devices = devices(&opts)?;
+    if devices.is_empty() {
+        return Err(String::from("no devices match"));
+    }
+
+    for dev in devices.iter_mut() {
+        let addr = dev.pci_address();
+        let slot = addr.to_string();
+
+        for op in opts.operations.iter() {
+            let offset = op.resolve(dev).map_err(|e| format!("{}: {}", slot, e))?;
+            let digits = op.width as usize * 2;
+
+            match op.write {
+                None => {
+                    let value = read(dev, offset, op.width);
+                    if opts.verbose {
+                        println!("{} @{:02x} = {:0w$x}", slot, offset, value, w = digits);
+                    } else {
+                        println!("{:0w$x}", value, w = digits);
+                    }
+                }
+                Some((value, mask)) => {
+                    let value = if mask != u32::MAX {
+                        (read(dev, offset, op.width) & !mask) | (value & mask)
+                    } else {
+                        value
+                    };
+                    if opts.verbose {
+                        println!("{} @{:02x} <- {:0w$x}", slot, offset, value, w = digits);
+                    }
+                    if !opts.dry_run {
+                        write(dev, offset, op.width, value);
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn main() {
+    if let Err(e) = run() {
+        eprintln!("setpci: {}", e);
+        process::exit(1);
+    }
+}
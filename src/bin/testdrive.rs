@@ -1,6 +1,598 @@
+//! Lists the PCI devices of a machine, similar to `lspci`.
+//!
+//! Config space is read from Linux sysfs (no privileges required for the
+//! basic listing) or from a dump file in the format of `lspci -x`.
+
 extern crate driverkit;
 
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::process;
+use std::sync::Arc;
+
+use driverkit::pci::dump::ConfigDump;
+use driverkit::pci::{
+    device_db, Capability, CapabilityId, CapabilityType, ConfigAccessRef, PCIAddress, PciDevice,
+    PciDeviceType,
+};
+use driverkit::sysfs::{SysfsAccess, SysfsResource};
+
+const USAGE: &str = "\
+Usage: testdrive [OPTIONS]
+
+Lists all PCI devices.
+
+Options:
+  -v            Show BARs, capabilities and MSI-X details (-vv for more)
+  -x            Show hex dump of config space (-xxx: 256 bytes, -xxxx: 4096 bytes)
+  -j, --json    Print devices as JSON
+  -d, --dump F  Read config space from F (output of `lspci -x`) instead of sysfs
+  -s, --sysfs D Read devices from sysfs directory D (default: /sys/bus/pci/devices)
+  -h, --help    Print this help";
+
+/// Config space of a conventional PCI function.
+const CONFIG_SPACE_SIZE: usize = 256;
+
+#[derive(Debug, Default)]
+struct Options {
+    verbose: usize,
+    hex: usize,
+    json: bool,
+    dump: Option<String>,
+    sysfs: Option<String>,
+}
+
+impl Options {
+    fn parse() -> Result<Options, String> {
+        let mut opts = Options::default();
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("option {} requires an argument", name))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    process::exit(0);
+                }
+                "-j" | "--json" => opts.json = true,
+                "-d" | "--dump" => opts.dump = Some(value(&arg)?),
+                "-s" | "--sysfs" => opts.sysfs = Some(value(&arg)?),
+                flags if flags.starts_with('-') && !flags.starts_with("--") => {
+                    for flag in flags.chars().skip(1) {
+                        match flag {
+                            'v' => opts.verbose += 1,
+                            'x' => opts.hex += 1,
+                            'j' => opts.json = true,
+                            _ => return Err(format!("unknown option -{}", flag)),
+                        }
+                    }
+                }
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        Ok(opts)
+    }
+
+    /// Number of config space bytes to dump.
+    fn hex_len(&self) -> usize {
+        match self.hex {
+            0 => 0,
+            1 => 64,
+            2 | 3 => 256,
+            _ => 4096,
+        }
+    }
+}
+
+/// Where config space is read from.
+enum Source {
+    Sysfs(Arc<SysfsAccess>),
+    Dump(Arc<ConfigDump>),
+}
+
+impl Source {
+    fn new(opts: &Options) -> Result<Source, String> {
+        if let Some(path) = &opts.dump {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            let dump = ConfigDump::parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+            Ok(Source::Dump(Arc::new(dump)))
+        } else {
+            let root = opts.sysfs.as_deref().unwrap_or(SysfsAccess::DEFAULT_ROOT);
+            Ok(Source::Sysfs(Arc::new(SysfsAccess::new(root))))
+        }
+    }
+
+    fn access(&self) -> ConfigAccessRef {
+        match self {
+            Source::Sysfs(sysfs) => sysfs.clone(),
+            Source::Dump(dump) => dump.clone(),
+        }
+    }
+
+    fn addresses(&self) -> Result<Vec<PCIAddress>, String> {
+        match self {
+            Source::Sysfs(sysfs) => sysfs.addresses().map_err(|e| e.to_string()),
+            Source::Dump(dump) => Ok(dump.addresses().collect()),
+        }
+    }
+
+    fn readable_len(&self, addr: PCIAddress) -> usize {
+        match self {
+            Source::Sysfs(sysfs) => sysfs.readable_len(addr),
+            Source::Dump(dump) => dump.captured_len(addr).unwrap_or(0),
+        }
+    }
+
+    fn resources(&self, addr: PCIAddress) -> Vec<SysfsResource> {
+        match self {
+            Source::Sysfs(sysfs) => sysfs.resources(addr).unwrap_or_default(),
+            Source::Dump(_) => Vec::new(),
+        }
+    }
+}
+
+/// A BAR decoded without sizing it (sizes come from the OS if available).
+#[derive(Debug)]
+struct BarInfo {
+    index: u32,
+    is_io: bool,
+    is_64bit: bool,
+    prefetchable: bool,
+    address: u64,
+    size: Option<u64>,
+}
+
+#[derive(Debug)]
+struct MsixInfo {
+    enabled: bool,
+    function_mask: bool,
+    entries: usize,
+    table_bir: u8,
+    table_offset: u32,
+    pba_bir: u8,
+    pba_offset: u32,
+}
+
+#[derive(Debug)]
+struct DeviceInfo {
+    addr: PCIAddress,
+    vendor_id: u16,
+    device_id: u16,
+    vendor_name: Option<&'static str>,
+    device_name: Option<&'static str>,
+    revision: u8,
+    class: (u8, u8, u8),
+    subsystem: Option<(u16, u16)>,
+    command: u16,
+    status: u16,
+    irq_line: u8,
+    irq_pin: u8,
+    bars: Vec<BarInfo>,
+    /// `None` if we are not allowed to read the capability list.
+    capabilities: Option<Vec<Capability>>,
+    msix: Option<MsixInfo>,
+    config: Vec<u8>,
+}
+
+/// Formats the address like lspci, the segment is omitted if it is 0.
+fn slot(addr: PCIAddress) -> String {
+    if addr.segment == 0 {
+        format!("{:02x}:{:02x}.{:x}", addr.bus, addr.dev, addr.fun)
+    } else {
+        addr.to_string()
+    }
+}
+
+fn decode_bars(dev: &PciDevice, resources: &[SysfsResource]) -> Vec<BarInfo> {
+    let count = match dev.device_type() {
+        PciDeviceType::Endpoint => 6,
+        PciDeviceType::PciBridge => 2,
+        PciDeviceType::Unknown => 0,
+    };
+
+    let mut bars = Vec::new();
+    let mut index = 0;
+    while index < count {
+        let raw = dev.read_config(0x10 + index * 4);
+        let is_io = raw & 0x1 == 0x1;
+        let is_64bit = !is_io && (raw >> 1) & 0b11 == 0b10;
+
+        let mut address = if is_io { raw & !0b11 } else { raw & !0xf } as u64;
+        if is_64bit && index + 1 < count {
+            address |= (dev.read_config(0x10 + (index + 1) * 4) as u64) << 32;
+        }
+
+        if raw != 0 {
+            bars.push(BarInfo {
+                index,
+                is_io,
+                is_64bit,
+                prefetchable: !is_io && raw & 0x8 == 0x8,
+                address,
+                size: resources
+                    .get(index as usize)
+                    .map(|r| r.size())
+                    .filter(|size| *size > 0),
+            });
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    bars
+}
+
+fn gather(source: &Source, addr: PCIAddress, opts: &Options) -> Option<DeviceInfo> {
+    let mut dev = PciDevice::with_access(addr, source.access())?;
+    let readable = source.readable_len(addr);
+
+    let (revision, base, sub, interface) = dev.revision_and_class();
+    let info = dev.info();
+    let subsystem = match dev.device_type() {
+        PciDeviceType::Endpoint => {
+            let ids = dev.read_config(0x2c);
+            Some((ids as u16, (ids >> 16) as u16))
+        }
+        _ => None,
+    };
+    let interrupt = dev.read_config(0x3c);
+
+    let capabilities: Option<Vec<Capability>> = if readable >= CONFIG_SPACE_SIZE {
+        Some(dev.capabilities().collect())
+    } else {
+        None
+    };
+    let msix_cap = capabilities
+        .as_ref()
+        .and_then(|caps| caps.iter().find(|cap| cap.id == CapabilityId::MsiX))
+        .map(|cap| Capability {
+            id: cap.id,
+            offset: cap.offset,
+        });
+    let msix = msix_cap.and_then(|cap| match dev.get_cap_region_mut(cap) {
+        Ok(CapabilityType::MsiX(msix)) => Some(MsixInfo {
+            enabled: msix.enabled(),
+            function_mask: msix.function_mask(),
+            entries: msix.table_size() + 1,
+            table_bir: msix.bir(),
+            table_offset: msix.table_offset(),
+            pba_bir: msix.pending_bit_bir(),
+            pba_offset: msix.pending_bit_table_offset(),
+        }),
+        Ok(CapabilityType::Unknown(_)) | Err(_) => None,
+    });
+
+    let config_len = opts.hex_len().min(readable);
+    let config = (0..config_len as u32)
+        .step_by(4)
+        .flat_map(|offset| dev.read_config(offset).to_le_bytes())
+        .collect();
+
+    Some(DeviceInfo {
+        addr,
+        vendor_id: dev.vendor_id(),
+        device_id: dev.device_id(),
+        vendor_name: info.map(|i| i.vendor_name),
+        device_name: info.map(|i| i.device_name),
+        revision,
+        class: (base, sub, interface),
+        subsystem,
+        command: dev.read_config(0x04) as u16,
+        status: dev.status(),
+        irq_line: interrupt as u8,
+        irq_pin: (interrupt >> 8) as u8,
+        bars: decode_bars(&dev, &source.resources(addr)),
+        capabilities,
+        msix,
+        config,
+    })
+}
+
+/// Formats a size like lspci (e.g., `16K`).
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["", "K", "M", "G", "T"];
+    let mut size = size;
+    let mut unit = 0;
+    while size >= 1024 && size % 1024 == 0 && unit < UNITS.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+    format!("{}{}", size, UNITS[unit])
+}
+
+/// Formats `flags` as a list of `Name+` / `Name-` entries.
+fn format_flags(value: u16, flags: &[(usize, &str)]) -> String {
+    flags
+        .iter()
+        .map(|(bit, name)| {
+            let set = if value & (1 << bit) != 0 { '+' } else { '-' };
+            format!("{}{}", name, set)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+const COMMAND_FLAGS: [(usize, &str); 10] = [
+    (0, "I/O"),
+    (1, "Mem"),
+    (2, "BusMaster"),
+    (3, "SpecCycle"),
+    (4, "MemWINV"),
+    (5, "VGASnoop"),
+    (6, "ParErr"),
+    (8, "SERR"),
+    (9, "FastB2B"),
+    (10, "DisINTx"),
+];
+
+const STATUS_FLAGS: [(usize, &str); 10] = [
+    (3, "INTx"),
+    (4, "Cap"),
+    (5, "66MHz"),
+    (7, "FastB2B"),
+    (8, "ParErr"),
+    (11, ">TAbort"),
+    (12, "<TAbort"),
+    (13, "<MAbort"),
+    (14, ">SERR"),
+    (15, "<PERR"),
+];
+
+fn print_text(dev: &DeviceInfo, opts: &Options) {
+    let (base, sub, interface) = dev.class;
+    let vendor = dev
+        .vendor_name
+        .map_or_else(|| format!("Vendor {:04x}", dev.vendor_id), String::from);
+    let device = dev
+        .device_name
+        .map_or_else(|| format!("Device {:04x}", dev.device_id), String::from);
+
+    print!(
+        "{} {} [{:02x}{:02x}]: {} {} [{:04x}:{:04x}]",
+        slot(dev.addr),
+        device_db::class_name(base, sub),
+        base,
+        sub,
+        vendor,
+        device,
+        dev.vendor_id,
+        dev.device_id
+    );
+    if dev.revision != 0 {
+        print!(" (rev {:02x})", dev.revision);
+    }
+    if interface != 0 {
+        print!(" (prog-if {:02x})", interface);
+    }
+    println!();
+
+    if opts.verbose > 0 {
+        if let Some((vendor, device)) = dev.subsystem {
+            println!("\tSubsystem: [{:04x}:{:04x}]", vendor, device);
+        }
+        if opts.verbose > 1 {
+            println!("\tControl: {}", format_flags(dev.command, &COMMAND_FLAGS));
+            println!("\tStatus: {}", format_flags(dev.status, &STATUS_FLAGS));
+        }
+        if dev.irq_pin != 0 {
+            // Only 1 to 4 (INTA# to INTD#) are valid, dumps may have anything.
+            let pin = match dev.irq_pin {
+                1..=4 => ((b'A' + dev.irq_pin - 1) as char).to_string(),
+                pin => format!("{:#04x}", pin),
+            };
+            println!("\tInterrupt: pin {} routed to IRQ {}", pin, dev.irq_line);
+        }
+        for bar in dev.bars.iter() {
+            let size = bar
+                .size
+                .map_or_else(String::new, |s| format!(" [size={}]", format_size(s)));
+            if bar.is_io {
+                println!("\tRegion {}: I/O ports at {:x}{}", bar.index, bar.address, size);
+            } else {
+                println!(
+                    "\tRegion {}: Memory at {:x} ({}-bit, {}){}",
+                    bar.index,
+                    bar.address,
+                    if bar.is_64bit { 64 } else { 32 },
+                    if bar.prefetchable {
+                        "prefetchable"
+                    } else {
+                        "non-prefetchable"
+                    },
+                    size
+                );
+            }
+        }
+
+        match &dev.capabilities {
+            None => println!("\tCapabilities: <access denied>"),
+            Some(caps) => {
+                for cap in caps.iter() {
+                    println!("\tCapabilities: [{:02x}] {:?}", cap.offset, cap.id);
+                    if cap.id == CapabilityId::MsiX {
+                        if let Some(msix) = &dev.msix {
+                            print_msix(dev, msix, opts);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    for (line, bytes) in dev.config.chunks(16).enumerate() {
+        let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{:02x}: {}", line * 16, bytes.join(" "));
+    }
+
+    if opts.verbose > 0 || !dev.config.is_empty() {
+        println!();
+    }
+}
+
+fn print_msix(dev: &DeviceInfo, msix: &MsixInfo, opts: &Options) {
+    println!(
+        "\t\tEnable{} Count={} Masked{}",
+        if msix.enabled { '+' } else { '-' },
+        msix.entries,
+        if msix.function_mask { '+' } else { '-' }
+    );
+    println!(
+        "\t\tVector table: BAR={} offset={:08x}",
+        msix.table_bir, msix.table_offset
+    );
+    println!("\t\tPBA: BAR={} offset={:08x}", msix.pba_bir, msix.pba_offset);
+
+    if opts.verbose > 1 {
+        let bar = dev.bars.iter().find(|b| b.index == msix.table_bir as u32);
+        match bar {
+            Some(bar) => println!(
+                "\t\tVector table at {:#x}, {} bytes",
+                bar.address + msix.table_offset as u64,
+                msix.entries * 16
+            ),
+            None => println!("\t\tVector table BAR is not assigned"),
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option<T, F: Fn(T) -> String>(value: Option<T>, f: F) -> String {
+    value.map_or_else(|| String::from("null"), f)
+}
+
+fn to_json(dev: &DeviceInfo) -> String {
+    let mut out = String::new();
+    let (base, sub, interface) = dev.class;
+
+    let _ = write!(out, "{{\"slot\": {}", json_string(&dev.addr.to_string()));
+    let _ = write!(out, ", \"vendor_id\": {}", dev.vendor_id);
+    let _ = write!(out, ", \"device_id\": {}", dev.device_id);
+    let _ = write!(out, ", \"vendor\": {}", json_option(dev.vendor_name, json_string));
+    let _ = write!(out, ", \"device\": {}", json_option(dev.device_name, json_string));
+    let _ = write!(out, ", \"revision\": {}", dev.revision);
+    let _ = write!(
+        out,
+        ", \"class\": {{\"base\": {}, \"sub\": {}, \"interface\": {}, \"name\": {}}}",
+        base,
+        sub,
+        interface,
+        json_string(device_db::class_name(base, sub))
+    );
+    let _ = write!(
+        out,
+        ", \"subsystem\": {}",
+        json_option(dev.subsystem, |(vendor, device)| format!(
+            "{{\"vendor_id\": {}, \"device_id\": {}}}",
+            vendor, device
+        ))
+    );
+    let _ = write!(out, ", \"command\": {}", dev.command);
+    let _ = write!(out, ", \"status\": {}", dev.status);
+    let _ = write!(out, ", \"irq_line\": {}", dev.irq_line);
+    let _ = write!(out, ", \"irq_pin\": {}", dev.irq_pin);
+
+    let bars: Vec<String> = dev
+        .bars
+        .iter()
+        .map(|bar| {
+            format!(
+                "{{\"index\": {}, \"type\": \"{}\", \"bits\": {}, \"prefetchable\": {}, \"address\": {}, \"size\": {}}}",
+                bar.index,
+                if bar.is_io { "io" } else { "memory" },
+                if bar.is_64bit { 64 } else { 32 },
+                bar.prefetchable,
+                bar.address,
+                json_option(bar.size, |s| s.to_string())
+            )
+        })
+        .collect();
+    let _ = write!(out, ", \"bars\": [{}]", bars.join(", "));
+
+    let caps = dev.capabilities.as_ref().map(|caps| {
+        let caps: Vec<String> = caps
+            .iter()
+            .map(|cap| {
+                format!(
+                    "{{\"offset\": {}, \"id\": {}}}",
+                    cap.offset,
+                    json_string(&format!("{:?}", cap.id))
+                )
+            })
+            .collect();
+        format!("[{}]", caps.join(", "))
+    });
+    let _ = write!(out, ", \"capabilities\": {}", json_option(caps, |c| c));
+
+    let _ = write!(
+        out,
+        ", \"msix\": {}",
+        json_option(dev.msix.as_ref(), |msix| format!(
+            "{{\"enabled\": {}, \"function_mask\": {}, \"entries\": {}, \"table_bir\": {}, \"table_offset\": {}, \"pba_bir\": {}, \"pba_offset\": {}}}",
+            msix.enabled,
+            msix.function_mask,
+            msix.entries,
+            msix.table_bir,
+            msix.table_offset,
+            msix.pba_bir,
+            msix.pba_offset
+        ))
+    );
+
+    if !dev.config.is_empty() {
+        let hex: String = dev.config.iter().map(|b| format!("{:02x}", b)).collect();
+        let _ = write!(out, ", \"config\": {}", json_string(&hex));
+    }
+
+    out.push('}');
+    out
+}
+
+fn run() -> Result<(), String> {
+    let opts = Options::parse()?;
+    let source = Source::new(&opts)?;
+
+    let devices: Vec<DeviceInfo> = source
+        .addresses()?
+        .into_iter()
+        .filter_map(|addr| gather(&source, addr, &opts))
+        .collect();
+
+    if opts.json {
+        let devices: Vec<String> = devices.iter().map(|d| format!("  {}", to_json(d))).collect();
+        println!("[\n{}\n]", devices.join(",\n"));
+    } else {
+        for dev in devices.iter() {
+            print_text(dev, &opts);
+        }
+    }
+
+    Ok(())
+}
+
 pub fn main() {
-    //let driver = driverkit::linux::PciDriver::new(0, 0, 0);
-    //println!("{:?}", driver);
+    if let Err(e) = run() {
+        eprintln!("testdrive: {}", e);
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
 }
@@ -1,20 +1,34 @@
 use alloc::alloc::{Allocator, Layout};
 use alloc::collections::vec_deque::VecDeque;
-use alloc::vec;
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::sync::{Arc, Weak};
 use alloc::vec::Vec;
 use alloc::{alloc::AllocError, collections::TryReserveError};
+use core::borrow::{Borrow, BorrowMut};
 use core::cmp;
-use core::ops::Index;
+use core::fmt;
+use core::ops::{Bound, Deref, DerefMut, Index, RangeBounds};
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use custom_error::custom_error;
+use spin::Mutex;
 
 use crate::{IOAddr, PAddr, VAddr};
 
 // custom error for the IOMemory
 custom_error! {pub IOMemError
     OutOfMemory = "reached out of memory",
-    NotYetImplemented = "feature not yet implemented"
+    NotYetImplemented = "feature not yet implemented",
+    NotMapped = "memory is not accessible by devices",
+    Untranslatable{vaddr: u64} = "no physical address for virtual address {vaddr}",
+    OutOfBounds = "not enough room in the buffer",
+    PoolExhausted = "all buffers of the pool are in use",
+    InvalidLayout = "buffer layout not supported",
+    TooManySegments = "data needs more DMA segments than the device supports",
+    InvalidSgLimits = "segment and page sizes must not be zero",
+    BeyondDmaMask = "no memory within the DMA mask of the device"
 }
 
 impl From<TryReserveError> for IOMemError {
@@ -23,96 +37,802 @@ impl From<TryReserveError> for IOMemError {
     }
 }
 
-///  TODO: get rid of this:
+/// Where the kernel maps physical memory, used by the default translator on
+/// bare metal (see [`OffsetTranslator`]).
 pub const KERNEL_BASE: u64 = 0x400000000000;
 
+/// Translates the addresses of memory used for DMA.
+pub trait AddressTranslator: Send + Sync {
+    /// The physical address backing `vaddr`.
+    fn virt_to_phys(&self, vaddr: VAddr) -> Result<PAddr, IOMemError>;
+
+    /// The address devices use to access `paddr`.
+    ///
+    /// The default assumes devices use physical addresses (no IOMMU).
+    fn phys_to_io(&self, paddr: PAddr) -> Result<IOAddr, IOMemError> {
+        Ok(IOAddr::from(paddr.as_u64()))
+    }
+
+    /// The address devices use to access `vaddr`.
+    fn virt_to_io(&self, vaddr: VAddr) -> Result<IOAddr, IOMemError> {
+        self.phys_to_io(self.virt_to_phys(vaddr)?)
+    }
+}
+
+/// Translation for physical memory mapped at a fixed offset (e.g., the
+/// kernel's direct map).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetTranslator {
+    /// Virtual address of physical address 0.
+    pub offset: u64,
+}
+
+impl AddressTranslator for OffsetTranslator {
+    fn virt_to_phys(&self, vaddr: VAddr) -> Result<PAddr, IOMemError> {
+        vaddr
+            .as_u64()
+            .checked_sub(self.offset)
+            .map(PAddr::from)
+            .ok_or(IOMemError::Untranslatable {
+                vaddr: vaddr.as_u64(),
+            })
+    }
+}
+
+#[cfg(target_os = "linux")]
+static DEFAULT_TRANSLATOR: crate::mem::PagemapTranslator = crate::mem::PagemapTranslator;
+
+#[cfg(not(target_os = "linux"))]
+static DEFAULT_TRANSLATOR: OffsetTranslator = OffsetTranslator {
+    offset: KERNEL_BASE,
+};
+
+static TRANSLATOR: Mutex<&'static dyn AddressTranslator> = Mutex::new(&DEFAULT_TRANSLATOR);
+
+/// Installs the translator of the platform.
+///
+/// Defaults to reading `/proc/self/pagemap` on Linux and to an
+/// [`OffsetTranslator`] for [`KERNEL_BASE`] elsewhere.
+pub fn set_address_translator(translator: &'static dyn AddressTranslator) {
+    *TRANSLATOR.lock() = translator;
+}
+
+/// Forwards to the translator installed with [`set_address_translator`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InstalledTranslator;
+
+impl AddressTranslator for InstalledTranslator {
+    fn virt_to_phys(&self, vaddr: VAddr) -> Result<PAddr, IOMemError> {
+        let translator = *TRANSLATOR.lock();
+        translator.virt_to_phys(vaddr)
+    }
+
+    fn phys_to_io(&self, paddr: PAddr) -> Result<IOAddr, IOMemError> {
+        let translator = *TRANSLATOR.lock();
+        translator.phys_to_io(paddr)
+    }
+}
+
 /// A trait to tag objects which a device needs to read or write over DMA.
+///
+/// Addresses are translated with the installed translator (see
+/// [`set_address_translator`]).
 pub trait DmaObject {
-    fn paddr(&self) -> PAddr {
-        PAddr::from(self as *const Self as *const () as u64) - PAddr::from(KERNEL_BASE)
+    fn paddr(&self) -> Result<PAddr, IOMemError> {
+        InstalledTranslator.virt_to_phys(self.vaddr())
     }
 
     fn vaddr(&self) -> VAddr {
         VAddr::from(self as *const Self as *const () as usize)
     }
 
-    fn ioaddr(&self) -> IOAddr {
-        IOAddr::from(self.paddr().as_u64())
+    fn ioaddr(&self) -> Result<IOAddr, IOMemError> {
+        InstalledTranslator.virt_to_io(self.vaddr())
     }
 }
 
-/// An allocator that (supposedly) backs memory accessible by devices.
-#[derive(Debug, Default, Clone, Copy)]
-pub struct DmaAllocator;
+/// Memory that devices can access, see [`DmaBackend`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmaMemory {
+    /// Where the CPU accesses the memory.
+    pub ptr: NonNull<u8>,
+    /// Where devices access the memory, `None` if the backend can't tell
+    /// (e.g., physical addresses are hidden from unprivileged processes).
+    pub ioaddr: Option<IOAddr>,
+    /// Size of the memory in bytes.
+    pub len: usize,
+}
+
+// Safety: `DmaMemory` only describes the memory, whoever allocated it
+// controls access.
+unsafe impl Send for DmaMemory {}
+unsafe impl Sync for DmaMemory {}
+
+/// Provides memory for DMA, e.g., pinned memory with a known physical
+/// address, or memory mapped into the IO address space by an IOMMU.
+pub trait DmaBackend: Send + Sync {
+    /// Allocates zeroed memory for `layout` that is pinned and accessible by
+    /// devices for as long as it is allocated.
+    fn allocate(&self, layout: Layout) -> Result<DmaMemory, IOMemError>;
+
+    /// Frees `memory`.
+    ///
+    /// `memory.ioaddr` is `None` when freed through the `Allocator` interface
+    /// of [`DmaAllocator`], backends must find the memory by `memory.ptr`.
+    ///
+    /// # Safety
+    /// - `memory` must have been allocated by this backend with `layout` and
+    ///   devices must no longer access it.
+    unsafe fn deallocate(&self, memory: DmaMemory, layout: Layout);
+
+    /// Like [`DmaBackend::allocate`] but for devices that only reach IO
+    /// addresses up to `mask`.
+    ///
+    /// The default only checks where `allocate` put the memory, backends
+    /// that control placement allocate within the mask instead (e.g.,
+    /// [`MappedBackend`] with [`IoMapper::map_within`]).
+    fn allocate_within(&self, layout: Layout, mask: u64) -> Result<DmaMemory, IOMemError> {
+        let memory = self.allocate(layout)?;
+        // Memory without an IO address fails later when one is needed.
+        if memory
+            .ioaddr
+            .map_or(true, |ioaddr| within_mask(ioaddr, memory.len, mask))
+        {
+            Ok(memory)
+        } else {
+            // Safety: Just allocated with `layout`, never seen by a device.
+            unsafe { self.deallocate(memory, layout) };
+            Err(IOMemError::BeyondDmaMask)
+        }
+    }
+}
+
+/// Are the `len` bytes at `ioaddr` reachable with DMA mask `mask`?
+fn within_mask(ioaddr: IOAddr, len: usize, mask: u64) -> bool {
+    len == 0
+        || ioaddr
+            .as_u64()
+            .checked_add(len as u64 - 1)
+            .map_or(false, |last| last <= mask)
+}
+
+/// Memory from the global allocator that devices access at a fixed offset
+/// from its virtual address (e.g., identity mapped or with a direct map of
+/// physical memory on bare metal).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetBackend {
+    /// Subtracted from the virtual address to get the IO address.
+    pub offset: u64,
+}
+
+impl DmaBackend for OffsetBackend {
+    fn allocate(&self, layout: Layout) -> Result<DmaMemory, IOMemError> {
+        // Safety: `DmaAllocator` doesn't pass zero-sized layouts.
+        let ptr = NonNull::new(unsafe { alloc::alloc::alloc_zeroed(layout) })
+            .ok_or(IOMemError::OutOfMemory)?;
+        match (ptr.as_ptr() as u64).checked_sub(self.offset) {
+            Some(ioaddr) => Ok(DmaMemory {
+                ptr,
+                ioaddr: Some(IOAddr::from(ioaddr)),
+                len: layout.size(),
+            }),
+            None => {
+                // Safety: Just allocated with `layout`, never seen by a device.
+                unsafe { alloc::alloc::dealloc(ptr.as_ptr(), layout) };
+                Err(IOMemError::NotMapped)
+            }
+        }
+    }
+
+    unsafe fn deallocate(&self, memory: DmaMemory, layout: Layout) {
+        alloc::alloc::dealloc(memory.ptr.as_ptr(), layout);
+    }
+}
+
+/// Maps memory into the IO address space of devices (e.g., an IOMMU).
+pub trait IoMapper: Send + Sync {
+    /// Maps `len` bytes at `vaddr`, returns the address devices use.
+    fn map(&self, vaddr: VAddr, len: usize) -> Result<IOAddr, IOMemError>;
+
+    /// Like [`IoMapper::map`] but at IO addresses up to `mask`.
+    ///
+    /// The default maps anywhere and fails if that is beyond `mask`, mappers
+    /// allocating IO addresses should allocate them below `mask` instead.
+    fn map_within(&self, vaddr: VAddr, len: usize, mask: u64) -> Result<IOAddr, IOMemError> {
+        let ioaddr = self.map(vaddr, len)?;
+        if within_mask(ioaddr, len, mask) {
+            Ok(ioaddr)
+        } else {
+            self.unmap(ioaddr, len);
+            Err(IOMemError::BeyondDmaMask)
+        }
+    }
+
+    /// Removes a mapping created by [`IoMapper::map`] or
+    /// [`IoMapper::map_within`].
+    fn unmap(&self, ioaddr: IOAddr, len: usize);
+}
+
+/// Memory from the global allocator mapped for devices by an [`IoMapper`].
+#[derive(Debug)]
+pub struct MappedBackend<M> {
+    mapper: M,
+    /// IO address of the allocations by CPU address, to unmap them when they
+    /// are freed without their IO address.
+    mappings: Mutex<BTreeMap<usize, IOAddr>>,
+}
+
+impl<M: IoMapper> MappedBackend<M> {
+    pub const fn new(mapper: M) -> Self {
+        MappedBackend {
+            mapper,
+            mappings: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn mapper(&self) -> &M {
+        &self.mapper
+    }
+
+    /// Allocates memory from the global allocator and maps it with `map`.
+    fn allocate_mapped(
+        &self,
+        layout: Layout,
+        map: impl FnOnce(VAddr, usize) -> Result<IOAddr, IOMemError>,
+    ) -> Result<DmaMemory, IOMemError> {
+        let memory = OffsetBackend { offset: 0 }.allocate(layout)?;
+        let vaddr = memory.ptr.as_ptr() as usize;
+        match map(VAddr::from(vaddr), layout.size()) {
+            Ok(ioaddr) => {
+                self.mappings.lock().insert(vaddr, ioaddr);
+                Ok(DmaMemory {
+                    ioaddr: Some(ioaddr),
+                    ..memory
+                })
+            }
+            Err(e) => {
+                // Safety: Just allocated with `layout`, never seen by a device.
+                unsafe { OffsetBackend { offset: 0 }.deallocate(memory, layout) };
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<M: IoMapper> DmaBackend for MappedBackend<M> {
+    fn allocate(&self, layout: Layout) -> Result<DmaMemory, IOMemError> {
+        self.allocate_mapped(layout, |vaddr, len| self.mapper.map(vaddr, len))
+    }
+
+    /// Maps the memory with [`IoMapper::map_within`].
+    fn allocate_within(&self, layout: Layout, mask: u64) -> Result<DmaMemory, IOMemError> {
+        self.allocate_mapped(layout, |vaddr, len| {
+            self.mapper.map_within(vaddr, len, mask)
+        })
+    }
+
+    unsafe fn deallocate(&self, memory: DmaMemory, layout: Layout) {
+        let mapping = self.mappings.lock().remove(&(memory.ptr.as_ptr() as usize));
+        if let Some(ioaddr) = mapping {
+            self.mapper.unmap(ioaddr, layout.size());
+        }
+        OffsetBackend { offset: 0 }.deallocate(memory, layout)
+    }
+}
+
+/// Memory from the global allocator translated for devices by an
+/// [`AddressTranslator`].
+///
+/// The memory is not pinned, so this is only suitable where the heap is
+/// never paged out (e.g., in a kernel). Memory the translator fails for is
+/// still allocated, without an IO address.
+pub struct TranslatedBackend {
+    translator: &'static dyn AddressTranslator,
+}
+
+impl TranslatedBackend {
+    pub const fn new(translator: &'static dyn AddressTranslator) -> Self {
+        TranslatedBackend { translator }
+    }
+}
+
+impl DmaBackend for TranslatedBackend {
+    fn allocate(&self, layout: Layout) -> Result<DmaMemory, IOMemError> {
+        let memory = OffsetBackend { offset: 0 }.allocate(layout)?;
+        let vaddr = VAddr::from(memory.ptr.as_ptr() as u64);
+        Ok(DmaMemory {
+            ioaddr: self.translator.virt_to_io(vaddr).ok(),
+            ..memory
+        })
+    }
+
+    unsafe fn deallocate(&self, memory: DmaMemory, layout: Layout) {
+        OffsetBackend { offset: 0 }.deallocate(memory, layout)
+    }
+}
+
+/// Used by [`DmaAllocator::default`] until another backend is registered:
+/// heap memory translated by the installed translator. On Linux, register a
+/// [`crate::mem::DevMemBackend`] for pinned memory.
+static DEFAULT_BACKEND: TranslatedBackend = TranslatedBackend::new(&InstalledTranslator);
+
+static DMA_BACKEND: Mutex<&'static dyn DmaBackend> = Mutex::new(&DEFAULT_BACKEND);
+
+/// Registers the backend used by allocators created afterwards with
+/// [`DmaAllocator::default`] (e.g., `IOBuf::new`).
+pub fn set_dma_backend(backend: &'static dyn DmaBackend) {
+    *DMA_BACKEND.lock() = backend;
+}
+
+/// An allocator for memory accessible by devices, delegating to a
+/// [`DmaBackend`].
+#[derive(Clone, Copy)]
+pub struct DmaAllocator {
+    backend: &'static dyn DmaBackend,
+    /// Highest IO address the device can reach.
+    mask: u64,
+}
+
+impl DmaAllocator {
+    /// An allocator using `backend` instead of the registered one.
+    pub fn with_backend(backend: &'static dyn DmaBackend) -> DmaAllocator {
+        DmaAllocator {
+            backend,
+            mask: u64::MAX,
+        }
+    }
+
+    /// The allocator for a device that only reaches IO addresses up to
+    /// `mask` (e.g., `0xffff_ffff` for 32-bit DMA).
+    pub fn with_mask(self, mask: u64) -> DmaAllocator {
+        DmaAllocator { mask, ..self }
+    }
 
+    pub fn mask(&self) -> u64 {
+        self.mask
+    }
+
+    /// Allocates zeroed memory for `layout`.
+    pub fn allocate_dma(&self, layout: Layout) -> Result<DmaMemory, IOMemError> {
+        if layout.size() == 0 {
+            return Ok(DmaMemory {
+                ptr: NonNull::new(layout.align() as *mut u8).ok_or(IOMemError::OutOfMemory)?,
+                ioaddr: Some(IOAddr::from(0u64)),
+                len: 0,
+            });
+        }
+        if self.mask == u64::MAX {
+            self.backend.allocate(layout)
+        } else {
+            self.backend.allocate_within(layout, self.mask)
+        }
+    }
+
+    /// Frees memory allocated with [`DmaAllocator::allocate_dma`].
+    ///
+    /// # Safety
+    /// - See [`DmaBackend::deallocate`].
+    pub unsafe fn deallocate_dma(&self, memory: DmaMemory, layout: Layout) {
+        if layout.size() != 0 {
+            self.backend.deallocate(memory, layout)
+        }
+    }
+}
+
+impl Default for DmaAllocator {
+    fn default() -> Self {
+        DmaAllocator::with_backend(*DMA_BACKEND.lock())
+    }
+}
+
+impl fmt::Debug for DmaAllocator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DmaAllocator")
+            .field("backend", &(self.backend as *const dyn DmaBackend))
+            .field("mask", &self.mask)
+            .finish()
+    }
+}
+
+/// Memory allocated through the `Allocator` interface loses its IO address,
+/// backends have to be able to free it given only the CPU pointer.
 unsafe impl Allocator for DmaAllocator {
     /// Allocates IO memory.
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-        // TODO: ensure IOMMU stuff etc. here, for now:
-        unsafe {
-            // do the actual allocation, refer to the OS allocator
-            let ptr: *mut u8 = alloc::alloc::alloc_zeroed(layout);
-            if !ptr.is_null() {
-                // wrap in in NonNull, remove option type
-                let ptr_nonnull = NonNull::new(ptr).unwrap();
-                // construct the NonNull slice for the return
-                Ok(NonNull::slice_from_raw_parts(ptr_nonnull, layout.size()))
-            } else {
-                Err(AllocError)
-            }
-        }
+        let memory = self.allocate_dma(layout).map_err(|_| AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(memory.ptr, layout.size()))
     }
 
     /// Deallocates the previously allocated IO memory.
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        // TODO: ensure IOMMU stuff, for now:
-        let buf = ptr.as_ptr();
-        alloc::alloc::dealloc(buf, layout);
+        let memory = DmaMemory {
+            ptr,
+            ioaddr: None,
+            len: layout.size(),
+        };
+        self.deallocate_dma(memory, layout)
+    }
+}
+
+/// DMA memory backing one or more [`IOBuf`]s, freed when the last of them
+/// is dropped.
+#[derive(Debug)]
+struct IOBufStorage {
+    memory: DmaMemory,
+    layout: Layout,
+    allocator: DmaAllocator,
+    /// The slab and slot the memory is carved from, `None` if it was
+    /// allocated on its own.
+    slab: Option<(Arc<Slab>, usize)>,
+    /// The pool counting the memory as outstanding (until it comes back or
+    /// is dropped).
+    pool: Mutex<Weak<PoolShared>>,
+}
+
+impl IOBufStorage {
+    fn new(layout: Layout, allocator: DmaAllocator) -> Result<Arc<IOBufStorage>, IOMemError> {
+        let memory = allocator.allocate_dma(layout)?;
+        Ok(Arc::new(IOBufStorage {
+            memory,
+            layout,
+            allocator,
+            slab: None,
+            pool: Mutex::new(Weak::new()),
+        }))
+    }
+}
+
+impl Drop for IOBufStorage {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.get_mut().upgrade() {
+            pool.outstanding.fetch_sub(1, Ordering::AcqRel);
+        }
+        match &self.slab {
+            Some((slab, slot)) => slab.release(*slot),
+            // Safety: Allocated by `allocator` with `layout`, no buffer (and so
+            // no device) uses it anymore.
+            None => unsafe { self.allocator.deallocate_dma(self.memory, self.layout) },
+        }
+    }
+}
+
+/// One DMA region carved into buffers of the same size (see
+/// [`IOBufPool::slab`]).
+#[derive(Debug)]
+struct Slab {
+    memory: DmaMemory,
+    layout: Layout,
+    allocator: DmaAllocator,
+    /// Layout of the buffers.
+    buf_layout: Layout,
+    /// Distance of the buffers within a page.
+    stride: usize,
+    page_size: usize,
+    per_page: usize,
+    slots: usize,
+    /// Slots from here on were never handed out.
+    next: AtomicUsize,
+    /// Slots that were handed out and freed again.
+    free: Mutex<Vec<usize>>,
+}
+
+impl Slab {
+    fn new(
+        buf_layout: Layout,
+        slots: usize,
+        page_size: usize,
+        allocator: DmaAllocator,
+    ) -> Result<Arc<Slab>, IOMemError> {
+        let stride = buf_layout.pad_to_align().size();
+        if !page_size.is_power_of_two() || stride == 0 || stride > page_size {
+            return Err(IOMemError::InvalidLayout);
+        }
+        let per_page = page_size / stride;
+        let size = slots
+            .div_ceil(per_page)
+            .checked_mul(page_size)
+            .ok_or(IOMemError::InvalidLayout)?;
+        let layout =
+            Layout::from_size_align(size, page_size).map_err(|_| IOMemError::InvalidLayout)?;
+
+        let mut free = Vec::new();
+        free.try_reserve_exact(slots)?;
+        let memory = allocator.allocate_dma(layout)?;
+        Ok(Arc::new(Slab {
+            memory,
+            layout,
+            allocator,
+            buf_layout,
+            stride,
+            page_size,
+            per_page,
+            slots,
+            next: AtomicUsize::new(0),
+            free: Mutex::new(free),
+        }))
+    }
+
+    /// Offset of `slot` in the region, buffers never cross a page boundary.
+    fn offset(&self, slot: usize) -> usize {
+        (slot / self.per_page) * self.page_size + (slot % self.per_page) * self.stride
+    }
+
+    /// Memory for a buffer, from a freed slot or the next unused one.
+    fn carve(slab: &Arc<Slab>) -> Result<Arc<IOBufStorage>, IOMemError> {
+        let slot = match slab.free.lock().pop() {
+            Some(slot) => slot,
+            None => slab
+                .next
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |next| {
+                    if next < slab.slots {
+                        Some(next + 1)
+                    } else {
+                        None
+                    }
+                })
+                .map_err(|_| IOMemError::PoolExhausted)?,
+        };
+        let offset = slab.offset(slot);
+        Ok(Arc::new(IOBufStorage {
+            memory: DmaMemory {
+                // Safety: `offset` is within the region.
+                ptr: unsafe { NonNull::new_unchecked(slab.memory.ptr.as_ptr().add(offset)) },
+                ioaddr: slab.memory.ioaddr.map(|ioaddr| ioaddr + offset as u64),
+                len: slab.buf_layout.size(),
+            },
+            layout: slab.buf_layout,
+            allocator: slab.allocator,
+            slab: Some((slab.clone(), slot)),
+            pool: Mutex::new(Weak::new()),
+        }))
+    }
+
+    fn release(&self, slot: usize) {
+        // Can't allocate, `free` has room for all slots.
+        self.free.lock().push(slot);
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        // Safety: Allocated by `allocator` with `layout`, all buffers carved
+        // from it are gone.
+        unsafe { self.allocator.deallocate_dma(self.memory, self.layout) }
     }
 }
 
 #[derive(Debug)]
 /// Represents an IO buffer (data handed to/from device).
+///
+/// The data starts `headroom` bytes into the memory of the buffer, so
+/// headers can be prepended ([`IOBuf::push`]) and trailers appended
+/// ([`IOBuf::put`]) without copying the data.
+///
+/// Buffers can share their memory ([`IOBuf::share`], [`IOBuf::slice`]),
+/// a shared buffer copies its data to memory of its own before it is
+/// modified.
 pub struct IOBuf {
-    buf: Vec<u8, DmaAllocator>,
+    /// The memory backing the buffer.
+    storage: Arc<IOBufStorage>,
+    /// Offset of the data in `storage`.
+    head: usize,
+    /// Bytes of data.
+    len: usize,
 }
 
 impl IOBuf {
     pub fn new(layout: Layout) -> Result<IOBuf, IOMemError> {
-        // get the aligned buffer length
-        // get the layouf for the allocation
-        let allocator = DmaAllocator::default();
-        let buf: Vec<u8, DmaAllocator> = Vec::with_capacity_in(layout.size(), allocator);
-        let mut iobuf = IOBuf { buf };
-        // call expand here to make sure the buffer has the full size
-        iobuf.expand();
-        // info!("IOBuf: new buffer of size {}!",iobuf.capacity());
-        Ok(iobuf)
+        IOBuf::new_in(layout, DmaAllocator::default())
+    }
+
+    /// Allocates a buffer with `allocator`.
+    pub fn new_in(layout: Layout, allocator: DmaAllocator) -> Result<IOBuf, IOMemError> {
+        // The buffer starts out with its full size (zeroed)
+        Ok(IOBuf {
+            storage: IOBufStorage::new(layout, allocator)?,
+            head: 0,
+            len: layout.size(),
+        })
+    }
+
+    /// An empty buffer for the memory of `storage`.
+    fn from_storage(storage: Arc<IOBufStorage>) -> IOBuf {
+        IOBuf {
+            storage,
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Another buffer with the same data, sharing the memory of this one.
+    pub fn share(&self) -> IOBuf {
+        IOBuf {
+            storage: self.storage.clone(),
+            head: self.head,
+            len: self.len,
+        }
+    }
+
+    /// A buffer with `range` of the data, sharing the memory of this one.
+    pub fn slice<R: RangeBounds<usize>>(&self, range: R) -> Result<IOBuf, IOMemError> {
+        let start = match range.start_bound() {
+            Bound::Included(start) => *start,
+            Bound::Excluded(start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => end + 1,
+            Bound::Excluded(end) => *end,
+            Bound::Unbounded => self.len,
+        };
+        if start > end || end > self.len {
+            return Err(IOMemError::OutOfBounds);
+        }
+        Ok(IOBuf {
+            storage: self.storage.clone(),
+            head: self.head + start,
+            len: end - start,
+        })
+    }
+
+    /// Does the buffer share its memory with other buffers?
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.storage) > 1
+    }
+
+    /// Makes sure the buffer doesn't share its memory, by copying its data
+    /// to newly allocated memory if necessary.
+    ///
+    /// The copy of a buffer carved from a slab (see [`IOBufPool::slab`]) is
+    /// allocated on its own with the allocator of the slab, outside of it
+    /// (the pool drops it when it comes back).
+    pub fn make_unique(&mut self) -> Result<(), IOMemError> {
+        if Arc::get_mut(&mut self.storage).is_some() {
+            return Ok(());
+        }
+        let storage = IOBufStorage::new(self.storage.layout, self.storage.allocator)?;
+        // Safety: Nobody else has `storage` yet, it has the same size.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.as_slice().as_ptr(),
+                storage.memory.ptr.as_ptr().add(self.head),
+                self.len,
+            )
+        };
+        self.storage = storage;
+        Ok(())
+    }
+
+    /// Size of the memory backing the buffer.
+    pub fn capacity(&self) -> usize {
+        self.storage.layout.size()
+    }
+
+    /// Bytes available in front of the data.
+    pub fn headroom(&self) -> usize {
+        self.head
+    }
+
+    /// Bytes available after the data.
+    pub fn tailroom(&self) -> usize {
+        self.capacity() - self.head - self.len
+    }
+
+    /// Empties the buffer and leaves `headroom` bytes in front of it.
+    pub fn reserve(&mut self, headroom: usize) -> Result<(), IOMemError> {
+        if headroom > self.capacity() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        self.head = headroom;
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Prepends `n` bytes from the headroom to the data, returns them to
+    /// fill in (e.g., a header).
+    pub fn push(&mut self, n: usize) -> Result<&mut [u8], IOMemError> {
+        if n > self.headroom() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        self.make_unique()?;
+        self.head -= n;
+        self.len += n;
+        Ok(&mut self.as_mut_slice()[..n])
+    }
+
+    /// Removes `n` bytes from the front of the data (into the headroom).
+    pub fn pull(&mut self, n: usize) -> Result<(), IOMemError> {
+        if n > self.len {
+            return Err(IOMemError::OutOfBounds);
+        }
+        self.head += n;
+        self.len -= n;
+        Ok(())
+    }
+
+    /// Appends `n` bytes from the tailroom to the data, returns them to fill
+    /// in (e.g., a trailer).
+    pub fn put(&mut self, n: usize) -> Result<&mut [u8], IOMemError> {
+        if n > self.tailroom() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        self.make_unique()?;
+        self.len += n;
+        let len = self.len;
+        Ok(&mut self.as_mut_slice()[len - n..])
+    }
+
+    /// Removes `n` bytes from the end of the data (into the tailroom).
+    pub fn trim(&mut self, n: usize) -> Result<(), IOMemError> {
+        if n > self.len {
+            return Err(IOMemError::OutOfBounds);
+        }
+        self.len -= n;
+        Ok(())
+    }
+
+    /// Address devices use to access the data, fails if the backend
+    /// couldn't translate the memory.
+    pub fn ioaddr(&self) -> Result<IOAddr, IOMemError> {
+        match self.storage.memory.ioaddr {
+            Some(ioaddr) => Ok(ioaddr + self.head as u64),
+            None => Err(IOMemError::Untranslatable {
+                vaddr: self.storage.memory.ptr.as_ptr() as u64,
+            }),
+        }
+    }
+
+    /// Resizes the data to `new_len`, filling new bytes with 0 (like
+    /// `Vec::resize`).
+    fn resize(&mut self, new_len: usize) {
+        debug_assert!(self.head + new_len <= self.capacity());
+        let (head, len) = (self.head, self.len);
+        if new_len > len {
+            self.as_capacity_mut()[head + len..head + new_len].fill(0);
+        }
+        self.len = new_len;
+    }
+
+    /// The whole memory of the buffer, including head- and tailroom.
+    ///
+    /// Copies the data first if the memory is shared, panics if that fails
+    /// (use [`IOBuf::make_unique`] beforehand to handle the error).
+    fn as_capacity_mut(&mut self) -> &mut [u8] {
+        self.make_unique()
+            .expect("Can't copy shared buffer before modifying it");
+        let storage = Arc::get_mut(&mut self.storage).expect("buffer is unique");
+        // Safety: We own `capacity` bytes at `ptr` and nobody else uses them.
+        unsafe {
+            core::slice::from_raw_parts_mut(storage.memory.ptr.as_ptr(), storage.layout.size())
+        }
     }
 
     /// Fill buffer with as many 0 as capacity allows.
     pub fn expand(&mut self) {
-        self.buf.resize(self.buf.capacity(), 0);
+        self.resize(self.capacity() - self.head);
     }
 
     pub fn truncate(&mut self, new_len: usize) {
-        self.buf.truncate(new_len)
+        self.len = cmp::min(self.len, new_len);
     }
 
     /// Removes all buffer contents.
     pub fn clear(&mut self) {
-        self.buf.clear();
+        self.len = 0;
     }
 
     /// Copy data from `src` into a given `offset` of the `IOBuf`.
     pub fn copy_in_at(&mut self, offset: usize, src: &[u8]) -> Result<usize, IOMemError> {
         // Currently we do not allow extending the buffer:
-        let remaining_capacity = self.buf.capacity() - offset;
+        let remaining_capacity = (self.capacity() - self.head)
+            .checked_sub(offset)
+            .ok_or(IOMemError::OutOfBounds)?;
         let cnt = cmp::min(remaining_capacity, src.len());
-        self.buf.resize(offset + cnt, 0);
+        self.make_unique()?;
+        self.resize(offset + cnt);
 
         // copy the slice
-        self.buf[offset..offset + cnt].copy_from_slice(&src[0..cnt]);
+        self.as_mut_slice()[offset..offset + cnt].copy_from_slice(&src[0..cnt]);
 
         Ok(cnt)
     }
@@ -125,13 +845,13 @@ impl IOBuf {
     /// Copy data out of the IOBuf, starting at a given `offset` into `dst`.
     pub fn copy_out_at(&self, offset: usize, dst: &mut [u8]) -> Result<usize, IOMemError> {
         // of the offset is outside of the length of the vector then we
-        if offset >= self.buf.len() {
+        if offset >= self.len {
             return Ok(0);
         }
 
-        let cnt = cmp::min(self.buf.len() - offset, dst.len());
+        let cnt = cmp::min(self.len - offset, dst.len());
         // copy the slice
-        dst[0..cnt].copy_from_slice(&self.buf[offset..offset + cnt]);
+        dst[0..cnt].copy_from_slice(&self.as_slice()[offset..offset + cnt]);
         Ok(cnt)
     }
 
@@ -142,20 +862,27 @@ impl IOBuf {
 
     /// Get a IOBuf contents as slice.
     pub fn as_slice(&self) -> &[u8] {
-        self.buf.as_slice()
+        // Safety: The storage has `capacity` (initialized) bytes at `ptr`, the
+        // data is within them.
+        unsafe {
+            core::slice::from_raw_parts(self.storage.memory.ptr.as_ptr().add(self.head), self.len)
+        }
     }
 
     /// Get a IOBuf contents as mutable slice.
+    ///
+    /// See [`IOBuf::make_unique`] for shared buffers.
     pub fn as_mut_slice(&mut self) -> &mut [u8] {
-        self.buf.as_mut_slice()
+        let (head, len) = (self.head, self.len);
+        &mut self.as_capacity_mut()[head..head + len]
     }
 
     pub fn len(&self) -> usize {
-        self.buf.len()
+        self.len
     }
 
     pub fn is_empty(&self) -> bool {
-        self.buf.is_empty()
+        self.len == 0
     }
 }
 
@@ -167,86 +894,390 @@ impl Index<usize> for IOBuf {
     /// Performs the indexing (`container[index]`) operation.
     #[inline]
     fn index(&self, index: usize) -> &Self::Output {
-        &self.buf[index]
+        &self.as_slice()[index]
     }
 }
 
 impl DmaObject for IOBuf {
-    /// Address of the IOBuf in main memory.
-    fn paddr(&self) -> PAddr {
-        PAddr::from(self.buf.as_ptr() as u64 - KERNEL_BASE)
+    /// Virtual address of the data.
+    fn vaddr(&self) -> VAddr {
+        VAddr::from(self.as_slice().as_ptr() as u64)
     }
 
-    /// Virtual address this buffer can be access by software.
-    fn vaddr(&self) -> VAddr {
-        VAddr::from(self.buf.as_ptr() as u64)
+    /// Address of the data for devices (based on the address returned by the
+    /// backend).
+    fn ioaddr(&self) -> Result<IOAddr, IOMemError> {
+        IOBuf::ioaddr(self)
     }
 }
 
+/// Buffers moved between the cache of a pool handle and the shared freelist
+/// at once.
+const POOL_BATCH: usize = 32;
+
 /// A pool of buffers IOBuf's with the same size and for the same the device.
+///
+/// Clones of the pool share its buffers (e.g., between the queues of a
+/// device). Every clone caches up to `2 * POOL_BATCH` buffers in front of
+/// the shared freelist and moves them in batches, so queues using a clone of
+/// their own rarely contend on the freelist. Buffers go back to the cache of
+/// the clone they were taken from, other clones only take them from there
+/// before they would allocate.
 pub struct IOBufPool {
-    /// Pool of buffers
-    pool: Vec<IOBuf>,
+    cache: Arc<PoolCache>,
+}
+
+/// The buffers cached by one clone of a pool.
+struct PoolCache {
+    shared: Arc<PoolShared>,
+    bufs: Mutex<Vec<IOBuf>>,
+}
+
+struct PoolShared {
+    /// Freelist of buffers not cached by a clone.
+    pool: Mutex<Vec<IOBuf>>,
+    /// The caches of all clones.
+    caches: Mutex<Vec<Weak<PoolCache>>>,
     /// The allocator used for new buffers
-    _allocator: DmaAllocator,
+    allocator: DmaAllocator,
     /// The allocation layout of the buffers
     layout: Layout,
+    /// The region buffers are carved from, `None` if they are allocated
+    /// on their own.
+    slab: Option<Arc<Slab>>,
+    /// Maximum number of outstanding buffers.
+    limit: usize,
+    /// Buffers handed out from the pool.
+    hits: AtomicUsize,
+    /// Buffers that had to be allocated.
+    misses: AtomicUsize,
+    /// Buffers handed out and not yet returned.
+    outstanding: AtomicUsize,
+    /// Buffers in the freelist and all caches.
+    free: AtomicUsize,
+}
+
+impl Drop for PoolCache {
+    fn drop(&mut self) {
+        let bufs = self.bufs.get_mut();
+        if !bufs.is_empty() {
+            self.shared.pool.lock().append(bufs);
+        }
+    }
+}
+
+/// Counters of an [`IOBufPool`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IOBufPoolStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub outstanding: usize,
+    /// Buffers in the pool (the freelist and all caches).
+    pub free: usize,
 }
 
 impl IOBufPool {
     pub fn new(len: usize, align: usize) -> Result<IOBufPool, IOMemError> {
-        let layout = Layout::from_size_align(len, align).expect("Layout was invalid.");
-        let allocator = DmaAllocator::default();
+        IOBufPool::new_in(len, align, DmaAllocator::default())
+    }
 
-        Ok(IOBufPool {
-            pool: vec![],
-            _allocator: allocator,
-            layout,
-        })
+    /// A pool allocating its buffers with `allocator`.
+    pub fn new_in(
+        len: usize,
+        align: usize,
+        allocator: DmaAllocator,
+    ) -> Result<IOBufPool, IOMemError> {
+        IOBufPool::with_limit(len, align, allocator, usize::MAX, 0)
     }
 
-    pub fn get_buf(&mut self) -> Result<IOBuf, IOMemError> {
-        if !self.pool.is_empty() {
-            let mut buf = self.pool.pop().expect("should have a buffer here");
-            buf.expand();
-            buf.clear();
-            Ok(buf)
-        } else {
-            IOBuf::new(self.layout)
+    /// A pool with at most `limit` outstanding buffers, `prepopulate` of
+    /// them are allocated right away.
+    pub fn with_limit(
+        len: usize,
+        align: usize,
+        allocator: DmaAllocator,
+        limit: usize,
+        prepopulate: usize,
+    ) -> Result<IOBufPool, IOMemError> {
+        let layout = Layout::from_size_align(len, align).expect("Layout was invalid.");
+
+        let mut pool = Vec::new();
+        pool.try_reserve_exact(cmp::min(limit, prepopulate))?;
+        for _ in 0..cmp::min(limit, prepopulate) {
+            pool.push(IOBuf::new_in(layout, allocator)?);
         }
+        Ok(IOBufPool::from_parts(pool, allocator, layout, None, limit))
     }
 
-    pub fn put_buf(&mut self, buf: IOBuf) {
-        self.pool.push(buf)
+    /// A pool of `count` buffers carved from one DMA region allocated with
+    /// `allocator` (e.g., a 2 MiB page with [`crate::mem::DevMemBackend`]),
+    /// aligned to `page_size`.
+    ///
+    /// The IO address of every buffer is the address of the region plus its
+    /// offset, so the region must be contiguous for devices. No buffer crosses
+    /// a `page_size` boundary. Copies made by [`IOBuf::make_unique`] are not
+    /// carved from the region and don't join the pool.
+    pub fn slab(
+        len: usize,
+        align: usize,
+        count: usize,
+        page_size: usize,
+        allocator: DmaAllocator,
+    ) -> Result<IOBufPool, IOMemError> {
+        let layout = Layout::from_size_align(len, align).map_err(|_| IOMemError::InvalidLayout)?;
+        let slab = Slab::new(layout, count, page_size, allocator)?;
+        Ok(IOBufPool::from_parts(
+            Vec::new(),
+            allocator,
+            layout,
+            Some(slab),
+            count,
+        ))
     }
-}
 
-#[derive(Debug)]
-/// An IO buffer.
-pub struct IOBufChain {
-    /// Completion queue index (set by driver),
-    /// TODO: remove once no longer necessary?
-    cqidx: usize,
+    fn from_parts(
+        pool: Vec<IOBuf>,
+        allocator: DmaAllocator,
+        layout: Layout,
+        slab: Option<Arc<Slab>>,
+        limit: usize,
+    ) -> IOBufPool {
+        let free = AtomicUsize::new(pool.len());
+        IOBufPool::with_cache(Arc::new(PoolShared {
+            pool: Mutex::new(pool),
+            caches: Mutex::new(Vec::new()),
+            allocator,
+            layout,
+            slab,
+            limit,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            outstanding: AtomicUsize::new(0),
+            free,
+        }))
+    }
 
-    /// Check sum flags (set by driver on rx)
-    pub csum_flags: u32,
+    /// A handle with an empty cache.
+    fn with_cache(shared: Arc<PoolShared>) -> IOBufPool {
+        let cache = Arc::new(PoolCache {
+            shared,
+            bufs: Mutex::new(Vec::new()),
+        });
+        let mut caches = cache.shared.caches.lock();
+        caches.retain(|cache| cache.strong_count() > 0);
+        caches.push(Arc::downgrade(&cache));
+        drop(caches);
+        IOBufPool { cache }
+    }
 
-    /// Checksum data (set by driver on rx)
-    pub csum_data: u32,
+    /// A buffer from the cache of another clone.
+    fn steal(&self) -> Option<IOBuf> {
+        let caches = self.shared().caches.lock();
+        caches
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find_map(|cache| cache.bufs.lock().pop())
+    }
 
-    /// VLAN tag (set by device driver on rx)
-    pub vtag: Option<u32>,
+    fn shared(&self) -> &PoolShared {
+        &self.cache.shared
+    }
 
-    /// Flags (to be used by device driver).
-    pub flags: u32,
+    /// A buffer that goes back to the pool when dropped.
+    ///
+    /// Fails with `PoolExhausted` if `limit` buffers are outstanding.
+    pub fn get(&self) -> Result<PooledIOBuf, IOMemError> {
+        Ok(PooledIOBuf {
+            buf: Some(self.get_buf()?),
+            pool: IOBufPool {
+                cache: self.cache.clone(),
+            },
+        })
+    }
 
-    /// Flow ID for RSS
-    pub rss_flow_id: Option<usize>,
+    /// A buffer that has to be returned with [`IOBufPool::put_buf`], it
+    /// counts as outstanding until then (or until its memory is freed).
+    ///
+    /// The buffer is empty, with all of its memory as tailroom.
+    pub fn get_buf(&self) -> Result<IOBuf, IOMemError> {
+        let shared = self.shared();
+        shared
+            .outstanding
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                if n < shared.limit {
+                    Some(n + 1)
+                } else {
+                    None
+                }
+            })
+            .map_err(|_| IOMemError::PoolExhausted)?;
 
-    /// RSS type
-    pub rss_type: u32,
+        let pooled = {
+            let mut cache = self.cache.bufs.lock();
+            if cache.is_empty() {
+                let mut pool = shared.pool.lock();
+                let batch = pool.len().saturating_sub(POOL_BATCH);
+                cache.extend(pool.drain(batch..));
+            }
+            cache.pop()
+        }
+        .or_else(|| self.steal());
+        let buf = match pooled {
+            Some(mut buf) => {
+                shared.free.fetch_sub(1, Ordering::Relaxed);
+                shared.hits.fetch_add(1, Ordering::Relaxed);
+                buf.reserve(0).map(|_| buf)
+            }
+            None => {
+                shared.misses.fetch_add(1, Ordering::Relaxed);
+                match &shared.slab {
+                    Some(slab) => Slab::carve(slab).map(IOBuf::from_storage),
+                    None => {
+                        IOBufStorage::new(shared.layout, shared.allocator).map(IOBuf::from_storage)
+                    }
+                }
+            }
+        };
+        match &buf {
+            Ok(buf) => *buf.storage.pool.lock() = Arc::downgrade(&self.cache.shared),
+            Err(_) => {
+                shared.outstanding.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+        buf
+    }
 
-    /// The `IOBuf` fragments
+    /// Returns a buffer from [`IOBufPool::get_buf`] (or adds another buffer
+    /// of the same size to the pool).
+    ///
+    /// Buffers still sharing their memory are dropped instead, as are
+    /// buffers not carved from the region of a pool from [`IOBufPool::slab`].
+    pub fn put_buf(&self, buf: IOBuf) {
+        let shared = self.shared();
+        debug_assert_eq!(buf.capacity(), shared.layout.size());
+        // Only buffers handed out by this pool are outstanding, and only
+        // until the first of their shares comes back.
+        let mut pool = buf.storage.pool.lock();
+        if core::ptr::eq(pool.as_ptr(), shared) {
+            *pool = Weak::new();
+            shared.outstanding.fetch_sub(1, Ordering::AcqRel);
+        }
+        drop(pool);
+        let foreign = match (&shared.slab, &buf.storage.slab) {
+            (Some(slab), Some((carved_from, _))) => !Arc::ptr_eq(slab, carved_from),
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if buf.is_shared() || foreign {
+            return;
+        }
+        shared.free.fetch_add(1, Ordering::Relaxed);
+        let mut cache = self.cache.bufs.lock();
+        cache.push(buf);
+        if cache.len() >= 2 * POOL_BATCH {
+            let batch = cache.len() - POOL_BATCH;
+            shared.pool.lock().extend(cache.drain(batch..));
+        }
+    }
+
+    pub fn stats(&self) -> IOBufPoolStats {
+        let shared = self.shared();
+        IOBufPoolStats {
+            hits: shared.hits.load(Ordering::Relaxed),
+            misses: shared.misses.load(Ordering::Relaxed),
+            outstanding: shared.outstanding.load(Ordering::Relaxed),
+            free: shared.free.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Another handle to the pool, with a cache of its own.
+impl Clone for IOBufPool {
+    fn clone(&self) -> Self {
+        IOBufPool::with_cache(self.cache.shared.clone())
+    }
+}
+
+impl fmt::Debug for IOBufPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IOBufPool")
+            .field("layout", &self.shared().layout)
+            .field("limit", &self.shared().limit)
+            .field("slab", &self.shared().slab.is_some())
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// A buffer of an [`IOBufPool`], returned to the pool when dropped.
+#[derive(Debug)]
+pub struct PooledIOBuf {
+    /// Only `None` while being dropped or taken.
+    buf: Option<IOBuf>,
+    pool: IOBufPool,
+}
+
+impl PooledIOBuf {
+    /// Takes the buffer out of the handle, it has to be returned with
+    /// [`IOBufPool::put_buf`].
+    pub fn into_inner(mut self) -> IOBuf {
+        self.buf.take().expect("buffer not taken yet")
+    }
+
+    /// The pool the buffer returns to.
+    pub fn pool(&self) -> &IOBufPool {
+        &self.pool
+    }
+}
+
+impl Deref for PooledIOBuf {
+    type Target = IOBuf;
+
+    fn deref(&self) -> &IOBuf {
+        self.buf.as_ref().expect("buffer not taken yet")
+    }
+}
+
+impl DerefMut for PooledIOBuf {
+    fn deref_mut(&mut self) -> &mut IOBuf {
+        self.buf.as_mut().expect("buffer not taken yet")
+    }
+}
+
+impl Drop for PooledIOBuf {
+    fn drop(&mut self) {
+        if let Some(buf) = self.buf.take() {
+            self.pool.put_buf(buf);
+        }
+    }
+}
+
+#[derive(Debug)]
+/// An IO buffer.
+pub struct IOBufChain {
+    /// Completion queue index (set by driver),
+    /// TODO: remove once no longer necessary?
+    cqidx: usize,
+
+    /// Check sum flags (set by driver on rx)
+    pub csum_flags: u32,
+
+    /// Checksum data (set by driver on rx)
+    pub csum_data: u32,
+
+    /// VLAN tag (set by device driver on rx)
+    pub vtag: Option<u32>,
+
+    /// Flags (to be used by device driver).
+    pub flags: u32,
+
+    /// Flow ID for RSS
+    pub rss_flow_id: Option<usize>,
+
+    /// RSS type
+    pub rss_type: u32,
+
+    /// The `IOBuf` fragments
     pub segments: VecDeque<IOBuf>,
 }
 
@@ -307,6 +1338,254 @@ impl IOBufChain {
     pub fn append(&mut self, buf: IOBuf) {
         self.segments.push_back(buf);
     }
+
+    /// Bytes of data in all segments.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|seg| seg.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.iter().all(|seg| seg.is_empty())
+    }
+
+    /// The segment containing byte `offset` of the chain and the offset
+    /// within it.
+    fn locate(&self, offset: usize) -> Option<(usize, usize)> {
+        let mut remaining = offset;
+        for (idx, seg) in self.segments.iter().enumerate() {
+            if remaining < seg.len() {
+                return Some((idx, remaining));
+            }
+            remaining -= seg.len();
+        }
+        None
+    }
+
+    /// Copy data out of the chain, starting at `offset` into `dst`.
+    ///
+    /// Returns the number of bytes copied, less than `dst.len()` if the
+    /// chain ends before.
+    pub fn copy_out_at(&self, offset: usize, dst: &mut [u8]) -> Result<usize, IOMemError> {
+        let (first, mut seg_offset) = match self.locate(offset) {
+            Some(location) => location,
+            None => return Ok(0),
+        };
+        let mut copied = 0;
+        for seg in self.segments.iter().skip(first) {
+            if copied == dst.len() {
+                break;
+            }
+            copied += seg.copy_out_at(seg_offset, &mut dst[copied..])?;
+            seg_offset = 0;
+        }
+        Ok(copied)
+    }
+
+    /// Copy `src` over the data of the chain, starting at `offset`.
+    ///
+    /// The chain is not extended, returns the number of bytes copied.
+    pub fn copy_in_at(&mut self, offset: usize, src: &[u8]) -> Result<usize, IOMemError> {
+        let (first, mut seg_offset) = match self.locate(offset) {
+            Some(location) => location,
+            None => return Ok(0),
+        };
+        let mut copied = 0;
+        for seg in self.segments.iter_mut().skip(first) {
+            if copied == src.len() {
+                break;
+            }
+            let cnt = cmp::min(seg.len() - seg_offset, src.len() - copied);
+            seg.make_unique()?;
+            seg.as_mut_slice()[seg_offset..seg_offset + cnt]
+                .copy_from_slice(&src[copied..copied + cnt]);
+            copied += cnt;
+            seg_offset = 0;
+        }
+        Ok(copied)
+    }
+
+    /// The first `n` bytes of the chain, `None` if they are not contiguous
+    /// in the first (non-empty) segment.
+    pub fn peek(&self, n: usize) -> Option<&[u8]> {
+        self.peek_at(0, n)
+    }
+
+    /// `n` bytes at `offset`, if they are in one segment.
+    fn peek_at(&self, offset: usize, n: usize) -> Option<&[u8]> {
+        if n == 0 {
+            return Some(&[]);
+        }
+        let (idx, seg_offset) = self.locate(offset)?;
+        self.segments[idx]
+            .as_slice()
+            .get(seg_offset..seg_offset + n)
+    }
+
+    /// Copies the data of all segments into one buffer (allocated like the
+    /// first segment).
+    pub fn linearize(&self) -> Result<IOBuf, IOMemError> {
+        let (align, allocator) = match self.segments.front() {
+            Some(seg) => (seg.storage.layout.align(), seg.storage.allocator),
+            None => (1, DmaAllocator::default()),
+        };
+        let len = self.len();
+        let layout = Layout::from_size_align(len, align).map_err(|_| IOMemError::OutOfBounds)?;
+        let mut buf = IOBuf::new_in(layout, allocator)?;
+        self.copy_out_at(0, buf.as_mut_slice())?;
+        Ok(buf)
+    }
+
+    /// Splits the chain at byte `at`, the returned chain has the data from
+    /// `at` on (and the same flags).
+    ///
+    /// A segment containing `at` is shared by both chains (see
+    /// [`IOBuf::slice`]).
+    pub fn split_off(&mut self, at: usize) -> Result<IOBufChain, IOMemError> {
+        let len = self.len();
+        if at > len {
+            return Err(IOMemError::OutOfBounds);
+        }
+        let mut tail = IOBufChain::new(self.flags, 0)?;
+        if let Some((idx, seg_offset)) = self.locate(at) {
+            if seg_offset == 0 {
+                tail.segments = self.segments.split_off(idx);
+            } else {
+                tail.segments = self.segments.split_off(idx + 1);
+                let seg = &mut self.segments[idx];
+                tail.segments.push_front(seg.slice(seg_offset..)?);
+                seg.truncate(seg_offset);
+            }
+        }
+        Ok(tail)
+    }
+
+    /// Appends the segments of `other` to the chain.
+    pub fn concat(&mut self, mut other: IOBufChain) {
+        self.segments.append(&mut other.segments);
+    }
+
+    /// Removes `n` bytes from the front of the chain, dropping segments that
+    /// become empty.
+    pub fn pull(&mut self, n: usize) -> Result<(), IOMemError> {
+        if n > self.len() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        let mut remaining = n;
+        while remaining > 0 {
+            let seg = self.segments.front_mut().expect("chain has enough data");
+            let cnt = cmp::min(seg.len(), remaining);
+            seg.pull(cnt)?;
+            remaining -= cnt;
+            if seg.is_empty() {
+                self.segments.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `n` bytes from the end of the chain, dropping segments that
+    /// become empty.
+    pub fn trim(&mut self, n: usize) -> Result<(), IOMemError> {
+        if n > self.len() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        let mut remaining = n;
+        while remaining > 0 {
+            let seg = self.segments.back_mut().expect("chain has enough data");
+            let cnt = cmp::min(seg.len(), remaining);
+            seg.trim(cnt)?;
+            remaining -= cnt;
+            if seg.is_empty() {
+                self.segments.pop_back();
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the first `n` bytes of the chain contiguous in the first
+    /// segment (e.g., to parse a header with [`IOBufChain::peek`]).
+    ///
+    /// Only the missing bytes are copied if they fit into the tailroom of
+    /// the first segment, otherwise the `n` bytes are copied to a new first
+    /// segment (with the same headroom).
+    pub fn pullup(&mut self, n: usize) -> Result<(), IOMemError> {
+        if n > self.len() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        let first = match self.segments.front() {
+            Some(first) if first.len() >= n => return Ok(()),
+            Some(first) => first,
+            None => return Ok(()),
+        };
+
+        if first.tailroom() >= n - first.len() {
+            let mut first = self.segments.pop_front().expect("chain has a segment");
+            let missing = n - first.len();
+            let result = first
+                .put(missing)
+                .and_then(|dst| self.copy_out_at(0, dst).map(|_| ()))
+                .and_then(|_| self.pull(missing));
+            self.segments.push_front(first);
+            return result;
+        }
+
+        let headroom = first.headroom();
+        let layout = Layout::from_size_align(headroom + n, first.storage.layout.align())
+            .map_err(|_| IOMemError::OutOfBounds)?;
+        let mut buf = IOBuf::new_in(layout, first.storage.allocator)?;
+        buf.reserve(headroom)?;
+        self.copy_out_at(0, buf.put(n)?)?;
+        self.pull(n)?;
+        self.segments.push_front(buf);
+        Ok(())
+    }
+
+    /// The DMA segments of the data, using the IO address of every segment
+    /// (i.e., the memory of each `IOBuf` is contiguous for devices).
+    ///
+    /// Adjacent segments are merged, and split at `max_segment_size`.
+    pub fn sg_list(&self, limits: &SgLimits) -> Result<Vec<(IOAddr, usize)>, IOMemError> {
+        let mut list = SgList::new(limits)?;
+        for seg in self.segments.iter() {
+            list.push(seg.ioaddr()?, seg.len())?;
+        }
+        Ok(list.segments)
+    }
+
+    /// Like [`IOBufChain::sg_list`] for memory that is only contiguous within
+    /// pages of `page_size`, every page is translated with `translator`.
+    pub fn sg_list_translated(
+        &self,
+        limits: &SgLimits,
+        translator: &dyn AddressTranslator,
+        page_size: usize,
+    ) -> Result<Vec<(IOAddr, usize)>, IOMemError> {
+        if page_size == 0 {
+            return Err(IOMemError::InvalidSgLimits);
+        }
+        let mut list = SgList::new(limits)?;
+        for seg in self.segments.iter() {
+            let mut vaddr = seg.vaddr().as_u64();
+            let mut len = seg.len();
+            while len > 0 {
+                let cnt = cmp::min(len, page_size - (vaddr % page_size as u64) as usize);
+                list.push(translator.virt_to_io(VAddr::from(vaddr))?, cnt)?;
+                vaddr += cnt as u64;
+                len -= cnt;
+            }
+        }
+        Ok(list.segments)
+    }
+
+    /// A cursor reading the chain from the start.
+    pub fn cursor(&self) -> IOBufCursor<&IOBufChain> {
+        IOBufCursor::new(self)
+    }
+
+    /// A cursor reading and writing the chain from the start.
+    pub fn cursor_mut(&mut self) -> IOBufCursor<&mut IOBufChain> {
+        IOBufCursor::new(self)
+    }
 }
 
 /// implementation for the index operator [] on IOBuf
@@ -315,17 +1594,894 @@ impl Index<usize> for IOBufChain {
     type Output = u8;
 
     /// Performs the indexing (`container[index]`) operation.
+    ///
+    /// Panics if `index` is beyond the data of the chain.
     fn index(&self, index: usize) -> &Self::Output {
-        let mut cidx = index;
-        let nseg = self.segments.len();
-        for i in 0..nseg {
-            let seglen = self.segments[i].len();
-            if index < seglen {
-                return &self.segments[i][cidx];
+        match self.locate(index) {
+            Some((idx, seg_offset)) => &self.segments[idx][seg_offset],
+            None => panic!("index {} out of range for chain of {}", index, self.len()),
+        }
+    }
+}
+
+/// Reads (and writes) the data of an [`IOBufChain`] as one contiguous stream
+/// of bytes, across segment boundaries.
+#[derive(Debug)]
+pub struct IOBufCursor<C> {
+    chain: C,
+    pos: usize,
+}
+
+impl<C: Borrow<IOBufChain>> IOBufCursor<C> {
+    pub fn new(chain: C) -> Self {
+        IOBufCursor { chain, pos: 0 }
+    }
+
+    /// Offset of the cursor in the chain.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Bytes left after the cursor.
+    pub fn remaining(&self) -> usize {
+        self.chain.borrow().len().saturating_sub(self.pos)
+    }
+
+    /// Moves the cursor to `pos` (at most the end of the chain).
+    pub fn seek(&mut self, pos: usize) -> Result<(), IOMemError> {
+        if pos > self.chain.borrow().len() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        self.pos = pos;
+        Ok(())
+    }
+
+    /// Moves the cursor `n` bytes forward.
+    pub fn skip(&mut self, n: usize) -> Result<(), IOMemError> {
+        self.seek(self.pos.checked_add(n).ok_or(IOMemError::OutOfBounds)?)
+    }
+
+    /// The next `n` bytes without moving the cursor, `None` if they are not
+    /// contiguous in one segment.
+    pub fn peek(&self, n: usize) -> Option<&[u8]> {
+        self.chain.borrow().peek_at(self.pos, n)
+    }
+
+    /// Reads as many bytes as available into `dst`, returns the number of
+    /// bytes read.
+    pub fn read(&mut self, dst: &mut [u8]) -> Result<usize, IOMemError> {
+        let cnt = self.chain.borrow().copy_out_at(self.pos, dst)?;
+        self.pos += cnt;
+        Ok(cnt)
+    }
+
+    /// Reads exactly `dst.len()` bytes, the cursor doesn't move if there are
+    /// not enough.
+    pub fn read_exact(&mut self, dst: &mut [u8]) -> Result<(), IOMemError> {
+        if dst.len() > self.remaining() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        self.read(dst).map(|_| ())
+    }
+}
+
+/// What DMA segments a device supports (e.g., in its descriptors).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SgLimits {
+    /// Maximum length of a segment, must not be zero.
+    pub max_segment_size: usize,
+    /// Maximum number of segments for one transfer.
+    pub max_segments: usize,
+}
+
+impl Default for SgLimits {
+    fn default() -> SgLimits {
+        SgLimits {
+            max_segment_size: usize::MAX,
+            max_segments: usize::MAX,
+        }
+    }
+}
+
+/// Builds a scatter-gather list within `limits`.
+struct SgList<'a> {
+    limits: &'a SgLimits,
+    segments: Vec<(IOAddr, usize)>,
+}
+
+impl<'a> SgList<'a> {
+    fn new(limits: &'a SgLimits) -> Result<SgList<'a>, IOMemError> {
+        if limits.max_segment_size == 0 {
+            return Err(IOMemError::InvalidSgLimits);
+        }
+        Ok(SgList {
+            limits,
+            segments: Vec::new(),
+        })
+    }
+
+    /// Adds `len` bytes at `ioaddr`, extending the last segment if they
+    /// follow it.
+    fn push(&mut self, mut ioaddr: IOAddr, mut len: usize) -> Result<(), IOMemError> {
+        let max = self.limits.max_segment_size;
+        while len > 0 {
+            let cnt = match self.segments.last_mut() {
+                Some(last) if last.0 + last.1 as u64 == ioaddr && last.1 < max => {
+                    let cnt = cmp::min(len, max - last.1);
+                    last.1 += cnt;
+                    cnt
+                }
+                _ => {
+                    if self.segments.len() >= self.limits.max_segments {
+                        return Err(IOMemError::TooManySegments);
+                    }
+                    let cnt = cmp::min(len, max);
+                    self.segments.push((ioaddr, cnt));
+                    cnt
+                }
+            };
+            ioaddr += cnt as u64;
+            len -= cnt;
+        }
+        Ok(())
+    }
+}
+
+/// Who accesses a buffer mapped for a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads the buffer (e.g., tx).
+    ToDevice,
+    /// The device writes the buffer (e.g., rx).
+    FromDevice,
+    Bidirectional,
+}
+
+/// Counters of [`BounceBuffers`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BounceStats {
+    /// Buffers mapped.
+    pub mapped: usize,
+    /// Buffers that had to be bounced.
+    pub bounced: usize,
+    /// Bytes copied to and from bounce buffers.
+    pub copied: usize,
+}
+
+/// Maps buffers for a device with a DMA mask, buffers the device can't reach
+/// are copied to (bounce) buffers within the mask.
+#[derive(Debug)]
+pub struct BounceBuffers {
+    /// Allocates the bounce buffers, with the mask of the device.
+    allocator: DmaAllocator,
+    mapped: AtomicUsize,
+    bounced: AtomicUsize,
+    copied: AtomicUsize,
+}
+
+/// The original of a buffer mapped with [`BounceBuffers::map`], needed to
+/// unmap it.
+#[must_use]
+#[derive(Debug)]
+pub struct Bounced {
+    /// `None` if the buffer was not bounced.
+    original: Option<IOBuf>,
+    direction: DmaDirection,
+}
+
+impl Bounced {
+    pub fn is_bounced(&self) -> bool {
+        self.original.is_some()
+    }
+}
+
+impl BounceBuffers {
+    /// Bounces to memory allocated with `allocator`, for a device that
+    /// reaches IO addresses up to `allocator.mask()`.
+    pub fn new(allocator: DmaAllocator) -> BounceBuffers {
+        BounceBuffers {
+            allocator,
+            mapped: AtomicUsize::new(0),
+            bounced: AtomicUsize::new(0),
+            copied: AtomicUsize::new(0),
+        }
+    }
+
+    /// Makes `buf` reachable for the device, by replacing it with a bounce
+    /// buffer if necessary (with the data for `ToDevice` and
+    /// `Bidirectional`).
+    pub fn map(&self, buf: &mut IOBuf, direction: DmaDirection) -> Result<Bounced, IOMemError> {
+        if within_mask(buf.ioaddr()?, buf.len(), self.allocator.mask()) {
+            self.mapped.fetch_add(1, Ordering::Relaxed);
+            return Ok(Bounced {
+                original: None,
+                direction,
+            });
+        }
+
+        let mut bounce = IOBuf::new_in(buf.storage.layout, self.allocator)?;
+        bounce.reserve(buf.headroom())?;
+        let data = bounce.put(buf.len())?;
+        if direction != DmaDirection::FromDevice {
+            data.copy_from_slice(buf.as_slice());
+            self.copied.fetch_add(buf.len(), Ordering::Relaxed);
+        }
+        self.mapped.fetch_add(1, Ordering::Relaxed);
+        self.bounced.fetch_add(1, Ordering::Relaxed);
+        Ok(Bounced {
+            original: Some(core::mem::replace(buf, bounce)),
+            direction,
+        })
+    }
+
+    /// Puts the original back once the device is done with `buf`, copying
+    /// the data of a bounce buffer (for `FromDevice` and `Bidirectional`).
+    pub fn unmap(&self, buf: &mut IOBuf, bounced: Bounced) -> Result<(), IOMemError> {
+        let mut original = match bounced.original {
+            Some(original) => original,
+            None => return Ok(()),
+        };
+        if bounced.direction != DmaDirection::ToDevice {
+            let cnt = original.copy_in(buf.as_slice())?;
+            self.copied.fetch_add(cnt, Ordering::Relaxed);
+        }
+        *buf = original;
+        Ok(())
+    }
+
+    /// Maps all segments of `chain`, see [`BounceBuffers::map`].
+    pub fn map_chain(
+        &self,
+        chain: &mut IOBufChain,
+        direction: DmaDirection,
+    ) -> Result<Vec<Bounced>, IOMemError> {
+        let mut mapped = Vec::new();
+        mapped.try_reserve_exact(chain.segments.len())?;
+        for idx in 0..chain.segments.len() {
+            match self.map(&mut chain.segments[idx], direction) {
+                Ok(bounced) => mapped.push(bounced),
+                Err(e) => {
+                    // Nothing was transferred yet, just put the originals back.
+                    for (seg, bounced) in chain.segments.iter_mut().zip(mapped) {
+                        if let Some(original) = bounced.original {
+                            *seg = original;
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(mapped)
+    }
+
+    /// Unmaps all segments of `chain`, see [`BounceBuffers::unmap`].
+    pub fn unmap_chain(
+        &self,
+        chain: &mut IOBufChain,
+        mapped: Vec<Bounced>,
+    ) -> Result<(), IOMemError> {
+        assert_eq!(chain.segments.len(), mapped.len(), "chain was mapped");
+        for (seg, bounced) in chain.segments.iter_mut().zip(mapped) {
+            self.unmap(seg, bounced)?;
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> BounceStats {
+        BounceStats {
+            mapped: self.mapped.load(Ordering::Relaxed),
+            bounced: self.bounced.load(Ordering::Relaxed),
+            copied: self.copied.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<C: BorrowMut<IOBufChain>> IOBufCursor<C> {
+    /// Overwrites as many bytes as available with `src`, returns the number
+    /// of bytes written.
+    pub fn write(&mut self, src: &[u8]) -> Result<usize, IOMemError> {
+        let cnt = self.chain.borrow_mut().copy_in_at(self.pos, src)?;
+        self.pos += cnt;
+        Ok(cnt)
+    }
+
+    /// Overwrites exactly `src.len()` bytes, the cursor doesn't move if there
+    /// are not enough.
+    pub fn write_all(&mut self, src: &[u8]) -> Result<(), IOMemError> {
+        if src.len() > self.remaining() {
+            return Err(IOMemError::OutOfBounds);
+        }
+        self.write(src).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    /// Hands out IO addresses from 0x8000_0000 (from 0x10_0000 within a
+    /// mask) and records live mappings.
+    #[derive(Default)]
+    struct FakeMapper {
+        mappings: Mutex<Vec<(VAddr, IOAddr, usize)>>,
+    }
+
+    impl IoMapper for FakeMapper {
+        fn map(&self, vaddr: VAddr, len: usize) -> Result<IOAddr, IOMemError> {
+            let mut mappings = self.mappings.lock();
+            let ioaddr = IOAddr::from(0x8000_0000 + 0x10_0000 * mappings.len() as u64);
+            mappings.push((vaddr, ioaddr, len));
+            Ok(ioaddr)
+        }
+
+        fn map_within(&self, vaddr: VAddr, len: usize, mask: u64) -> Result<IOAddr, IOMemError> {
+            let mut mappings = self.mappings.lock();
+            let ioaddr = IOAddr::from(0x10_0000 * (mappings.len() as u64 + 1));
+            if !within_mask(ioaddr, len, mask) {
+                return Err(IOMemError::BeyondDmaMask);
             }
-            cidx -= seglen;
+            mappings.push((vaddr, ioaddr, len));
+            Ok(ioaddr)
+        }
+
+        fn unmap(&self, ioaddr: IOAddr, len: usize) {
+            let mut mappings = self.mappings.lock();
+            let idx = mappings
+                .iter()
+                .position(|m| m.1 == ioaddr && m.2 == len)
+                .expect("unmapping unknown mapping");
+            mappings.remove(idx);
         }
-        // error here?
-        &self.segments[0][0]
+    }
+
+    fn fake_backend() -> &'static MappedBackend<FakeMapper> {
+        Box::leak(Box::new(MappedBackend::new(FakeMapper::default())))
+    }
+
+    #[test]
+    fn iobuf_from_backend() {
+        let backend = fake_backend();
+        let allocator = DmaAllocator::with_backend(backend);
+        let layout = Layout::from_size_align(2048, 64).unwrap();
+
+        let mut buf = IOBuf::new_in(layout, allocator).unwrap();
+        assert_eq!(buf.len(), 2048);
+        assert!(buf.as_slice().iter().all(|b| *b == 0));
+        let (vaddr, ioaddr, len) = backend.mapper().mappings.lock()[0];
+        assert_eq!(
+            (buf.vaddr(), buf.ioaddr().unwrap(), len),
+            (vaddr, ioaddr, 2048)
+        );
+        assert_eq!(
+            DmaObject::ioaddr(&buf).unwrap(),
+            IOAddr::from(0x8000_0000u64)
+        );
+
+        buf.clear();
+        assert_eq!(buf.copy_in_at(4, b"data").unwrap(), 4);
+        assert_eq!(buf.as_slice(), b"\0\0\0\0data");
+        let mut out = [0u8; 4];
+        assert_eq!(buf.copy_out_at(4, &mut out).unwrap(), 4);
+        assert_eq!(&out, b"data");
+
+        drop(buf);
+        assert!(backend.mapper().mappings.lock().is_empty());
+    }
+
+    #[test]
+    fn allocator_interface() {
+        let backend = fake_backend();
+        let mut v: Vec<u8, DmaAllocator> =
+            Vec::with_capacity_in(100, DmaAllocator::with_backend(backend));
+        v.extend_from_slice(b"data");
+        assert_eq!(backend.mapper().mappings.lock().len(), 1);
+        drop(v);
+        assert!(backend.mapper().mappings.lock().is_empty());
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let beyond = OffsetBackend { offset: u64::MAX };
+        assert!(matches!(
+            beyond.allocate(layout),
+            Err(IOMemError::NotMapped)
+        ));
+    }
+
+    #[test]
+    fn pool_uses_allocator() {
+        let backend = fake_backend();
+        let pool = IOBufPool::new_in(512, 512, DmaAllocator::with_backend(backend)).unwrap();
+
+        let first = pool.get_buf().unwrap();
+        let second = pool.get_buf().unwrap();
+        assert_eq!(backend.mapper().mappings.lock().len(), 2);
+        assert_ne!(first.ioaddr().unwrap(), second.ioaddr().unwrap());
+        pool.put_buf(first);
+        drop(second);
+        assert_eq!(backend.mapper().mappings.lock().len(), 1);
+    }
+
+    #[test]
+    fn translated_backend() {
+        static DIRECT_MAP: OffsetTranslator = OffsetTranslator { offset: 0x1000 };
+        static NOWHERE: OffsetTranslator = OffsetTranslator { offset: u64::MAX };
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        let allocator =
+            DmaAllocator::with_backend(Box::leak(Box::new(TranslatedBackend::new(&DIRECT_MAP))));
+        let buf = IOBuf::new_in(layout, allocator).unwrap();
+        assert_eq!(
+            buf.ioaddr().unwrap().as_u64(),
+            buf.vaddr().as_u64() - 0x1000,
+        );
+
+        let allocator =
+            DmaAllocator::with_backend(Box::leak(Box::new(TranslatedBackend::new(&NOWHERE))));
+        let buf = IOBuf::new_in(layout, allocator).unwrap();
+        assert!(matches!(
+            buf.ioaddr(),
+            Err(IOMemError::Untranslatable { .. })
+        ));
+    }
+
+    #[test]
+    fn headroom_and_tailroom() {
+        let allocator = DmaAllocator::with_backend(fake_backend());
+        let mut buf = IOBuf::new_in(Layout::from_size_align(128, 64).unwrap(), allocator).unwrap();
+        let base = buf.ioaddr().unwrap();
+
+        buf.reserve(32).unwrap();
+        assert_eq!((buf.headroom(), buf.len(), buf.tailroom()), (32, 0, 96));
+        buf.copy_in(b"payload").unwrap();
+        buf.push(4).unwrap().copy_from_slice(b"hdr:");
+        buf.put(2).unwrap().copy_from_slice(b"\r\n");
+        assert_eq!(buf.as_slice(), b"hdr:payload\r\n");
+        assert_eq!(buf.ioaddr().unwrap(), base + 28u64);
+        assert_eq!(buf.vaddr().as_u64(), buf.as_slice().as_ptr() as u64);
+
+        buf.pull(4).unwrap();
+        buf.trim(2).unwrap();
+        assert_eq!(buf.as_slice(), b"payload");
+        assert!(matches!(buf.push(33), Err(IOMemError::OutOfBounds)));
+        assert!(matches!(buf.put(90), Err(IOMemError::OutOfBounds)));
+        assert!(matches!(buf.pull(8), Err(IOMemError::OutOfBounds)));
+        assert!(buf.reserve(129).is_err());
+    }
+
+    #[test]
+    fn shared_and_sliced() {
+        let backend = fake_backend();
+        let allocator = DmaAllocator::with_backend(backend);
+        let mut buf = IOBuf::new_in(Layout::from_size_align(64, 64).unwrap(), allocator).unwrap();
+        buf.reserve(16).unwrap();
+        buf.copy_in(b"hello world").unwrap();
+
+        let copy = buf.share();
+        let world = buf.slice(6..).unwrap();
+        assert!(buf.is_shared() && world.is_shared());
+        assert_eq!(world.as_slice(), b"world");
+        assert_eq!(world.ioaddr().unwrap(), buf.ioaddr().unwrap() + 6u64);
+        assert_eq!(backend.mapper().mappings.lock().len(), 1);
+        assert!(buf.slice(..12).is_err());
+
+        // Writing copies the data, the other buffers keep the original.
+        buf.as_mut_slice()[0] = b'j';
+        buf.push(2).unwrap().copy_from_slice(b"> ");
+        assert!(!buf.is_shared());
+        assert_eq!(buf.as_slice(), b"> jello world");
+        assert_eq!(copy.as_slice(), b"hello world");
+        assert_eq!(backend.mapper().mappings.lock().len(), 2);
+
+        drop(copy);
+        let mut world = world;
+        assert!(!world.is_shared());
+        world.as_mut_slice()[0] = b'W';
+        assert_eq!(backend.mapper().mappings.lock().len(), 2);
+        drop(world);
+        assert_eq!(backend.mapper().mappings.lock().len(), 1);
+    }
+
+    fn chain_of(allocator: DmaAllocator, parts: &[&[u8]]) -> IOBufChain {
+        let mut chain = IOBufChain::new(0, parts.len()).unwrap();
+        for part in parts {
+            let layout = Layout::from_size_align(part.len(), 8).unwrap();
+            let mut buf = IOBuf::new_in(layout, allocator).unwrap();
+            buf.copy_in(part).unwrap();
+            chain.append(buf);
+        }
+        chain
+    }
+
+    #[test]
+    fn chain_cursor() {
+        let allocator = DmaAllocator::with_backend(fake_backend());
+        let mut chain = chain_of(allocator, &[b"abc", b"", b"defg", b"h"]);
+        assert_eq!(chain.len(), 8);
+        assert_eq!(chain[3], b'd');
+        assert_eq!(chain[7], b'h');
+
+        let mut out = [0u8; 4];
+        assert_eq!(chain.copy_out_at(2, &mut out).unwrap(), 4);
+        assert_eq!(&out, b"cdef");
+        assert_eq!(chain.copy_out_at(6, &mut out).unwrap(), 2);
+        assert_eq!(chain.peek(3), Some(&b"abc"[..]));
+        assert_eq!(chain.peek(4), None);
+
+        let mut cursor = chain.cursor();
+        cursor.skip(2).unwrap();
+        assert_eq!(cursor.peek(2), None);
+        cursor.read_exact(&mut out[..3]).unwrap();
+        assert_eq!(&out[..3], b"cde");
+        assert_eq!(cursor.peek(2), Some(&b"fg"[..]));
+        assert!(cursor.read_exact(&mut [0u8; 4]).is_err());
+        assert_eq!(cursor.position(), 5);
+        assert!(cursor.seek(9).is_err());
+
+        let mut cursor = chain.cursor_mut();
+        cursor.seek(1).unwrap();
+        cursor.write_all(b"BCDE").unwrap();
+        assert!(cursor.write_all(b"FGHI").is_err());
+        assert_eq!(chain.linearize().unwrap().as_slice(), b"aBCDEfgh");
+    }
+
+    #[test]
+    #[should_panic]
+    fn chain_index_out_of_range() {
+        let allocator = DmaAllocator::with_backend(fake_backend());
+        let chain = chain_of(allocator, &[b"abc"]);
+        let _ = chain[3];
+    }
+
+    #[test]
+    fn chain_surgery() {
+        let backend = fake_backend();
+        let allocator = DmaAllocator::with_backend(backend);
+        let mut chain = chain_of(allocator, &[b"abc", b"defg", b"hi"]);
+
+        let mut tail = chain.split_off(5).unwrap();
+        assert_eq!(chain.linearize().unwrap().as_slice(), b"abcde");
+        assert_eq!(tail.linearize().unwrap().as_slice(), b"fghi");
+        assert_eq!(tail.segments.len(), 2);
+        assert!(tail.segments[0].is_shared());
+        assert_eq!(chain.split_off(3).unwrap().segments.len(), 1);
+        assert!(chain.split_off(4).is_err());
+
+        chain.concat(tail.split_off(4).unwrap());
+        chain.concat(tail);
+        assert_eq!(chain.linearize().unwrap().as_slice(), b"abcfghi");
+
+        chain.pull(4).unwrap();
+        chain.trim(2).unwrap();
+        assert_eq!(chain.segments.len(), 1);
+        assert_eq!(chain.segments[0].as_slice(), b"g");
+        assert!(chain.pull(2).is_err());
+        chain.trim(1).unwrap();
+        assert!(chain.is_empty() && chain.segments.is_empty());
+
+        // Fits into the tailroom of the first segment
+        let mut chain = chain_of(allocator, &[b"abXXXX", b"cd", b"efg"]);
+        chain.segments[0].trim(4).unwrap();
+        let mappings = backend.mapper().mappings.lock().len();
+        chain.pullup(5).unwrap();
+        assert_eq!(backend.mapper().mappings.lock().len(), mappings - 1);
+        assert_eq!(chain.peek(5), Some(&b"abcde"[..]));
+        assert_eq!(chain.segments.len(), 2);
+        assert_eq!(chain.segments[1].as_slice(), b"fg");
+
+        // Needs a new first segment
+        let mut chain = chain_of(allocator, &[b"ab", b"cd", b"efg"]);
+        let mappings = backend.mapper().mappings.lock().len();
+        chain.pullup(5).unwrap();
+        assert_eq!(backend.mapper().mappings.lock().len(), mappings - 1);
+        assert_eq!(chain.peek(5), Some(&b"abcde"[..]));
+        assert_eq!(chain.linearize().unwrap().as_slice(), b"abcdefg");
+        assert!(chain.pullup(8).is_err());
+    }
+
+    #[test]
+    fn pooled_buffers() {
+        let backend = fake_backend();
+        let allocator = DmaAllocator::with_backend(backend);
+        let pool = IOBufPool::with_limit(256, 64, allocator, 3, 2).unwrap();
+        assert_eq!(backend.mapper().mappings.lock().len(), 2);
+
+        let mut first = pool.get().unwrap();
+        first.copy_in(b"data").unwrap();
+        let second = pool.clone().get().unwrap();
+        let third = pool.get_buf().unwrap();
+        assert!(second.is_empty() && third.is_empty());
+        assert!(matches!(pool.get(), Err(IOMemError::PoolExhausted)));
+        assert_eq!(
+            pool.stats(),
+            IOBufPoolStats {
+                hits: 2,
+                misses: 1,
+                outstanding: 3,
+                free: 0
+            }
+        );
+
+        drop(first);
+        let shared = second.share();
+        drop(second);
+        pool.put_buf(third);
+        let stats = pool.stats();
+        assert_eq!((stats.outstanding, stats.free), (0, 2));
+        assert!(pool.get().unwrap().is_empty());
+
+        let taken = pool.get().unwrap().into_inner();
+        assert_eq!(pool.stats().outstanding, 1);
+        pool.put_buf(taken);
+
+        // Buffers that never came from the pool can be added to it.
+        let layout = Layout::from_size_align(256, 64).unwrap();
+        pool.put_buf(IOBuf::new_in(layout, allocator).unwrap());
+        let stats = pool.stats();
+        assert_eq!((stats.outstanding, stats.free), (0, 3));
+        drop(shared);
+        drop(pool);
+        assert!(backend.mapper().mappings.lock().is_empty());
+    }
+
+    #[test]
+    fn pool_copy_on_write() {
+        let allocator = DmaAllocator::with_backend(fake_backend());
+        let pool = IOBufPool::with_limit(64, 64, allocator, 1, 0).unwrap();
+
+        // Writing to the pooled buffer copies it away from the share.
+        let mut buf = pool.get().unwrap();
+        let shared = buf.share();
+        buf.copy_in(b"data").unwrap();
+        drop((buf, shared));
+        assert_eq!(pool.stats().outstanding, 0);
+
+        // The same if the share is written to instead.
+        let buf = pool.get().unwrap();
+        let mut shared = buf.share();
+        shared.copy_in(b"data").unwrap();
+        drop((buf, shared));
+        assert_eq!(pool.stats().outstanding, 0);
+
+        // And if the buffer is dropped instead of returned.
+        drop(pool.get_buf().unwrap());
+        assert!(pool.get().is_ok());
+    }
+
+    #[test]
+    fn pool_clones() {
+        let allocator = DmaAllocator::with_backend(fake_backend());
+        let pool = IOBufPool::slab(512, 64, 2, 4096, allocator).unwrap();
+        let queue = pool.clone();
+        drop((queue.get().unwrap(), queue.get().unwrap()));
+
+        // Both buffers are in the cache of `queue` now.
+        let (first, second) = (pool.get().unwrap(), pool.get().unwrap());
+        assert!(matches!(pool.get(), Err(IOMemError::PoolExhausted)));
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+        drop((first, second, queue));
+        assert_eq!(pool.stats().free, 2);
+    }
+
+    #[test]
+    fn slab_pool() {
+        let backend = fake_backend();
+        let allocator = DmaAllocator::with_backend(backend);
+        let pool = IOBufPool::slab(1500, 64, 5, 4096, allocator).unwrap();
+        let (vaddr, base, len) = backend.mapper().mappings.lock()[0];
+        assert_eq!(len, 3 * 4096);
+
+        let mut bufs: Vec<PooledIOBuf> = (0..5).map(|_| pool.get().unwrap()).collect();
+        let offsets: Vec<u64> = bufs
+            .iter()
+            .map(|b| b.ioaddr().unwrap().as_u64() - base.as_u64())
+            .collect();
+        assert_eq!(offsets, [0, 1536, 4096, 4096 + 1536, 8192]);
+        assert_eq!(bufs[1].vaddr().as_u64(), vaddr.as_u64() + 1536);
+        assert_eq!((bufs[4].len(), bufs[4].capacity()), (0, 1500));
+        assert!(matches!(pool.get(), Err(IOMemError::PoolExhausted)));
+        assert_eq!(backend.mapper().mappings.lock().len(), 1);
+
+        bufs[2].expand();
+        bufs[2].as_mut_slice()[0] = 0xaa;
+        let second = bufs.remove(1);
+        let shared = second.share();
+        drop(second);
+        drop(bufs.remove(1));
+        let reused = pool.get().unwrap();
+        assert_eq!(reused.ioaddr().unwrap(), base + 4096u64);
+        assert!(matches!(pool.get(), Err(IOMemError::PoolExhausted)));
+
+        // Copies outside of the region don't join the pool.
+        let mut last = bufs.pop().unwrap();
+        let copied = last.share();
+        last.copy_in(b"data").unwrap();
+        assert_eq!(backend.mapper().mappings.lock().len(), 2);
+        drop(last);
+        assert_eq!(backend.mapper().mappings.lock().len(), 1);
+        assert_eq!(pool.stats().free, 0);
+        drop(copied);
+
+        // The region stays until the last buffer carved from it is gone.
+        drop((pool, bufs, reused));
+        assert_eq!(backend.mapper().mappings.lock().len(), 1);
+        drop(shared);
+        assert!(backend.mapper().mappings.lock().is_empty());
+
+        assert!(matches!(
+            IOBufPool::slab(4097, 64, 1, 4096, allocator),
+            Err(IOMemError::InvalidLayout)
+        ));
+    }
+
+    #[test]
+    fn scatter_gather() {
+        let allocator = DmaAllocator::with_backend(fake_backend());
+        let mut whole =
+            IOBuf::new_in(Layout::from_size_align(0x3000, 64).unwrap(), allocator).unwrap();
+        whole.pull(0x100).unwrap();
+        let base = whole.ioaddr().unwrap();
+        let mut chain = IOBufChain::new(0, 4).unwrap();
+        chain.append(whole.slice(..0x800).unwrap());
+        chain.append(whole.slice(0x800..0x2000).unwrap());
+        chain.append(whole.slice(0x2000..0x2000).unwrap());
+        chain.append(
+            chain_of(allocator, &[b"tail"])
+                .segments
+                .pop_front()
+                .unwrap(),
+        );
+        let tail = chain.segments[3].ioaddr().unwrap();
+
+        assert_eq!(
+            chain.sg_list(&SgLimits::default()).unwrap(),
+            [(base, 0x2000), (tail, 4)]
+        );
+        let limits = SgLimits {
+            max_segment_size: 0x1000,
+            max_segments: 4,
+        };
+        assert_eq!(
+            chain.sg_list(&limits).unwrap(),
+            [(base, 0x1000), (base + 0x1000u64, 0x1000), (tail, 4)]
+        );
+        let limits = SgLimits {
+            max_segments: 2,
+            ..limits
+        };
+        assert!(matches!(
+            chain.sg_list(&limits),
+            Err(IOMemError::TooManySegments)
+        ));
+        let zero = SgLimits {
+            max_segment_size: 0,
+            ..limits
+        };
+        assert!(matches!(
+            chain.sg_list(&zero),
+            Err(IOMemError::InvalidSgLimits)
+        ));
+
+        // Every page of the first buffer ends up somewhere else.
+        struct Scattered;
+        impl AddressTranslator for Scattered {
+            fn virt_to_phys(&self, vaddr: VAddr) -> Result<PAddr, IOMemError> {
+                let (page, offset) = (vaddr.as_u64() / 0x1000, vaddr.as_u64() % 0x1000);
+                Ok(PAddr::from(((page % 64) << 20) + offset))
+            }
+        }
+        chain.segments.pop_back();
+        let vaddr = chain.segments[0].vaddr().as_u64();
+        let list = chain
+            .sg_list_translated(&SgLimits::default(), &Scattered, 0x1000)
+            .unwrap();
+        let lens: Vec<usize> = list.iter().map(|s| s.1).collect();
+        assert_eq!(lens.iter().sum::<usize>(), 0x2000);
+        assert_eq!(list.len(), if vaddr % 0x1000 == 0 { 2 } else { 3 });
+        assert_eq!(list[0].0, Scattered.virt_to_io(VAddr::from(vaddr)).unwrap());
+        assert!(matches!(
+            chain.sg_list_translated(&SgLimits::default(), &Scattered, 0),
+            Err(IOMemError::InvalidSgLimits)
+        ));
+    }
+
+    #[test]
+    fn dma_mask_and_bounce() {
+        /// Places all memory above 4 GiB.
+        struct High;
+        impl AddressTranslator for High {
+            fn virt_to_phys(&self, vaddr: VAddr) -> Result<PAddr, IOMemError> {
+                Ok(PAddr::from((1 << 40) + (vaddr.as_u64() & 0xffff_ffff)))
+            }
+        }
+        /// Places all memory below 4 GiB.
+        struct Low;
+        impl AddressTranslator for Low {
+            fn virt_to_phys(&self, vaddr: VAddr) -> Result<PAddr, IOMemError> {
+                Ok(PAddr::from(vaddr.as_u64() & 0x7fff_ffff))
+            }
+        }
+        static HIGH: TranslatedBackend = TranslatedBackend::new(&High);
+        static LOW: TranslatedBackend = TranslatedBackend::new(&Low);
+        let layout = Layout::from_size_align(256, 64).unwrap();
+
+        // An IOMMU maps the memory below the mask.
+        let backend = fake_backend();
+        let mapped = DmaAllocator::with_backend(backend);
+        let buf = IOBuf::new_in(layout, mapped.with_mask(0x7fff_ffff)).unwrap();
+        assert!(buf.ioaddr().unwrap().as_u64() <= 0x7fff_ffff);
+        assert!(
+            IOBuf::new_in(layout, mapped)
+                .unwrap()
+                .ioaddr()
+                .unwrap()
+                .as_u64()
+                > 0x7fff_ffff
+        );
+        assert!(matches!(
+            IOBuf::new_in(layout, mapped.with_mask(0xfff)),
+            Err(IOMemError::BeyondDmaMask)
+        ));
+        assert_eq!(backend.mapper().mappings.lock().len(), 1);
+
+        let high = DmaAllocator::with_backend(&HIGH);
+        assert!(matches!(
+            IOBuf::new_in(layout, high.with_mask(0xffff_ffff)),
+            Err(IOMemError::BeyondDmaMask)
+        ));
+        let low = DmaAllocator::with_backend(&LOW).with_mask(0xffff_ffff);
+        assert!(
+            IOBuf::new_in(layout, low)
+                .unwrap()
+                .ioaddr()
+                .unwrap()
+                .as_u64()
+                <= 0xffff_ffff
+        );
+
+        let bounce = BounceBuffers::new(low);
+        let mut chain = IOBufChain::new(0, 2).unwrap();
+        for (allocator, data) in [(high, b"high"), (low, b"low!")] {
+            let mut buf = IOBuf::new_in(layout, allocator).unwrap();
+            buf.reserve(16).unwrap();
+            buf.copy_in(data).unwrap();
+            chain.append(buf);
+        }
+        let original = chain.segments[0].ioaddr().unwrap();
+
+        let mapped = bounce
+            .map_chain(&mut chain, DmaDirection::Bidirectional)
+            .unwrap();
+        assert!(mapped[0].is_bounced() && !mapped[1].is_bounced());
+        assert!(chain.segments[0].ioaddr().unwrap().as_u64() <= 0xffff_ffff);
+        assert_eq!(chain.segments[0].as_slice(), b"high");
+        assert_eq!(chain.segments[0].headroom(), 16);
+
+        // The device writes the (bounced) buffer
+        chain.copy_in_at(0, b"HIGHLOW").unwrap();
+        bounce.unmap_chain(&mut chain, mapped).unwrap();
+        assert_eq!(chain.segments[0].ioaddr().unwrap(), original);
+        assert_eq!(chain.linearize().unwrap().as_slice(), b"HIGHLOW!");
+        assert_eq!(
+            bounce.stats(),
+            BounceStats {
+                mapped: 2,
+                bounced: 1,
+                copied: 8
+            }
+        );
+
+        let mut buf = IOBuf::new_in(layout, high).unwrap();
+        let bounced = bounce.map(&mut buf, DmaDirection::FromDevice).unwrap();
+        assert!(buf.as_slice().iter().all(|b| *b == 0));
+        bounce.unmap(&mut buf, bounced).unwrap();
+        assert_eq!(bounce.stats().copied, 8 + 256);
+
+        // Buffers without an IO address can't be mapped and don't count.
+        static NOWHERE: OffsetTranslator = OffsetTranslator { offset: u64::MAX };
+        static UNTRANSLATED: TranslatedBackend = TranslatedBackend::new(&NOWHERE);
+        let mut buf = IOBuf::new_in(layout, DmaAllocator::with_backend(&UNTRANSLATED)).unwrap();
+        assert!(bounce.map(&mut buf, DmaDirection::ToDevice).is_err());
+        assert_eq!(bounce.stats().mapped, 3);
     }
 }
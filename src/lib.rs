@@ -17,9 +17,12 @@ extern crate libbarrelfish;
 
 pub mod devq;
 pub mod iomem;
+pub mod mmio;
 pub mod pci;
+pub mod register;
 #[cfg(unix)]
 pub mod timedops;
+pub mod trace;
 
 /// Definitions for network devices.
 pub mod net;
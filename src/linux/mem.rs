@@ -1,15 +1,26 @@
+use std::alloc::Layout;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io;
 use std::io::Seek;
+use std::ptr::NonNull;
+use std::sync::Mutex;
+use std::vec::Vec;
 
 use byteorder::{LittleEndian, ReadBytesExt};
 use libc;
 //use libc::{MAP_ANON, MAP_HUGETLB, MAP_POPULATE, MAP_SHARED};
 use memmap2;
 
+use crate::iomem::{AddressTranslator, DmaBackend, DmaMemory, IOMemError};
+use crate::{IOAddr, PAddr, VAddr};
+
 /// Represents a consecutive region of physical memory pinned in memory.
 pub struct DevMem {
     mapping: memmap2::MmapMut,
+    /// Start of the region in `mapping`.
+    offset: usize,
+    size: usize,
 }
 
 //const MAP_HUGE_SHIFT: usize = 26;
@@ -24,6 +35,12 @@ const PAGESIZE: u64 = FOUR_KIB as u64;
 /// Function to read the pagemap in Linux.
 /// See also https://www.kernel.org/doc/Documentation/vm/pagemap.txt.
 fn read_pagemap(virtual_page: u64) -> io::Result<u64> {
+    read_pagemap_range(virtual_page, 1).map(|frames| frames[0])
+}
+
+/// Reads the physical addresses of `count` consecutive virtual pages from
+/// the pagemap.
+fn read_pagemap_range(virtual_page: u64, count: usize) -> io::Result<Vec<u64>> {
     assert!(virtual_page % PAGESIZE == 0);
 
     let mut f = File::open("/proc/self/pagemap")?;
@@ -32,15 +49,34 @@ fn read_pagemap(virtual_page: u64) -> io::Result<u64> {
     const PAGEMAP_ENTRY_SIZE: u64 = 8;
     let start = (virtual_page / PAGESIZE) * PAGEMAP_ENTRY_SIZE;
     f.seek(io::SeekFrom::Start(start))?;
-    let value = f.read_u64::<LittleEndian>()?;
+    let mut f = io::BufReader::new(f);
+
+    (0..count)
+        .map(|_| {
+            let value = f.read_u64::<LittleEndian>()?;
+
+            // Sanity check that the page is not swapped:
+            let present_bit = 1 << 63;
+            if value & present_bit == 0 {
+                return Err(io::Error::other("page not present"));
+            }
 
-    // Sanity check that the page is not swapped:
-    let present_bit = 1 << 63;
-    assert!(value & present_bit > 0);
+            // Get the physical address by multiplying the PFN bits with the page size
+            let pfn_mask: u64 = (1 << 55) - 1;
+            Ok((value & pfn_mask) * PAGESIZE)
+        })
+        .collect()
+}
 
-    // Get the physical address by multiplying the PFN bits with the page size
-    let pfn_mask: u64 = (1 << 55) - 1;
-    Ok((value & pfn_mask) * PAGESIZE)
+/// The physical address of the first page if all `frames` follow each other
+/// (zero frames are hidden addresses, not frame 0).
+fn contiguous(frames: &[u64]) -> Option<u64> {
+    let first = *frames.first()?;
+    let consecutive = frames
+        .iter()
+        .zip((0..).map(|page| first + page * PAGESIZE))
+        .all(|(frame, expected)| *frame != 0 && *frame == expected);
+    consecutive.then_some(first)
 }
 
 #[derive(Debug)]
@@ -58,6 +94,9 @@ impl From<std::io::Error> for AllocError {
 impl DevMem {
     /// Allocates a chunk of consecutive physical, pinned memory.
     /// This should be usable by devices that do DMA.
+    ///
+    /// The region is aligned to its size (which the kernel needs to back it
+    /// with a huge page).
     pub fn alloc(size: usize) -> Result<DevMem, AllocError> {
         assert!(size == FOUR_KIB || size == TWO_MIB || size == ONE_GIB);
 
@@ -77,17 +116,24 @@ impl DevMem {
         //            mmap::MapOption::MapWritable,
         //        ];
 
-        let res = memmap2::MmapMut::map_anon(size)?;
+        // Map enough to contain an aligned region, the rest is never touched.
+        let res = memmap2::MmapMut::map_anon(2 * size - FOUR_KIB)?;
         res.advise(memmap2::Advice::HugePage)?;
+        let offset = (res.as_ptr() as usize).next_multiple_of(size) - res.as_ptr() as usize;
 
         // Make sure memory is not swapped:
-        let lock_ret = unsafe { libc::mlock(res.as_ptr() as *const libc::c_void, res.len()) };
+        let lock_ret =
+            unsafe { libc::mlock(res.as_ptr().add(offset) as *const libc::c_void, size) };
         if lock_ret == -1 {
             return Err(AllocError::Pin);
         }
         assert!(lock_ret == 0);
 
-        Ok(DevMem { mapping: res })
+        Ok(DevMem {
+            mapping: res,
+            offset,
+            size,
+        })
     }
 
     /// Returns the physical address of the memory region.
@@ -97,21 +143,21 @@ impl DevMem {
 
     /// Returns the virtual address of the memory region.
     pub fn virtual_address(&self) -> usize {
-        self.mapping.as_ptr() as usize
+        self.as_slice().as_ptr() as usize
     }
 
     /// Returns a pointer to the memory region.
     pub fn as_mut_ptr(&mut self) -> *mut u8 {
-        self.mapping.as_mut_ptr()
+        self.mapping[self.offset..self.offset + self.size].as_mut_ptr()
     }
 
     pub fn as_slice(&self) -> &[u8] {
-        &self.mapping
+        &self.mapping[self.offset..self.offset + self.size]
     }
 
     /// Returns the size of the memory region.
     pub fn len(&self) -> usize {
-        self.mapping.len()
+        self.size
     }
 
     pub fn is_empty(&self) -> bool {
@@ -119,6 +165,88 @@ impl DevMem {
     }
 }
 
+/// Translates virtual addresses of this process with `/proc/self/pagemap`.
+///
+/// Requires `CAP_SYS_ADMIN` (the kernel hides physical addresses otherwise)
+/// and the page must be present, translations of memory that isn't locked
+/// (see [`DevMem`]) can become stale at any time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PagemapTranslator;
+
+impl AddressTranslator for PagemapTranslator {
+    fn virt_to_phys(&self, vaddr: VAddr) -> Result<PAddr, IOMemError> {
+        let page = vaddr.as_u64() & !(PAGESIZE - 1);
+        match read_pagemap(page) {
+            Ok(frame) if frame != 0 => Ok(PAddr::from(frame + (vaddr.as_u64() - page))),
+            _ => Err(IOMemError::Untranslatable {
+                vaddr: vaddr.as_u64(),
+            }),
+        }
+    }
+}
+
+/// A [`DmaBackend`] that backs every allocation with its own [`DevMem`]
+/// region (of 4 KiB, 2 MiB or 1 GiB), devices access it by physical address.
+/// Regions are aligned to their size, so allocations can be aligned up to
+/// 1 GiB.
+///
+/// Reading physical addresses requires `CAP_SYS_ADMIN`, without it the
+/// memory is allocated (and pinned) but has no IO address. Neither has
+/// memory larger than a page unless the kernel happened to back it with
+/// physically contiguous pages (e.g., a huge page).
+///
+/// Every allocation costs an `mmap` and counts against `RLIMIT_MEMLOCK`, so
+/// this isn't the default backend, register it with
+/// [`crate::iomem::set_dma_backend`].
+#[derive(Default)]
+pub struct DevMemBackend {
+    /// Allocated regions by virtual address.
+    regions: Mutex<BTreeMap<usize, DevMem>>,
+}
+
+impl DevMemBackend {
+    pub const fn new() -> DevMemBackend {
+        DevMemBackend {
+            regions: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl DmaBackend for DevMemBackend {
+    fn allocate(&self, layout: Layout) -> Result<DmaMemory, IOMemError> {
+        if layout.align() > ONE_GIB {
+            return Err(IOMemError::InvalidLayout);
+        }
+        let size = [FOUR_KIB, TWO_MIB, ONE_GIB]
+            .iter()
+            .copied()
+            .find(|size| layout.size() <= *size && layout.align() <= *size)
+            .ok_or(IOMemError::OutOfMemory)?;
+        let mut region = DevMem::alloc(size).map_err(|_| IOMemError::OutOfMemory)?;
+
+        let pages = layout.size().div_ceil(FOUR_KIB);
+        let paddr = read_pagemap_range(region.virtual_address() as u64, pages)
+            .ok()
+            .and_then(|frames| contiguous(&frames));
+        let ptr = NonNull::new(region.as_mut_ptr()).ok_or(IOMemError::OutOfMemory)?;
+        self.regions
+            .lock()
+            .unwrap()
+            .insert(region.virtual_address(), region);
+
+        Ok(DmaMemory {
+            ptr,
+            ioaddr: paddr.map(IOAddr::from),
+            len: layout.size(),
+        })
+    }
+
+    unsafe fn deallocate(&self, memory: DmaMemory, _layout: Layout) {
+        let vaddr = memory.ptr.as_ptr() as usize;
+        self.regions.lock().unwrap().remove(&vaddr);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::mem::*;
@@ -145,6 +273,43 @@ mod tests {
         }
     }
 
+    #[test]
+    fn aligned_regions() {
+        use crate::iomem::{DmaAllocator, IOBufPool};
+
+        static BACKEND: DevMemBackend = DevMemBackend::new();
+        let pool = IOBufPool::slab(
+            2048,
+            64,
+            1024,
+            TWO_MIB,
+            DmaAllocator::with_backend(&BACKEND),
+        )
+        .unwrap();
+        let buf = pool.get().unwrap();
+        assert_eq!(buf.as_slice().as_ptr() as usize % TWO_MIB, 0);
+    }
+
+    #[test]
+    fn contiguous_frames() {
+        assert_eq!(contiguous(&[0x5000, 0x6000, 0x7000]), Some(0x5000));
+        assert_eq!(contiguous(&[0x5000, 0x9000]), None);
+        assert_eq!(contiguous(&[0, 0x1000]), None);
+        assert_eq!(contiguous(&[]), None);
+    }
+
+    #[test]
+    fn default_backend() {
+        use crate::iomem::IOBuf;
+        use std::alloc::Layout;
+
+        // Heap memory: works without privileges and without pinning a page
+        // per buffer.
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let bufs: Vec<IOBuf> = (0..1024).map(|_| IOBuf::new(layout).unwrap()).collect();
+        assert!(bufs.iter().all(|buf| buf.as_slice().len() == 64));
+    }
+
     #[test]
     #[ignore]
     fn alloc_1gib() {
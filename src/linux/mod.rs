@@ -12,6 +12,7 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use crate::MsrInterface;
 
 pub mod mem;
+pub mod sysfs;
 
 pub struct MsrWriter {
     cpu: usize,
@@ -0,0 +1,191 @@
+//! Access to PCI devices through Linux sysfs (`/sys/bus/pci/devices`).
+//!
+//! Reading the first 64 bytes of config space does not require any
+//! privileges, the rest of config space (and writes) are only accessible to
+//! root.
+
+use std::prelude::v1::*;
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::pci::{ConfigAccess, PCIAddress};
+
+/// Config space access through the `config` files in sysfs.
+#[derive(Debug, Clone)]
+pub struct SysfsAccess {
+    root: PathBuf,
+}
+
+/// A resource (BAR) of a device as reported by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SysfsResource {
+    pub start: u64,
+    pub end: u64,
+    pub flags: u64,
+}
+
+impl SysfsResource {
+    /// Size of the resource, zero if the resource is not in use.
+    pub fn size(&self) -> u64 {
+        if self.end > self.start {
+            self.end - self.start + 1
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for SysfsAccess {
+    fn default() -> Self {
+        SysfsAccess::new(SysfsAccess::DEFAULT_ROOT)
+    }
+}
+
+impl SysfsAccess {
+    pub const DEFAULT_ROOT: &'static str = "/sys/bus/pci/devices";
+
+    /// Creates a backend for the devices in `root`, usually
+    /// [`SysfsAccess::DEFAULT_ROOT`].
+    pub fn new<P: AsRef<Path>>(root: P) -> SysfsAccess {
+        SysfsAccess {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, addr: PCIAddress, file: &str) -> PathBuf {
+        self.root.join(format!("{}/{}", addr, file))
+    }
+
+    /// Addresses of all functions in ascending order.
+    pub fn addresses(&self) -> io::Result<Vec<PCIAddress>> {
+        let mut addresses = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let name = entry?.file_name();
+            if let Some(Ok(addr)) = name.to_str().map(str::parse) {
+                addresses.push(addr);
+            }
+        }
+        addresses.sort();
+        Ok(addresses)
+    }
+
+    /// Number of config space bytes the current user is allowed to read.
+    pub fn readable_len(&self, addr: PCIAddress) -> usize {
+        fs::read(self.path(addr, "config")).map_or(0, |data| data.len())
+    }
+
+    /// The resources (BARs, ROM etc.) of the function at `addr`.
+    ///
+    /// The index in the returned vector corresponds to the BAR index.
+    pub fn resources(&self, addr: PCIAddress) -> io::Result<Vec<SysfsResource>> {
+        let content = fs::read_to_string(self.path(addr, "resource"))?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed resource file");
+
+        let mut resources = Vec::new();
+        for line in content.lines() {
+            let mut fields = line
+                .split_whitespace()
+                .map(|f| u64::from_str_radix(f.trim_start_matches("0x"), 16));
+            let mut next = || fields.next().and_then(|f| f.ok()).ok_or_else(invalid);
+            resources.push(SysfsResource {
+                start: next()?,
+                end: next()?,
+                flags: next()?,
+            });
+        }
+        Ok(resources)
+    }
+
+    /// The expansion ROM of the function at `addr` (only readable by root).
+    ///
+    /// See [`crate::pci::rom::parse`] to parse it.
+    pub fn read_rom(&self, addr: PCIAddress) -> io::Result<Vec<u8>> {
+        // The kernel only enables decode of the ROM while the file is
+        // enabled:
+        let path = self.path(addr, "rom");
+        fs::write(&path, "1")?;
+        let rom = fs::read(&path);
+        fs::write(&path, "0")?;
+        rom
+    }
+
+    /// Reads `bytes.len()` bytes at `offset`, returns `false` if the function
+    /// does not exist. Bytes we are not allowed to read are left untouched.
+    fn read_bytes(&self, addr: PCIAddress, offset: u32, bytes: &mut [u8]) -> bool {
+        let file = match File::open(self.path(addr, "config")) {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+
+        let mut read = 0;
+        while read < bytes.len() {
+            match file.read_at(&mut bytes[read..], offset as u64 + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        true
+    }
+
+    fn write_bytes(&self, addr: PCIAddress, offset: u32, bytes: &[u8]) {
+        let res = OpenOptions::new()
+            .write(true)
+            .open(self.path(addr, "config"))
+            .and_then(|file| file.write_all_at(bytes, offset as u64));
+        if let Err(e) = res {
+            warn!("Can't write config space of {:?} at {:#x}: {}", addr, offset, e);
+        }
+    }
+}
+
+/// Functions that don't exist read as all ones, bytes we are not allowed to
+/// read as zero.
+///
+/// Sub-dword writes only touch the addressed bytes.
+impl ConfigAccess for SysfsAccess {
+    fn read(&self, addr: PCIAddress, offset: u32) -> u32 {
+        let mut bytes = [0u8; 4];
+        if self.read_bytes(addr, offset, &mut bytes) {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::MAX
+        }
+    }
+
+    fn write(&self, addr: PCIAddress, offset: u32, value: u32) {
+        self.write_bytes(addr, offset, &value.to_le_bytes())
+    }
+
+    fn read_u8(&self, addr: PCIAddress, offset: u32) -> u8 {
+        let mut bytes = [0u8; 1];
+        if self.read_bytes(addr, offset, &mut bytes) {
+            bytes[0]
+        } else {
+            u8::MAX
+        }
+    }
+
+    fn read_u16(&self, addr: PCIAddress, offset: u32) -> u16 {
+        let mut bytes = [0u8; 2];
+        if self.read_bytes(addr, offset, &mut bytes) {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::MAX
+        }
+    }
+
+    fn write_u8(&self, addr: PCIAddress, offset: u32, value: u8) {
+        self.write_bytes(addr, offset, &[value])
+    }
+
+    fn write_u16(&self, addr: PCIAddress, offset: u32, value: u16) {
+        self.write_bytes(addr, offset, &value.to_le_bytes())
+    }
+}
@@ -0,0 +1,396 @@
+//! Bounds-checked, volatile access to memory mapped device registers (e.g.,
+//! the memory BARs of a PCI device).
+
+use alloc::string::ToString;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::Deref;
+use core::ptr;
+
+use custom_error::custom_error;
+
+use crate::pci::{Bar, BarType};
+use crate::{PAddr, VAddr};
+
+custom_error! {pub MmioError
+    OutOfBounds{offset: usize, size: usize, len: usize} = "access of {size} bytes at offset {offset} is outside of the {len} byte region",
+    Misaligned{offset: usize, align: usize} = "offset {offset} is not aligned to {align} bytes",
+    NotMemory = "BAR is not a memory BAR",
+}
+
+/// A region of device memory.
+///
+/// All accesses are volatile and checked against the bounds of the region:
+/// the `try_*` functions return an error, the others panic.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MmioRegion {
+    base: VAddr,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// Creates a region of `len` bytes starting at `base`.
+    ///
+    /// # Safety
+    /// - `base..base + len` must be mapped (as device memory) for as long as
+    ///   the region and any sub-regions are in use.
+    pub unsafe fn new(base: VAddr, len: usize) -> MmioRegion {
+        MmioRegion { base, len }
+    }
+
+    pub fn base(&self) -> VAddr {
+        self.base
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Checks that `size` bytes at `offset` are within the region and that
+    /// `offset` is aligned to `align`.
+    fn check(&self, offset: usize, size: usize, align: usize) -> Result<usize, MmioError> {
+        match offset.checked_add(size) {
+            Some(end) if end <= self.len => {}
+            _ => {
+                return Err(MmioError::OutOfBounds {
+                    offset,
+                    size,
+                    len: self.len,
+                })
+            }
+        }
+        let addr = self.base.as_usize() + offset;
+        if addr % align != 0 {
+            return Err(MmioError::Misaligned { offset, align });
+        }
+        Ok(addr)
+    }
+
+    /// Reads a `T` at `offset`.
+    pub fn try_read<T: Copy>(&self, offset: usize) -> Result<T, MmioError> {
+        let addr = self.check(offset, mem::size_of::<T>(), mem::align_of::<T>())?;
+        // Safety: Within the region (see `MmioRegion::new`) and aligned.
+        Ok(unsafe { ptr::read_volatile(addr as *const T) })
+    }
+
+    /// Writes `value` at `offset`.
+    pub fn try_write<T: Copy>(&self, offset: usize, value: T) -> Result<(), MmioError> {
+        let addr = self.check(offset, mem::size_of::<T>(), mem::align_of::<T>())?;
+        // Safety: Within the region (see `MmioRegion::new`) and aligned.
+        unsafe { ptr::write_volatile(addr as *mut T, value) };
+        Ok(())
+    }
+
+    fn read<T: Copy>(&self, offset: usize) -> T {
+        self.try_read(offset).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn write<T: Copy>(&self, offset: usize, value: T) {
+        self.try_write(offset, value)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        self.read(offset)
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        self.read(offset)
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        self.read(offset)
+    }
+
+    /// Reads 64 bits with a single access (some devices require two 32-bit
+    /// accesses instead).
+    pub fn read64(&self, offset: usize) -> u64 {
+        self.read(offset)
+    }
+
+    pub fn write8(&self, offset: usize, value: u8) {
+        self.write(offset, value)
+    }
+
+    pub fn write16(&self, offset: usize, value: u16) {
+        self.write(offset, value)
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        self.write(offset, value)
+    }
+
+    /// Writes 64 bits with a single access (see [`MmioRegion::read64`]).
+    pub fn write64(&self, offset: usize, value: u64) {
+        self.write(offset, value)
+    }
+
+    /// The `len` bytes at `offset` as a region of their own.
+    pub fn subregion(&self, offset: usize, len: usize) -> Result<MmioRegion, MmioError> {
+        let addr = self.check(offset, len, 1)?;
+        Ok(MmioRegion {
+            base: VAddr::from(addr),
+            len,
+        })
+    }
+
+    /// A typed view of the `T` at `offset`.
+    pub fn typed<T: Copy>(&self, offset: usize) -> Result<Mmio<T>, MmioError> {
+        let addr = self.check(offset, mem::size_of::<T>(), mem::align_of::<T>())?;
+        Ok(Mmio {
+            addr,
+            _marker: PhantomData,
+        })
+    }
+
+    /// A typed view of `count` consecutive `T`s at `offset` (e.g., the MSI-X
+    /// table).
+    pub fn array<T: Copy>(&self, offset: usize, count: usize) -> Result<MmioArray<T>, MmioError> {
+        let size = mem::size_of::<T>()
+            .checked_mul(count)
+            .ok_or(MmioError::OutOfBounds {
+                offset,
+                size: usize::MAX,
+                len: self.len,
+            })?;
+        let addr = self.check(offset, size, mem::align_of::<T>())?;
+        Ok(MmioArray {
+            addr,
+            count,
+            _marker: PhantomData,
+        })
+    }
+}
+
+impl fmt::Debug for MmioRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MmioRegion({:#x}, {:#x})",
+            self.base.as_usize(),
+            self.len
+        )
+    }
+}
+
+/// A `T` in device memory, see [`MmioRegion::typed`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mmio<T> {
+    addr: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> Mmio<T> {
+    pub fn read(&self) -> T {
+        // Safety: Checked when created by `MmioRegion`.
+        unsafe { ptr::read_volatile(self.addr as *const T) }
+    }
+
+    pub fn write(&self, value: T) {
+        // Safety: Checked when created by `MmioRegion`.
+        unsafe { ptr::write_volatile(self.addr as *mut T, value) }
+    }
+
+    /// Reads the value, applies `f` and writes it back.
+    pub fn modify<F: FnOnce(T) -> T>(&self, f: F) {
+        self.write(f(self.read()))
+    }
+}
+
+/// An array of `T`s in device memory, see [`MmioRegion::array`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MmioArray<T> {
+    addr: usize,
+    count: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> MmioArray<T> {
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// The element at `index`.
+    pub fn get(&self, index: usize) -> Option<Mmio<T>> {
+        if index < self.count {
+            Some(Mmio {
+                addr: self.addr + index * mem::size_of::<T>(),
+                _marker: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Mmio<T>> + '_ {
+        (0..self.count).filter_map(move |i| self.get(i))
+    }
+}
+
+/// A memory BAR of a PCI device mapped into the address space.
+#[derive(Debug, Clone, Copy)]
+pub struct MappedBar {
+    bar: Bar,
+    region: MmioRegion,
+}
+
+impl MappedBar {
+    /// Maps `bar`, `map` is called with the physical address and size of
+    /// the BAR and returns the virtual address it is mapped at.
+    ///
+    /// # Safety
+    /// - `map` must map the whole BAR as device memory, and the mapping must
+    ///   stay valid for as long as the `MappedBar` (and regions derived from
+    ///   it) are in use.
+    pub unsafe fn new(
+        bar: Bar,
+        map: &dyn Fn(PAddr, usize) -> VAddr,
+    ) -> Result<MappedBar, MmioError> {
+        if bar.region_type != BarType::Mem {
+            return Err(MmioError::NotMemory);
+        }
+        let base = map(PAddr::from(bar.address), bar.size as usize);
+        Ok(MappedBar {
+            bar,
+            region: MmioRegion::new(base, bar.size as usize),
+        })
+    }
+
+    pub fn bar(&self) -> Bar {
+        self.bar
+    }
+
+    pub fn region(&self) -> MmioRegion {
+        self.region
+    }
+}
+
+impl Deref for MappedBar {
+    type Target = MmioRegion;
+
+    fn deref(&self) -> &MmioRegion {
+        &self.region
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn region_backed_by_memory() {
+        let mut mem = vec![0u64; 16];
+        let region = unsafe { MmioRegion::new(VAddr::from(mem.as_mut_ptr() as usize), 128) };
+
+        region.write32(0x0, 0xdead_beef);
+        region.write16(0x4, 0x1234);
+        region.write8(0x6, 0x56);
+        region.write64(0x8, 0x0123_4567_89ab_cdef);
+        assert_eq!(mem[0], 0x0056_1234_dead_beef);
+        assert_eq!(region.read64(0x8), 0x0123_4567_89ab_cdef);
+        assert_eq!(region.read8(0x3), 0xde);
+
+        assert!(matches!(
+            region.try_read::<u32>(126),
+            Err(MmioError::OutOfBounds {
+                offset: 126,
+                size: 4,
+                len: 128
+            })
+        ));
+        assert!(matches!(
+            region.try_write::<u32>(0x2, 0),
+            Err(MmioError::Misaligned {
+                offset: 2,
+                align: 4
+            })
+        ));
+        assert!(region.try_read::<u64>(usize::MAX - 2).is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_bounds_panics() {
+        let mut mem = vec![0u32; 4];
+        let region = unsafe { MmioRegion::new(VAddr::from(mem.as_mut_ptr() as usize), 16) };
+        region.read32(16);
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(C)]
+    struct Entry {
+        addr: u64,
+        data: u32,
+        control: u32,
+    }
+
+    #[test]
+    fn typed_subregions() {
+        let mut mem = vec![0u64; 64];
+        let region = unsafe { MmioRegion::new(VAddr::from(mem.as_mut_ptr() as usize), 512) };
+
+        let sub = region.subregion(0x100, 0x40).unwrap();
+        assert_eq!(sub.len(), 0x40);
+        sub.write32(0x3c, 7);
+        assert_eq!(region.read32(0x13c), 7);
+        assert!(sub.try_read::<u32>(0x40).is_err());
+        assert!(region.subregion(0x1f0, 0x20).is_err());
+
+        let table = sub.array::<Entry>(0, 4).unwrap();
+        assert_eq!(table.len(), 4);
+        let entry = table.get(1).unwrap();
+        entry.write(Entry {
+            addr: 0xfee0_0000,
+            data: 0x41,
+            control: 1,
+        });
+        entry.modify(|mut e| {
+            e.control = 0;
+            e
+        });
+        assert_eq!(region.read64(0x110), 0xfee0_0000);
+        assert_eq!(region.read32(0x118), 0x41);
+        assert_eq!(region.read32(0x11c), 0);
+        assert!(table.get(4).is_none());
+        assert!(sub.array::<Entry>(0, 5).is_err());
+
+        let reg = region.typed::<u32>(0x8).unwrap();
+        reg.write(3);
+        assert_eq!(mem[1], 3);
+    }
+
+    #[test]
+    fn mapped_bar() {
+        let mut mem = vec![0u32; 1024];
+        let vaddr = VAddr::from(mem.as_mut_ptr() as usize);
+        let bar = Bar {
+            region_type: BarType::Mem,
+            prefetchable: false,
+            address: 0xfebf_0000,
+            size: 4096,
+        };
+        let mapped = unsafe { MappedBar::new(bar, &|_, _| vaddr).unwrap() };
+        mapped.write32(0xffc, 1);
+        assert_eq!(mem[1023], 1);
+        assert!(mapped.try_read::<u32>(4096).is_err());
+
+        let io = Bar {
+            region_type: BarType::IO,
+            ..bar
+        };
+        assert!(matches!(
+            unsafe { MappedBar::new(io, &|_, _| vaddr) },
+            Err(MmioError::NotMemory)
+        ));
+    }
+}
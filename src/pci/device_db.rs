@@ -30,8 +30,62 @@ pub fn make_key(vendor: u16, device: u16) -> u32 {
 
 include!(concat!(env!("OUT_DIR"), "/pci_device_map.rs"));
 
+/// Returns a human readable name for a class code.
+///
+/// Falls back to the name of the base class if the sub-class is unknown.
+pub fn class_name(base_class: u8, sub_class: u8) -> &'static str {
+    match (base_class, sub_class) {
+        (0x00, 0x01) => "VGA compatible unclassified device",
+        (0x00, _) => "Unclassified device",
+        (0x01, 0x00) => "SCSI storage controller",
+        (0x01, 0x01) => "IDE interface",
+        (0x01, 0x04) => "RAID bus controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x07) => "Serial Attached SCSI controller",
+        (0x01, 0x08) => "Non-Volatile memory controller",
+        (0x01, _) => "Mass storage controller",
+        (0x02, 0x00) => "Ethernet controller",
+        (0x02, 0x07) => "Infiniband controller",
+        (0x02, _) => "Network controller",
+        (0x03, 0x00) => "VGA compatible controller",
+        (0x03, 0x02) => "3D controller",
+        (0x03, _) => "Display controller",
+        (0x04, 0x01) => "Multimedia audio controller",
+        (0x04, 0x03) => "Audio device",
+        (0x04, _) => "Multimedia controller",
+        (0x05, 0x00) => "RAM memory",
+        (0x05, _) => "Memory controller",
+        (0x06, 0x00) => "Host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "Bridge",
+        (0x07, 0x00) => "Serial controller",
+        (0x07, _) => "Communication controller",
+        (0x08, 0x00) => "PIC",
+        (0x08, 0x05) => "SD Host controller",
+        (0x08, 0x06) => "IOMMU",
+        (0x08, _) => "System peripheral",
+        (0x09, _) => "Input device controller",
+        (0x0a, _) => "Docking station",
+        (0x0b, _) => "Processor",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus",
+        (0x0c, _) => "Serial bus controller",
+        (0x0d, _) => "Wireless controller",
+        (0x0e, _) => "Intelligent controller",
+        (0x0f, _) => "Satellite communications controller",
+        (0x10, _) => "Encryption controller",
+        (0x11, _) => "Signal processing controller",
+        (0x12, _) => "Processing accelerators",
+        (0x13, _) => "Non-Essential Instrumentation",
+        (0x40, _) => "Coprocessor",
+        _ => "Unassigned class",
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn test_device_map() {
@@ -42,4 +96,11 @@ mod tests {
         assert_eq!(dev.vendor_id, 0xfffe);
         assert_eq!(dev.device_id, 0x0710);
     }
+
+    #[test]
+    fn test_class_name() {
+        assert_eq!(class_name(0x02, 0x00), "Ethernet controller");
+        assert_eq!(class_name(0x02, 0x80), "Network controller");
+        assert_eq!(class_name(0xfe, 0x00), "Unassigned class");
+    }
 }
@@ -0,0 +1,216 @@
+//! Config space served from a dump instead of real hardware.
+//!
+//! The dump format is the one produced by `lspci -x` (or `-xxx`, `-xxxx`): a
+//! line starting with the address of a function, followed by lines with an
+//! offset and up to 16 hex bytes:
+//!
+//! ```text
+//! 00:1f.3 Audio device: Intel Corporation Device a348 (rev 10)
+//! 00: 86 80 48 a3 06 04 10 00 10 00 03 04 10 00 00 00
+//! 10: 04 00 12 a1 00 00 00 00 00 00 00 00 00 00 00 00
+//! ```
+//!
+//! Writes update the dump, so a `ConfigDump` can also stand in for a device in
+//! tests.
+
+use alloc::collections::BTreeMap;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use custom_error::custom_error;
+
+use super::{ConfigAccess, PCIAddress};
+
+custom_error! {pub ConfigDumpError
+    InvalidAddress{line: usize} = "line {line}: invalid PCI address",
+    InvalidData{line: usize} = "line {line}: invalid config space data",
+    NoFunction{line: usize} = "line {line}: data without preceding PCI address",
+}
+
+/// The largest (extended) config space of a function.
+const CONFIG_SPACE_SIZE: usize = 4096;
+
+/// Config space of a set of PCI functions.
+#[derive(Debug, Default)]
+pub struct ConfigDump {
+    /// Captured functions and their config space (in dwords).
+    functions: BTreeMap<PCIAddress, Vec<AtomicU32>>,
+}
+
+impl ConfigDump {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Parses the output of `lspci -x`.
+    pub fn parse(text: &str) -> Result<ConfigDump, ConfigDumpError> {
+        let mut dump = ConfigDump::new();
+        let mut current: Option<(PCIAddress, Vec<u8>)> = None;
+
+        for (idx, line) in text.lines().enumerate() {
+            let line_nr = idx + 1;
+            // Indented lines are details printed by `lspci -v`.
+            if line.starts_with('\t') {
+                continue;
+            }
+            let line = line.trim();
+            let first = match line.split_whitespace().next() {
+                Some(token) => token,
+                None => continue,
+            };
+
+            if let Some(offset) = first.strip_suffix(':') {
+                let (_addr, data) = current
+                    .as_mut()
+                    .ok_or(ConfigDumpError::NoFunction { line: line_nr })?;
+                let invalid = ConfigDumpError::InvalidData { line: line_nr };
+                let offset = usize::from_str_radix(offset, 16).map_err(|_| invalid)?;
+
+                for (i, byte) in line.split_whitespace().skip(1).enumerate() {
+                    let invalid = ConfigDumpError::InvalidData { line: line_nr };
+                    let byte = u8::from_str_radix(byte, 16).map_err(|_| invalid)?;
+                    let pos = offset + i;
+                    if pos >= CONFIG_SPACE_SIZE {
+                        return Err(ConfigDumpError::InvalidData { line: line_nr });
+                    }
+                    if data.len() <= pos {
+                        data.resize(pos + 1, 0);
+                    }
+                    data[pos] = byte;
+                }
+            } else {
+                let addr: PCIAddress = first
+                    .parse()
+                    .map_err(|_| ConfigDumpError::InvalidAddress { line: line_nr })?;
+                if let Some((addr, data)) = current.replace((addr, Vec::new())) {
+                    dump.insert(addr, &data);
+                }
+            }
+        }
+
+        if let Some((addr, data)) = current {
+            dump.insert(addr, &data);
+        }
+        Ok(dump)
+    }
+
+    /// Adds (or replaces) the config space of the function at `addr`.
+    ///
+    /// `data` is padded with zeroes to a multiple of a dword.
+    pub fn insert(&mut self, addr: PCIAddress, data: &[u8]) {
+        let dwords = data
+            .chunks(4)
+            .map(|chunk| {
+                let mut bytes = [0u8; 4];
+                bytes[..chunk.len()].copy_from_slice(chunk);
+                AtomicU32::new(u32::from_le_bytes(bytes))
+            })
+            .collect();
+
+        self.functions.insert(addr, dwords);
+    }
+
+    /// Addresses of all functions in the dump (in ascending order).
+    pub fn addresses(&self) -> impl Iterator<Item = PCIAddress> + '_ {
+        self.functions.keys().copied()
+    }
+
+    /// Number of config space bytes captured for the function at `addr`.
+    pub fn captured_len(&self, addr: PCIAddress) -> Option<usize> {
+        self.function(addr).map(|dwords| dwords.len() * 4)
+    }
+
+    fn function(&self, addr: PCIAddress) -> Option<&[AtomicU32]> {
+        self.functions.get(&addr).map(|dwords| dwords.as_slice())
+    }
+}
+
+impl ConfigAccess for ConfigDump {
+    /// Reads of functions not in the dump return all ones (like an aborted
+    /// config cycle), reads outside of the captured range return zero.
+    fn read(&self, addr: PCIAddress, offset: u32) -> u32 {
+        match self.function(addr) {
+            Some(dwords) => dwords
+                .get(offset as usize / 4)
+                .map_or(0, |dword| dword.load(Ordering::Relaxed)),
+            None => u32::MAX,
+        }
+    }
+
+    fn write(&self, addr: PCIAddress, offset: u32, value: u32) {
+        if let Some(dword) = self
+            .function(addr)
+            .and_then(|dwords| dwords.get(offset as usize / 4))
+        {
+            dword.store(value, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DUMP: &str = "\
+00:00.0 Host bridge: Intel Corporation 440FX - 82441FX PMC [Natoma] (rev 02)
+00: 86 80 37 12 00 00 00 00 02 00 00 06 00 00 00 00
+\tSubsystem: Red Hat, Inc. Qemu virtual machine
+10: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+
+0000:00:03.0 Ethernet controller: Intel Corporation 82540EM Gigabit Ethernet Controller (rev 03)
+00: 86 80 0e 10 07 01 10 00 03 00 00 02 00 00 00 00
+
+0001:00:00.0 Non-Volatile memory controller: Samsung Electronics Co Ltd NVMe SSD Controller SM981/PM981/PM983
+00: 4d 14 08 a8 06 04 10 00 00 02 08 01 00 00 00 00
+";
+
+    #[test]
+    fn parse_lspci_dump() {
+        let dump = ConfigDump::parse(DUMP).unwrap();
+        let host = PCIAddress::new(0, 0, 0).unwrap();
+        let nic = PCIAddress::new(0, 3, 0).unwrap();
+
+        assert_eq!(dump.addresses().count(), 3);
+        assert_eq!(dump.captured_len(host), Some(32));
+        assert_eq!(dump.read(host, 0x0), 0x1237_8086);
+        assert_eq!(dump.read(nic, 0x8), 0x0200_0003);
+        assert_eq!(dump.read(PCIAddress::with_segment(1, 0, 0, 0).unwrap(), 0x0), 0xa808_144d);
+        // Outside of the captured range:
+        assert_eq!(dump.read(nic, 0x40), 0);
+        // Function not in the dump:
+        assert_eq!(dump.read(PCIAddress::new(0, 1, 0).unwrap(), 0x0), u32::MAX);
+
+        dump.write(nic, 0x4, 0x0010_0006);
+        assert_eq!(dump.read(nic, 0x4), 0x0010_0006);
+    }
+
+    #[test]
+    fn sub_dword_access() {
+        let dump = ConfigDump::parse(DUMP).unwrap();
+        let nic = PCIAddress::new(0, 3, 0).unwrap();
+
+        assert_eq!(dump.read_u16(nic, 0x2), 0x100e);
+        assert_eq!(dump.read_u8(nic, 0xb), 0x02);
+
+        dump.write_u8(nic, 0x5, 0x05);
+        dump.write_u16(nic, 0x6, 0x0290);
+        assert_eq!(dump.read(nic, 0x4), 0x0290_0507);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(
+            ConfigDump::parse("00: 86 80"),
+            Err(ConfigDumpError::NoFunction { line: 1 })
+        ));
+        assert!(matches!(
+            ConfigDump::parse("00:00.0 Host\n00: 86 zz"),
+            Err(ConfigDumpError::InvalidData { line: 2 })
+        ));
+        assert!(matches!(
+            ConfigDump::parse("00:20.0 Host"),
+            Err(ConfigDumpError::InvalidAddress { line: 1 })
+        ));
+    }
+}
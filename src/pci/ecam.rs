@@ -0,0 +1,350 @@
+//! Config space access through the PCI Express Enhanced Configuration Access
+//! Mechanism (ECAM).
+//!
+//! With ECAM the (4 KiB) config space of every function is memory mapped at
+//! `base + (bus << 20 | device << 15 | function << 12)`.
+
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use crate::arch::VAddr;
+
+use super::{ConfigAccess, PCIAddress, PciError};
+
+/// Size of the config space of a function.
+pub const FUNCTION_CONFIG_SIZE: usize = 4096;
+
+/// Size of the ECAM region covering one bus.
+pub const BUS_CONFIG_SIZE: usize = 1 << 20;
+
+/// Offset of `offset` in the config space of `addr` from the start of an ECAM
+/// window that begins with bus `start_bus`.
+///
+/// Returns `None` if the bus is below `start_bus` or `offset` is outside of
+/// config space.
+pub fn window_offset(start_bus: u8, addr: PCIAddress, offset: u32) -> Option<usize> {
+    if addr.bus < start_bus || offset as usize >= FUNCTION_CONFIG_SIZE {
+        return None;
+    }
+
+    Some(
+        ((addr.bus - start_bus) as usize) << 20
+            | (addr.dev as usize) << 15
+            | (addr.fun as usize) << 12
+            | offset as usize,
+    )
+}
+
+/// Splits a legacy configuration address (the value written to port 0xcf8,
+/// see [`PCIAddress::addr`]) into the function and register offset.
+///
+/// The offset keeps the low two bits (the byte within the dword) for
+/// sub-dword accesses, dword accesses have to clear them.
+pub fn decode_conf_address(addr: u32) -> (PCIAddress, u32) {
+    let function = PCIAddress {
+        segment: 0,
+        bus: (addr >> 16) as u8,
+        dev: ((addr >> 11) & 0x1f) as u8,
+        fun: ((addr >> 8) & 0x7) as u8,
+    };
+    (function, addr & 0xff)
+}
+
+/// Fails if the bus range `start_bus..=end_bus` is empty.
+pub(crate) fn check_bus_range(start_bus: u8, end_bus: u8) -> Result<(), PciError> {
+    if start_bus > end_bus {
+        return Err(PciError::InvalidBusRange { start_bus, end_bus });
+    }
+    Ok(())
+}
+
+/// A mapped ECAM window covering a range of buses in one segment.
+#[derive(Debug, Clone, Copy)]
+pub struct EcamWindow {
+    /// Virtual address of the config space of `start_bus`.
+    base: VAddr,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+impl EcamWindow {
+    /// Creates a window for buses `start_bus..=end_bus` of `segment`.
+    ///
+    /// Fails if `end_bus` is below `start_bus` (e.g., from broken firmware
+    /// tables).
+    ///
+    /// # Safety
+    /// - `base` must be the virtual address of the config space of `start_bus`
+    ///   and map the whole window (see [`EcamWindow::size`]) as device memory
+    ///   for as long as the window is used.
+    pub unsafe fn new(
+        base: VAddr,
+        segment: u16,
+        start_bus: u8,
+        end_bus: u8,
+    ) -> Result<EcamWindow, PciError> {
+        check_bus_range(start_bus, end_bus)?;
+        Ok(EcamWindow {
+            base,
+            segment,
+            start_bus,
+            end_bus,
+        })
+    }
+
+    pub fn segment(&self) -> u16 {
+        self.segment
+    }
+
+    pub fn start_bus(&self) -> u8 {
+        self.start_bus
+    }
+
+    pub fn end_bus(&self) -> u8 {
+        self.end_bus
+    }
+
+    /// Size of the window in bytes.
+    pub fn size(&self) -> usize {
+        (self.end_bus as usize - self.start_bus as usize + 1) * BUS_CONFIG_SIZE
+    }
+
+    /// Does the window cover the function at `addr`?
+    pub fn contains(&self, addr: PCIAddress) -> bool {
+        addr.segment == self.segment && addr.bus >= self.start_bus && addr.bus <= self.end_bus
+    }
+
+    /// The pointer to `offset` in the config space of `addr`, `None` if it is
+    /// not covered by the window or not aligned for a `T`.
+    fn ptr<T>(&self, addr: PCIAddress, offset: u32) -> Option<*mut T> {
+        if !self.contains(addr) || offset as usize % core::mem::size_of::<T>() != 0 {
+            return None;
+        }
+        window_offset(self.start_bus, addr, offset)
+            .map(|off| VAddr::from(self.base.as_usize() + off).as_mut_ptr::<T>())
+    }
+
+    fn read_value<T: Copy>(&self, addr: PCIAddress, offset: u32, absent: T) -> T {
+        match self.ptr::<T>(addr, offset) {
+            // Safety: Within the window (see `EcamWindow::new`) and aligned.
+            Some(ptr) => unsafe { ptr::read_volatile(ptr) },
+            None => absent,
+        }
+    }
+
+    fn write_value<T: Copy>(&self, addr: PCIAddress, offset: u32, value: T) {
+        if let Some(ptr) = self.ptr::<T>(addr, offset) {
+            // Safety: Within the window (see `EcamWindow::new`) and aligned.
+            unsafe { ptr::write_volatile(ptr, value) }
+        }
+    }
+}
+
+/// Reads outside of the window (or unaligned reads) return all ones, such
+/// writes are dropped.
+impl ConfigAccess for EcamWindow {
+    fn read(&self, addr: PCIAddress, offset: u32) -> u32 {
+        self.read_value(addr, offset, u32::MAX)
+    }
+
+    fn write(&self, addr: PCIAddress, offset: u32, value: u32) {
+        self.write_value(addr, offset, value)
+    }
+
+    fn read_u8(&self, addr: PCIAddress, offset: u32) -> u8 {
+        self.read_value(addr, offset, u8::MAX)
+    }
+
+    fn read_u16(&self, addr: PCIAddress, offset: u32) -> u16 {
+        self.read_value(addr, offset, u16::MAX)
+    }
+
+    fn write_u8(&self, addr: PCIAddress, offset: u32, value: u8) {
+        self.write_value(addr, offset, value)
+    }
+
+    fn write_u16(&self, addr: PCIAddress, offset: u32, value: u16) {
+        self.write_value(addr, offset, value)
+    }
+}
+
+/// An ECAM window that is configured once at runtime (e.g., after parsing the
+/// MCFG table or device tree) and then used by the platform's config access
+/// functions.
+#[derive(Debug, Default)]
+pub struct SharedEcamWindow {
+    /// Virtual address of the window, zero if not configured.
+    base: AtomicUsize,
+    /// Segment, start bus and end bus of the window.
+    range: AtomicU32,
+}
+
+impl SharedEcamWindow {
+    pub const fn new() -> SharedEcamWindow {
+        SharedEcamWindow {
+            base: AtomicUsize::new(0),
+            range: AtomicU32::new(0),
+        }
+    }
+
+    /// Configures `window` to be used for all config space accesses.
+    ///
+    /// # Safety
+    /// - Must not race with another call to `set`.
+    pub unsafe fn set(&self, window: EcamWindow) {
+        let range =
+            (window.segment as u32) << 16 | (window.start_bus as u32) << 8 | window.end_bus as u32;
+        self.base.store(0, Ordering::Release);
+        self.range.store(range, Ordering::Relaxed);
+        self.base.store(window.base.as_usize(), Ordering::Release);
+    }
+
+    /// The configured window (if any).
+    pub fn get(&self) -> Option<EcamWindow> {
+        let base = self.base.load(Ordering::Acquire);
+        if base == 0 {
+            return None;
+        }
+        let range = self.range.load(Ordering::Relaxed);
+        Some(EcamWindow {
+            base: VAddr::from(base),
+            segment: (range >> 16) as u16,
+            start_bus: (range >> 8) as u8,
+            end_bus: range as u8,
+        })
+    }
+}
+
+/// Config space access through a set of ECAM windows (e.g., one per segment).
+#[derive(Debug, Clone, Default)]
+pub struct EcamAccess {
+    windows: Vec<EcamWindow>,
+}
+
+impl EcamAccess {
+    pub fn new(windows: Vec<EcamWindow>) -> EcamAccess {
+        EcamAccess { windows }
+    }
+
+    pub fn windows(&self) -> &[EcamWindow] {
+        &self.windows
+    }
+
+    /// The window covering `addr`.
+    pub fn window(&self, addr: PCIAddress) -> Option<&EcamWindow> {
+        self.windows.iter().find(|w| w.contains(addr))
+    }
+
+    /// The segments covered by the windows (in ascending order).
+    pub fn segments(&self) -> Vec<u16> {
+        let mut segments: Vec<u16> = self.windows.iter().map(|w| w.segment).collect();
+        segments.sort_unstable();
+        segments.dedup();
+        segments
+    }
+}
+
+impl ConfigAccess for EcamAccess {
+    fn read(&self, addr: PCIAddress, offset: u32) -> u32 {
+        self.window(addr).map_or(u32::MAX, |w| w.read(addr, offset))
+    }
+
+    fn write(&self, addr: PCIAddress, offset: u32, value: u32) {
+        if let Some(w) = self.window(addr) {
+            w.write(addr, offset, value)
+        }
+    }
+
+    fn read_u8(&self, addr: PCIAddress, offset: u32) -> u8 {
+        self.window(addr).map_or(u8::MAX, |w| w.read_u8(addr, offset))
+    }
+
+    fn read_u16(&self, addr: PCIAddress, offset: u32) -> u16 {
+        self.window(addr).map_or(u16::MAX, |w| w.read_u16(addr, offset))
+    }
+
+    fn write_u8(&self, addr: PCIAddress, offset: u32, value: u8) {
+        if let Some(w) = self.window(addr) {
+            w.write_u8(addr, offset, value)
+        }
+    }
+
+    fn write_u16(&self, addr: PCIAddress, offset: u32, value: u16) {
+        if let Some(w) = self.window(addr) {
+            w.write_u16(addr, offset, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn offsets() {
+        let addr = PCIAddress::new(3, 0x1f, 7).unwrap();
+        assert_eq!(window_offset(0, addr, 0x10), Some(0x3f_f010));
+        assert_eq!(window_offset(2, addr, 0xffc), Some(0x1f_fffc));
+        assert_eq!(window_offset(4, addr, 0), None);
+        assert_eq!(window_offset(0, addr, 0x1000), None);
+    }
+
+    #[test]
+    fn conf_address() {
+        let addr = PCIAddress::new(0x12, 0x1f, 5).unwrap();
+        assert_eq!(decode_conf_address(addr.addr() | 0x3c), (addr, 0x3c));
+        assert_eq!(decode_conf_address(addr.addr() | 0x3e), (addr, 0x3e));
+    }
+
+    #[test]
+    fn shared_window() {
+        let mut mem = vec![0u32; BUS_CONFIG_SIZE / 4];
+        let shared = SharedEcamWindow::new();
+        assert!(shared.get().is_none());
+
+        let base = VAddr::from(mem.as_mut_ptr() as usize);
+        unsafe { shared.set(EcamWindow::new(base, 0, 4, 4).unwrap()) };
+        let window = shared.get().unwrap();
+        assert_eq!((window.segment(), window.start_bus(), window.end_bus()), (0, 4, 4));
+
+        let dev = PCIAddress::new(4, 1, 0).unwrap();
+        window.write(dev, 0x10, 0xfebf_0000);
+        assert_eq!(mem[window_offset(4, dev, 0x10).unwrap() / 4], 0xfebf_0000);
+        assert_eq!(window.read(PCIAddress::new(5, 1, 0).unwrap(), 0x10), u32::MAX);
+    }
+
+    #[test]
+    fn window_backed_by_memory() {
+        // Two buses (1 and 2) of segment 1:
+        let mut mem = vec![u32::MAX; 2 * BUS_CONFIG_SIZE / 4];
+        let base = VAddr::from(mem.as_mut_ptr() as usize);
+        let window = unsafe { EcamWindow::new(base, 1, 1, 2).unwrap() };
+        assert_eq!(
+            unsafe { EcamWindow::new(base, 1, 2, 1) }.unwrap_err(),
+            PciError::InvalidBusRange {
+                start_bus: 2,
+                end_bus: 1
+            }
+        );
+        let ecam = EcamAccess::new(vec![window]);
+
+        let dev = PCIAddress::with_segment(1, 2, 3, 1).unwrap();
+        ecam.write(dev, 0x0, 0x100e_8086);
+        ecam.write_u8(dev, 0x3c, 0x0b);
+        ecam.write_u16(dev, 0x4, 0x0006);
+
+        assert_eq!(mem[window_offset(1, dev, 0).unwrap() / 4], 0x100e_8086);
+        assert_eq!(ecam.read(dev, 0x0), 0x100e_8086);
+        assert_eq!(ecam.read_u16(dev, 0x2), 0x100e);
+        assert_eq!(ecam.read_u8(dev, 0x3c), 0x0b);
+        assert_eq!(ecam.read(dev, 0x4) & 0xffff, 0x0006);
+
+        // Not covered by any window:
+        assert_eq!(ecam.read(PCIAddress::new(2, 3, 1).unwrap(), 0x0), u32::MAX);
+        assert_eq!(ecam.read(PCIAddress::with_segment(1, 3, 0, 0).unwrap(), 0x0), u32::MAX);
+        assert_eq!(ecam.segments(), vec![1]);
+    }
+}
@@ -0,0 +1,194 @@
+//! Decoded snapshot of the Type 0 (endpoint) configuration header.
+//!
+//! Unlike the `PciDevice` accessors, which read config space on every call,
+//! [`PciHeader`] decodes all registers of the header from one pass over its
+//! 64 bytes (see `PciDevice::read_header`).
+
+use bit_field::BitField;
+
+use super::{
+    BaseClass, ClassCode, DeviceId, DeviceRevision, HeaderType, Interface, PciError, SubClass,
+    VendorId,
+};
+
+/// Result of the built-in self test (see `PciDevice::start_bist`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BistStatus {
+    /// The test is still running (it must complete within 2 seconds).
+    Running,
+    Passed,
+    /// The test failed with the given device specific completion code.
+    Failed(u8),
+}
+
+impl BistStatus {
+    /// BIST Capable bit of the BIST register.
+    pub(crate) const CAPABLE: u8 = 1 << 7;
+    /// Start BIST bit of the BIST register, cleared by the device when done.
+    pub(crate) const START: u8 = 1 << 6;
+
+    /// Decodes the BIST register, `None` if the device doesn't support BIST.
+    pub fn from_register(bist: u8) -> Option<BistStatus> {
+        if bist & BistStatus::CAPABLE == 0 {
+            None
+        } else if bist & BistStatus::START != 0 {
+            Some(BistStatus::Running)
+        } else {
+            match bist.get_bits(0..4) {
+                0 => Some(BistStatus::Passed),
+                code => Some(BistStatus::Failed(code)),
+            }
+        }
+    }
+}
+
+/// The registers of a Type 0 configuration header.
+///
+/// BARs and the expansion ROM register are the raw register values, use
+/// `PciDevice::bar` and `PciDevice::expansion_rom` to decode and size them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciHeader {
+    pub vendor_id: VendorId,
+    pub device_id: DeviceId,
+    pub command: u16,
+    pub status: u16,
+    pub revision: DeviceRevision,
+    pub interface: Interface,
+    pub sub_class: SubClass,
+    pub base_class: BaseClass,
+    /// In units of dwords.
+    pub cache_line_size: u8,
+    pub latency_timer: u8,
+    /// Layout of the header in bits 0..7, bit 7 is set for multi-function
+    /// devices.
+    pub header_type: HeaderType,
+    pub bist: u8,
+    pub bars: [u32; 6],
+    pub cardbus_cis: u32,
+    pub subsystem_vendor_id: VendorId,
+    pub subsystem_id: DeviceId,
+    pub expansion_rom: u32,
+    pub capabilities_pointer: u8,
+    pub interrupt_line: u8,
+    /// 0 if the function uses no legacy interrupt, 1 to 4 for INTA# to INTD#.
+    pub interrupt_pin: u8,
+    /// In units of 250 ns.
+    pub min_grant: u8,
+    /// In units of 250 ns.
+    pub max_latency: u8,
+}
+
+impl PciHeader {
+    /// Size of the header in bytes.
+    pub const SIZE: usize = 64;
+
+    /// Decodes the header from its 16 dwords.
+    ///
+    /// Fails if the header is not a Type 0 header.
+    pub fn decode(regs: &[u32; 16]) -> Result<PciHeader, PciError> {
+        let header_type = regs[3].get_bits(16..24) as HeaderType;
+        if header_type & 0x7f != 0x00 {
+            return Err(PciError::UnknownHeaderType {
+                header_type: header_type & 0x7f,
+            });
+        }
+
+        let mut bars = [0; 6];
+        bars.copy_from_slice(&regs[4..10]);
+
+        Ok(PciHeader {
+            vendor_id: regs[0].get_bits(0..16) as VendorId,
+            device_id: regs[0].get_bits(16..32) as DeviceId,
+            command: regs[1].get_bits(0..16) as u16,
+            status: regs[1].get_bits(16..32) as u16,
+            revision: regs[2].get_bits(0..8) as DeviceRevision,
+            interface: regs[2].get_bits(8..16) as Interface,
+            sub_class: regs[2].get_bits(16..24) as SubClass,
+            base_class: regs[2].get_bits(24..32) as BaseClass,
+            cache_line_size: regs[3].get_bits(0..8) as u8,
+            latency_timer: regs[3].get_bits(8..16) as u8,
+            header_type,
+            bist: regs[3].get_bits(24..32) as u8,
+            bars,
+            cardbus_cis: regs[10],
+            subsystem_vendor_id: regs[11].get_bits(0..16) as VendorId,
+            subsystem_id: regs[11].get_bits(16..32) as DeviceId,
+            expansion_rom: regs[12],
+            // The bottom two bits of the pointer are reserved.
+            capabilities_pointer: regs[13].get_bits(0..8) as u8 & !0b11,
+            interrupt_line: regs[15].get_bits(0..8) as u8,
+            interrupt_pin: regs[15].get_bits(8..16) as u8,
+            min_grant: regs[15].get_bits(16..24) as u8,
+            max_latency: regs[15].get_bits(24..32) as u8,
+        })
+    }
+
+    pub fn is_multifunction(&self) -> bool {
+        self.header_type.get_bit(7)
+    }
+
+    /// Does the function have a capability list?
+    pub fn has_capabilities(&self) -> bool {
+        self.status.get_bit(4) && self.capabilities_pointer != 0
+    }
+
+    pub fn class_code(&self) -> ClassCode {
+        ((self.base_class as u16) << 8 | self.sub_class as u16).into()
+    }
+
+    /// State of the built-in self test, `None` if the device doesn't
+    /// support it.
+    pub fn bist_status(&self) -> Option<BistStatus> {
+        BistStatus::from_register(self.bist)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_header() {
+        #[rustfmt::skip]
+        let regs = [
+            0x100e_8086, 0x0290_0107, 0x0200_0003, 0x0080_4010,
+            0xfebc_0000, 0x0000_0000, 0x0000_c001, 0x0000_0000,
+            0x0000_0000, 0x0000_0000, 0x0000_0000, 0x0001_1af4,
+            0xfeb8_0000, 0x0000_00dc, 0x0000_0000, 0x0408_010b,
+        ];
+        let header = PciHeader::decode(&regs).unwrap();
+
+        assert_eq!((header.vendor_id, header.device_id), (0x8086, 0x100e));
+        assert_eq!((header.command, header.status), (0x0107, 0x0290));
+        assert_eq!(header.revision, 0x03);
+        assert!(matches!(header.class_code(), ClassCode::EthernetController));
+        assert_eq!((header.cache_line_size, header.latency_timer), (0x10, 0x40));
+        assert!(header.is_multifunction());
+        assert_eq!(header.bist_status(), None);
+        assert_eq!(header.bars[2], 0xc001);
+        assert_eq!(
+            (header.subsystem_vendor_id, header.subsystem_id),
+            (0x1af4, 0x0001)
+        );
+        assert_eq!(header.expansion_rom, 0xfeb8_0000);
+        assert_eq!(header.capabilities_pointer, 0xdc);
+        assert!(header.has_capabilities());
+        assert_eq!((header.interrupt_line, header.interrupt_pin), (0x0b, 1));
+        assert_eq!((header.min_grant, header.max_latency), (0x08, 0x04));
+
+        let mut bridge = regs;
+        bridge[3] = 0x0001_0000;
+        assert!(matches!(
+            PciHeader::decode(&bridge),
+            Err(PciError::UnknownHeaderType { header_type: 1 })
+        ));
+    }
+
+    #[test]
+    fn bist_register() {
+        assert_eq!(BistStatus::from_register(0x0f), None);
+        assert_eq!(BistStatus::from_register(0xc0), Some(BistStatus::Running));
+        assert_eq!(BistStatus::from_register(0x80), Some(BistStatus::Passed));
+        assert_eq!(BistStatus::from_register(0x83), Some(BistStatus::Failed(3)));
+    }
+}
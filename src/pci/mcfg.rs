@@ -0,0 +1,250 @@
+//! Parser for the ACPI MCFG table which describes the ECAM regions of a
+//! system.
+//!
+//! # See also
+//! - PCI Firmware Specification, Revision 3.2, Section 4.1.2
+//! - <https://wiki.osdev.org/PCI_Express>
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use custom_error::custom_error;
+
+use crate::arch::{PAddr, VAddr};
+
+use super::ecam::{check_bus_range, EcamAccess, EcamWindow, BUS_CONFIG_SIZE};
+use super::PciError;
+
+custom_error! {pub McfgError
+    TooShort{len: usize} = "table of {len} bytes is too short for an MCFG table",
+    InvalidSignature = "table signature is not MCFG",
+    InvalidLength{len: usize} = "table length field ({len}) does not match the table",
+    InvalidChecksum = "table checksum is invalid",
+    InvalidBusRange{index: usize} = "allocation entry {index} ends before it starts",
+}
+
+/// Size of the ACPI system description table header.
+const HEADER_SIZE: usize = 36;
+
+/// Size of the header plus the 8 reserved bytes preceding the entries.
+const ENTRIES_OFFSET: usize = HEADER_SIZE + 8;
+
+/// Size of a configuration space base address allocation entry.
+const ENTRY_SIZE: usize = 16;
+
+/// A configuration space base address allocation entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// Base address of the ECAM region, this corresponds to bus 0 even if the
+    /// entry starts at a later bus.
+    pub base_address: u64,
+    /// The PCI segment group.
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl McfgEntry {
+    /// Physical address of the config space of `start_bus`.
+    pub fn window_address(&self) -> PAddr {
+        PAddr::from(self.base_address + self.start_bus as u64 * BUS_CONFIG_SIZE as u64)
+    }
+
+    /// Size of the ECAM region for `start_bus..=end_bus` (0 if the range is
+    /// empty).
+    pub fn window_size(&self) -> usize {
+        (self.end_bus as usize + 1).saturating_sub(self.start_bus as usize) * BUS_CONFIG_SIZE
+    }
+}
+
+/// A parsed MCFG table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mcfg {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub entries: Vec<McfgEntry>,
+}
+
+impl Mcfg {
+    /// Parses an MCFG table (including its ACPI header), e.g., as read from
+    /// `/sys/firmware/acpi/tables/MCFG`.
+    pub fn parse(data: &[u8]) -> Result<Mcfg, McfgError> {
+        if data.len() < ENTRIES_OFFSET {
+            return Err(McfgError::TooShort { len: data.len() });
+        }
+        if &data[0..4] != b"MCFG" {
+            return Err(McfgError::InvalidSignature);
+        }
+
+        let len = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        if len > data.len() || len < ENTRIES_OFFSET || (len - ENTRIES_OFFSET) % ENTRY_SIZE != 0 {
+            return Err(McfgError::InvalidLength { len });
+        }
+        let data = &data[..len];
+        if data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(McfgError::InvalidChecksum);
+        }
+
+        let mut entries = Vec::with_capacity((len - ENTRIES_OFFSET) / ENTRY_SIZE);
+        for (index, raw) in data[ENTRIES_OFFSET..].chunks_exact(ENTRY_SIZE).enumerate() {
+            let entry = McfgEntry {
+                base_address: u64::from_le_bytes(raw[0..8].try_into().unwrap()),
+                segment: u16::from_le_bytes(raw[8..10].try_into().unwrap()),
+                start_bus: raw[10],
+                end_bus: raw[11],
+            };
+            if entry.end_bus < entry.start_bus {
+                return Err(McfgError::InvalidBusRange { index });
+            }
+            entries.push(entry);
+        }
+
+        Ok(Mcfg {
+            revision: data[8],
+            oem_id: data[10..16].try_into().unwrap(),
+            oem_table_id: data[16..24].try_into().unwrap(),
+            oem_revision: u32::from_le_bytes(data[24..28].try_into().unwrap()),
+            entries,
+        })
+    }
+
+    /// Creates ECAM config space access for all entries of the table.
+    ///
+    /// `map` is called for every entry with the physical address and size of
+    /// its window and returns the virtual address it is mapped at.
+    ///
+    /// Fails without mapping anything if an entry has an empty bus range.
+    ///
+    /// # Safety
+    /// - `map` must map the whole region as device memory, and the mapping
+    ///   must stay valid for as long as the returned `EcamAccess` is in use.
+    pub unsafe fn ecam_access(
+        &self,
+        map: &dyn Fn(PAddr, usize) -> VAddr,
+    ) -> Result<EcamAccess, PciError> {
+        for entry in self.entries.iter() {
+            check_bus_range(entry.start_bus, entry.end_bus)?;
+        }
+        let windows = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let base = map(entry.window_address(), entry.window_size());
+                EcamWindow::new(base, entry.segment, entry.start_bus, entry.end_bus)
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(EcamAccess::new(windows))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    /// Builds an MCFG table with a valid checksum.
+    fn table(entries: &[(u64, u16, u8, u8)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"MCFG");
+        data.extend_from_slice(&((ENTRIES_OFFSET + entries.len() * ENTRY_SIZE) as u32).to_le_bytes());
+        data.push(1); // revision
+        data.push(0); // checksum
+        data.extend_from_slice(b"BOCHS ");
+        data.extend_from_slice(b"BXPC    ");
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(b"BXPC");
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&[0; 8]);
+        for (base, segment, start, end) in entries {
+            data.extend_from_slice(&base.to_le_bytes());
+            data.extend_from_slice(&segment.to_le_bytes());
+            data.extend_from_slice(&[*start, *end, 0, 0, 0, 0]);
+        }
+        let sum = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        data[9] = 0u8.wrapping_sub(sum);
+        data
+    }
+
+    #[test]
+    fn parse_firecracker() {
+        let mcfg = Mcfg::parse(include_bytes!("fixtures/mcfg-firecracker.bin")).unwrap();
+        assert_eq!(&mcfg.oem_id, b"FIRECK");
+        assert_eq!(
+            mcfg.entries,
+            vec![McfgEntry {
+                base_address: 0xeec0_0000,
+                segment: 0,
+                start_bus: 0,
+                end_bus: 0,
+            }]
+        );
+        assert_eq!(mcfg.entries[0].window_size(), BUS_CONFIG_SIZE);
+    }
+
+    #[test]
+    fn parse_multiple_segments() {
+        let data = table(&[(0xb000_0000, 0, 0, 0xff), (0x3_8000_0000, 1, 0x80, 0x81)]);
+        let mcfg = Mcfg::parse(&data).unwrap();
+        assert_eq!(mcfg.entries.len(), 2);
+
+        let second = mcfg.entries[1];
+        assert_eq!(second.segment, 1);
+        assert_eq!(second.window_address(), PAddr::from(0x3_8800_0000u64));
+        assert_eq!(second.window_size(), 2 * BUS_CONFIG_SIZE);
+
+        let mapped = RefCell::new(Vec::new());
+        let ecam = unsafe {
+            mcfg.ecam_access(&|paddr, size| {
+                mapped.borrow_mut().push((paddr.as_u64(), size));
+                VAddr::from(paddr.as_u64())
+            })
+        }
+        .unwrap();
+        assert_eq!(
+            mapped.into_inner(),
+            vec![(0xb000_0000, 256 * BUS_CONFIG_SIZE), (0x3_8800_0000, 2 * BUS_CONFIG_SIZE)]
+        );
+        assert_eq!(ecam.segments(), vec![0, 1]);
+        assert!(ecam
+            .window(crate::pci::PCIAddress::with_segment(1, 0x81, 0, 0).unwrap())
+            .is_some());
+
+        let mut broken = mcfg;
+        broken.entries[1].end_bus = 0x7f;
+        let mapped = RefCell::new(0);
+        let result = unsafe {
+            broken.ecam_access(&|paddr, _size| {
+                *mapped.borrow_mut() += 1;
+                VAddr::from(paddr.as_u64())
+            })
+        };
+        assert!(matches!(result, Err(PciError::InvalidBusRange { .. })));
+        assert_eq!(mapped.into_inner(), 0);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut data = table(&[(0xb000_0000, 0, 0, 0xff)]);
+        assert!(matches!(
+            Mcfg::parse(&data[..20]),
+            Err(McfgError::TooShort { len: 20 })
+        ));
+
+        data[20] ^= 0xff;
+        assert!(matches!(Mcfg::parse(&data), Err(McfgError::InvalidChecksum)));
+
+        let data = table(&[(0xb000_0000, 0, 0x10, 0x0f)]);
+        assert!(matches!(
+            Mcfg::parse(&data),
+            Err(McfgError::InvalidBusRange { index: 0 })
+        ));
+
+        let mut data = table(&[]);
+        data[0] = b'X';
+        assert!(matches!(Mcfg::parse(&data), Err(McfgError::InvalidSignature)));
+    }
+}
@@ -1,10 +1,27 @@
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::fmt;
+use core::str::FromStr;
 
 use bit_field::BitField;
+use custom_error::custom_error;
 
 use crate::arch::{PAddr, VAddr, PciInterface};
+use crate::mmio::MmioRegion;
+use crate::register::RegisterIo;
+
+use header::{BistStatus, PciHeader};
+use quirks::{DeviceIdentity, Quirk};
 
 pub mod device_db;
+pub mod dump;
+pub mod ecam;
+pub mod fdt;
+pub mod header;
+pub mod mcfg;
+pub mod quirks;
+pub mod rom;
 
 pub type VendorId = u16;
 pub type DeviceId = u16;
@@ -21,41 +38,240 @@ pub enum PciDeviceType {
     Unknown = 0xff,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+custom_error! {pub AddressParseError
+    InvalidFormat = "expected an address of the form [DDDD:]BB:DD.F",
+    InvalidNumber = "invalid hex number in PCI address",
+    OutOfRange = "device or function number out of range",
+}
+
+custom_error! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub PciError
+    InvalidAddress{dev: u8, fun: u8} = "device {dev} or function {fun} out of range",
+    UnknownHeaderType{header_type: u8} = "unsupported header type {header_type}",
+    InvalidBar{index: u8} = "BAR {index} does not exist for the header type",
+    MissingBar{index: u8} = "BAR {index} is not implemented",
+    ReservedBarType{index: u8} = "BAR {index} uses a reserved memory type",
+    MissingCapability = "the device does not have the capability",
+    CapabilityMismatch{offset: u8} = "unexpected capability at offset {offset}",
+    MsiBroken = "MSI and MSI-X are broken on the device",
+    InvalidMsiXTable = "the MSI-X table is not within its BAR",
+    BistNotSupported = "the device does not implement BIST",
+    InvalidBusRange{start_bus: u8, end_bus: u8} = "bus range {start_bus} to {end_bus} is empty",
+}
+
+/// The address of a PCI function.
+///
+/// Addresses are ordered by segment, bus, device and function.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct PCIAddress {
+    /// The PCI segment group (also known as domain), each segment has its own
+    /// set of 256 buses.
+    pub segment: u16,
     pub bus: u8,
     pub dev: u8,
     pub fun: u8,
 }
 
 impl PCIAddress {
-    fn new(bus: u8, dev: u8, fun: u8) -> Self {
-        assert!(dev <= 31);
-        assert!(fun <= 7);
+    pub fn new(bus: u8, dev: u8, fun: u8) -> Result<Self, PciError> {
+        PCIAddress::with_segment(0, bus, dev, fun)
+    }
+
+    pub fn with_segment(segment: u16, bus: u8, dev: u8, fun: u8) -> Result<Self, PciError> {
+        if dev > 31 || fun > 7 {
+            return Err(PciError::InvalidAddress { dev, fun });
+        }
 
-        //trace!("address ({:2}:{:2}.{:1})", bus, dev, fun);
-        PCIAddress { bus, dev, fun }
+        //trace!("address ({:04x}:{:02x}:{:02x}.{:x})", segment, bus, dev, fun);
+        Ok(PCIAddress {
+            segment,
+            bus,
+            dev,
+            fun,
+        })
     }
 
+    /// The value to program into `CONFIG_ADDRESS` for the legacy configuration
+    /// mechanism (which can only reach segment 0).
     pub fn addr(&self) -> u32 {
         (1 << 31) | ((self.bus as u32) << 16) | ((self.dev as u32) << 11) | ((self.fun as u32) << 8)
     }
 }
 
+/// Formats the address as `DDDD:BB:DD.F` (all numbers in hex).
+impl fmt::Display for PCIAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            self.segment, self.bus, self.dev, self.fun
+        )
+    }
+}
+
 impl fmt::Debug for PCIAddress {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:02}:{:02}.{}", self.bus, self.dev, self.fun)
+        fmt::Display::fmt(self, f)
     }
 }
 
-#[derive(Debug)]
-pub struct PCIHeader(PCIAddress);
+/// Parses `DDDD:BB:DD.F` or `BB:DD.F` (segment 0), all numbers in hex.
+impl FromStr for PCIAddress {
+    type Err = AddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.rsplitn(3, ':');
+        let (dev_fun, bus, segment) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(dev_fun), Some(bus), segment) => (dev_fun, bus, segment.unwrap_or("0")),
+            _ => return Err(AddressParseError::InvalidFormat),
+        };
+        let (dev, fun) = dev_fun
+            .split_once('.')
+            .ok_or(AddressParseError::InvalidFormat)?;
+
+        let hex = |s: &str| {
+            if s.is_empty() || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(AddressParseError::InvalidNumber);
+            }
+            u32::from_str_radix(s, 16).map_err(|_| AddressParseError::InvalidNumber)
+        };
+        let (segment, bus, dev, fun) = (hex(segment)?, hex(bus)?, hex(dev)?, hex(fun)?);
+        if segment > u16::MAX as u32 || bus > u8::MAX as u32 {
+            return Err(AddressParseError::InvalidNumber);
+        }
+        if dev > 31 || fun > 7 {
+            return Err(AddressParseError::OutOfRange);
+        }
+
+        PCIAddress::with_segment(segment as u16, bus as u8, dev as u8, fun as u8)
+            .map_err(|_| AddressParseError::OutOfRange)
+    }
+}
+
+/// Access to the configuration space of PCI functions.
+///
+/// By default the configuration mechanism of the platform is used (see
+/// [`ArchConfigAccess`]), other implementations can serve config space from
+/// e.g., Linux sysfs or a dump file.
+pub trait ConfigAccess {
+    /// Reads the dword at `offset` in the config space of function `addr`.
+    fn read(&self, addr: PCIAddress, offset: u32) -> u32;
+
+    /// Writes `value` to the dword at `offset` in the config space of function
+    /// `addr`.
+    fn write(&self, addr: PCIAddress, offset: u32, value: u32);
+
+    /// Reads the byte at `offset`.
+    fn read_u8(&self, addr: PCIAddress, offset: u32) -> u8 {
+        (self.read(addr, offset & !0b11) >> ((offset & 0b11) * 8)) as u8
+    }
+
+    /// Reads the word at `offset` (which must be 2-byte aligned).
+    fn read_u16(&self, addr: PCIAddress, offset: u32) -> u16 {
+        (self.read(addr, offset & !0b11) >> ((offset & 0b10) * 8)) as u16
+    }
+
+    /// Writes the byte at `offset`.
+    ///
+    /// The default implementation does a read-modify-write of the whole dword,
+    /// which also writes back the other bytes (and thereby clears any set
+    /// write-1-to-clear bits in them).
+    fn write_u8(&self, addr: PCIAddress, offset: u32, value: u8) {
+        let shift = (offset & 0b11) * 8;
+        let dword = self.read(addr, offset & !0b11) & !(0xff << shift);
+        self.write(addr, offset & !0b11, dword | (value as u32) << shift)
+    }
+
+    /// Writes the word at `offset` (which must be 2-byte aligned).
+    ///
+    /// See [`ConfigAccess::write_u8`] for the caveats of the default
+    /// implementation.
+    fn write_u16(&self, addr: PCIAddress, offset: u32, value: u16) {
+        let shift = (offset & 0b10) * 8;
+        let dword = self.read(addr, offset & !0b11) & !(0xffff << shift);
+        self.write(addr, offset & !0b11, dword | (value as u32) << shift)
+    }
+}
+
+/// Shared handle to a config space backend.
+pub type ConfigAccessRef = Arc<dyn ConfigAccess + Send + Sync>;
+
+/// Config space access through the platform's [`PciInterface`] (port IO on
+/// x86, the ECAM window configured with `set_ecam_window` on aarch64).
+///
+/// Only segment 0 is reachable, functions in other segments read as absent.
+/// On x86 the same goes for registers beyond the first 256 bytes, the port IO
+/// mechanism can't address them.
+/// Accesses are serialized by the platform (see `set_conf_lock_hooks` on
+/// x86) and sub-dword accesses only touch the addressed bytes.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ArchConfigAccess;
+
+impl ArchConfigAccess {
+    /// Can the platform access `offset` in the config space of `addr`?
+    fn reaches(addr: PCIAddress, offset: u32) -> bool {
+        addr.segment == 0 && (cfg!(not(target_arch = "x86_64")) || offset < 0x100)
+    }
+}
+
+impl ConfigAccess for ArchConfigAccess {
+    fn read(&self, addr: PCIAddress, offset: u32) -> u32 {
+        if !ArchConfigAccess::reaches(addr, offset) {
+            return u32::MAX;
+        }
+        PciInterface::read(&addr, offset)
+    }
+
+    fn write(&self, mut addr: PCIAddress, offset: u32, value: u32) {
+        if ArchConfigAccess::reaches(addr, offset) {
+            PciInterface::write(&mut addr, offset, value)
+        }
+    }
+
+    fn read_u8(&self, addr: PCIAddress, offset: u32) -> u8 {
+        if !ArchConfigAccess::reaches(addr, offset) {
+            return u8::MAX;
+        }
+        PciInterface::read_u8(&addr, offset)
+    }
+
+    fn read_u16(&self, addr: PCIAddress, offset: u32) -> u16 {
+        if !ArchConfigAccess::reaches(addr, offset) {
+            return u16::MAX;
+        }
+        PciInterface::read_u16(&addr, offset)
+    }
+
+    fn write_u8(&self, mut addr: PCIAddress, offset: u32, value: u8) {
+        if ArchConfigAccess::reaches(addr, offset) {
+            PciInterface::write_u8(&mut addr, offset, value)
+        }
+    }
+
+    fn write_u16(&self, mut addr: PCIAddress, offset: u32, value: u16) {
+        if ArchConfigAccess::reaches(addr, offset) {
+            PciInterface::write_u16(&mut addr, offset, value)
+        }
+    }
+}
+
+pub struct PCIHeader {
+    addr: PCIAddress,
+    access: ConfigAccessRef,
+}
 
 impl PCIHeader {
     pub fn new(bus: u8, device: u8, function: u8) -> Option<Self> {
-        let addr = PCIAddress::new(bus, device, function);
-        if PCIHeader::is_valid(addr) {
-            Some(PCIHeader(addr))
+        let addr = PCIAddress::new(bus, device, function).ok()?;
+        PCIHeader::with_access(addr, Arc::new(ArchConfigAccess))
+    }
+
+    /// Creates a header for `addr` whose config space is accessed through
+    /// `access`.
+    pub fn with_access(addr: PCIAddress, access: ConfigAccessRef) -> Option<Self> {
+        if access.read(addr, 0) != u32::MAX {
+            Some(PCIHeader { addr, access })
         } else {
             None
         }
@@ -64,6 +280,83 @@ impl PCIHeader {
     pub fn is_valid(addr: PCIAddress) -> bool {
         addr.read(0) != u32::MAX
     }
+
+    pub fn read(&self, offset: u32) -> u32 {
+        self.access.read(self.addr, offset)
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) {
+        self.access.write(self.addr, offset, value)
+    }
+
+    pub fn read_u8(&self, offset: u32) -> u8 {
+        self.access.read_u8(self.addr, offset)
+    }
+
+    pub fn read_u16(&self, offset: u32) -> u16 {
+        self.access.read_u16(self.addr, offset)
+    }
+
+    pub fn write_u8(&mut self, offset: u32, value: u8) {
+        self.access.write_u8(self.addr, offset, value)
+    }
+
+    pub fn write_u16(&mut self, offset: u32, value: u16) {
+        self.access.write_u16(self.addr, offset, value)
+    }
+}
+
+impl fmt::Debug for PCIHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("PCIHeader").field(&self.addr).finish()
+    }
+}
+
+/// A window into the config space of a function starting at `base` (e.g., a
+/// capability), to access it as a register block (see [`crate::register`]).
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigWindow<'a> {
+    header: &'a PCIHeader,
+    base: u32,
+}
+
+impl<'a> ConfigWindow<'a> {
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn offset(&self, offset: usize) -> u32 {
+        self.base + offset as u32
+    }
+}
+
+impl<'a> RegisterIo for ConfigWindow<'a> {
+    fn read8(&self, offset: usize) -> u8 {
+        self.header.read_u8(self.offset(offset))
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        self.header.read_u16(self.offset(offset))
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        self.header.read(self.offset(offset))
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        let header = self.header;
+        header.access.write_u8(header.addr, self.offset(offset), value)
+    }
+
+    fn write16(&self, offset: usize, value: u16) {
+        let header = self.header;
+        header.access.write_u16(header.addr, self.offset(offset), value)
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        let header = self.header;
+        header.access.write(header.addr, self.offset(offset), value)
+    }
 }
 
 /// # See also
@@ -97,7 +390,7 @@ impl From<u16> for ClassCode {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BarType {
     IO,
     Mem,
@@ -120,6 +413,20 @@ pub struct Bar {
     pub size: u64,
 }
 
+/// The Expansion ROM Base Address register of a function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExpansionRom {
+    pub address: u64,
+    pub size: u64,
+    /// Is address decode for the ROM enabled?
+    pub enabled: bool,
+}
+
+impl ExpansionRom {
+    /// Bits of the register holding the address (bit 0 is the enable bit).
+    const ADDRESS_MASK: u32 = 0xffff_f800;
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CapabilityId {
     /// Null Capability
@@ -273,26 +580,65 @@ pub struct MsiX<'s> {
     pub offset: u32,
 }
 
+crate::register_block! {
+    /// Registers of the MSI-X capability (offsets relative to the capability).
+    pub struct MsiXRegisters {
+        0x02 => message_control: MsiXMessageControl(u16), RW {
+            /// Table Size is N - 1 encoded, and is the number of entries in
+            /// the MSI-X table.
+            ///
+            /// This field is Read-Only.
+            TABLE_SIZE: 0..11,
+            FUNCTION_MASK: 14..15 as bool,
+            ENABLE: 15..16 as bool,
+        },
+        /// Location of the MSI-X table.
+        0x04 => table: MsiXTableLocation(u32), RO {
+            /// BIR specifies which BAR is used for the Message Table.
+            ///
+            /// This may be a 64-bit BAR, and is zero-indexed (so BIR=0, BAR0,
+            /// offset 0x10 into the header).
+            BIR: 0..3 as u8,
+            /// Offset into the BAR in units of 8 bytes.
+            OFFSET: 3..32,
+        },
+        /// Location of the pending bit array.
+        0x08 => pending_bit_array: MsiXPbaLocation(u32), RO {
+            BIR: 0..3 as u8,
+            OFFSET: 3..32,
+        },
+    }
+}
+
 impl<'s> MsiX<'s> {
+    /// The registers of the capability.
+    pub fn registers(&self) -> MsiXRegisters<ConfigWindow<'_>> {
+        MsiXRegisters::new(ConfigWindow {
+            header: &*self.header,
+            base: self.offset,
+        })
+    }
 
     pub fn message_control(&self) -> u16 {
-        (self.header.0.read(self.offset) >> 16) as u16
+        self.registers().message_control().read().0
     }
 
     pub fn enabled(&self) -> bool {
-        self.message_control().get_bit(15)
+        self.registers()
+            .message_control()
+            .get(MsiXMessageControl::ENABLE)
     }
 
     pub fn enable(&mut self) {
-        let ctrl = *self.message_control().set_bit(15, true);
-
-        let mut hdr = self.header.0.read(self.offset);
-        hdr = (hdr & 0xFFFF) | ((ctrl as u32) << 16);
-        self.header.0.write(self.offset, hdr);
+        self.registers()
+            .message_control()
+            .set(MsiXMessageControl::ENABLE, true)
     }
 
     pub fn function_mask(&self) -> bool {
-        self.message_control().get_bit(14)
+        self.registers()
+            .message_control()
+            .get(MsiXMessageControl::FUNCTION_MASK)
     }
 
     /// Table Size is N - 1 encoded, and is the number of entries in the MSI-X
@@ -300,7 +646,9 @@ impl<'s> MsiX<'s> {
     ///
     /// This field is Read-Only.
     pub fn table_size(&self) -> usize {
-        self.message_control().get_bits(0..10) as usize
+        self.registers()
+            .message_control()
+            .get(MsiXMessageControl::TABLE_SIZE) as usize
     }
 
     /// BIR specifies which BAR is used for the Message Table.
@@ -308,35 +656,39 @@ impl<'s> MsiX<'s> {
     /// This may be a 64-bit BAR, and is zero-indexed (so BIR=0, BAR0, offset
     /// 0x10 into the header).
     pub fn bir(&self) -> u8 {
-        (self.header.0.read(self.offset + 4) & 0b111) as u8
+        self.registers().table().get(MsiXTableLocation::BIR)
     }
 
     /// Table Offset is an offset into that BAR where the Message Table lives.
     ///
     /// Note that it is 8-byte aligned.
     pub fn table_offset(&self) -> u32 {
-        self.header.0.read(self.offset + 4) & !0b111
+        self.registers().table().get(MsiXTableLocation::OFFSET) << 3
     }
 
-
     /// BIR specifies which BAR is used for the Message Table.
     ///
     /// This may be a 64-bit BAR, and is zero-indexed (so BIR=0, BAR0, offset
     /// 0x10 into the header).
     pub fn pending_bit_bir(&self) -> u8 {
-        (self.header.0.read(self.offset + 8) & 0b111) as u8
+        self.registers()
+            .pending_bit_array()
+            .get(MsiXPbaLocation::BIR)
     }
 
     /// Table Offset is an offset into that BAR where the Message Table lives.
     ///
     /// Note that it is 8-byte aligned.
     pub fn pending_bit_table_offset(&self) -> u32 {
-        self.header.0.read(self.offset + 8) & !0b111
+        self.registers()
+            .pending_bit_array()
+            .get(MsiXPbaLocation::OFFSET)
+            << 3
     }
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct MsiXTableEntry {
     pub addr: u64,
@@ -356,24 +708,34 @@ pub struct Capability {
 pub struct CapabilitiesIter<'s> {
     header: &'s PCIHeader,
     next: u8,
+    /// Upper bound on the entries left to visit, guards against looping
+    /// capability lists on broken devices.
+    remaining: usize,
+}
+
+impl<'s> CapabilitiesIter<'s> {
+    /// The most capabilities that fit in the 192 bytes after the header.
+    const MAX_CAPABILITIES: usize = (256 - 0x40) / 4;
 }
 
 impl<'s> Iterator for CapabilitiesIter<'s> {
     type Item = Capability;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.next == 0 {
+        if self.next == 0 || self.remaining == 0 {
             return None;
         }
+        self.remaining -= 1;
 
-        let cap_header = self.header.0.read(self.next as u32);
+        let cap_header = self.header.read(self.next as u32);
         let id = CapabilityId::from(cap_header.get_bits(0..8) as u8);
         let cap = Capability {
             id,
             offset: self.next,
         };
 
-        self.next = cap_header.get_bits(8..16) as u8;
+        // The bottom two bits of the pointer are reserved.
+        self.next = cap_header.get_bits(8..16) as u8 & !0b11;
         Some(cap)
     }
 }
@@ -381,32 +743,76 @@ impl<'s> Iterator for CapabilitiesIter<'s> {
 #[derive(Debug)]
 pub struct PciDevice {
     header: PCIHeader,
+    /// BARs sized so far, see [`PciDevice::bar`].
+    bars: [Option<Result<Bar, PciError>>; 6],
+    /// The read-only ID registers, read once when the device is created.
+    identity: DeviceIdentity,
+    class: (BaseClass, SubClass, Interface),
+    header_type: HeaderType,
 }
 
 impl PciDevice {
     pub fn new(bus: u8, device: u8, function: u8) -> Option<Self> {
         let header = PCIHeader::new(bus, device, function);
-        header.map(|header| PciDevice { header })
+        header.map(PciDevice::from_header)
+    }
+
+    /// Creates a device for the function at `addr` whose config space is
+    /// accessed through `access`.
+    pub fn with_access(addr: PCIAddress, access: ConfigAccessRef) -> Option<Self> {
+        PCIHeader::with_access(addr, access).map(PciDevice::from_header)
+    }
+
+    fn from_header(header: PCIHeader) -> Self {
+        let ids = header.read(0x00);
+        let class = header.read(0x08);
+        let header_type = header.read(0x0c).get_bits(16..24) as HeaderType;
+        let subsystem = match header_type & 0x7f {
+            0x00 => header.read(0x2c),
+            _ => 0,
+        };
+
+        PciDevice {
+            header,
+            bars: [None; 6],
+            identity: DeviceIdentity {
+                vendor_id: ids.get_bits(0..16) as VendorId,
+                device_id: ids.get_bits(16..32) as DeviceId,
+                revision: class.get_bits(0..8) as DeviceRevision,
+                subsystem_vendor_id: subsystem.get_bits(0..16) as VendorId,
+                subsystem_id: subsystem.get_bits(16..32) as DeviceId,
+            },
+            class: (
+                class.get_bits(24..32) as BaseClass,
+                class.get_bits(16..24) as SubClass,
+                class.get_bits(8..16) as Interface,
+            ),
+            header_type,
+        }
     }
 
     pub fn pci_address(&self) -> PCIAddress {
-        self.header.0
+        self.header.addr
     }
 
     pub fn device_type(&self) -> PciDeviceType {
-        let header = self.header.0.read(0x0c);
-
-        match header.get_bits(16..23) as u8 {
+        match self.header_type & 0x7f {
             0x00 => PciDeviceType::Endpoint,
             0x01 => PciDeviceType::PciBridge,
             _ => PciDeviceType::Unknown,
         }
     }
 
-    pub fn get_cap_region_mut(&mut self, cap: Capability) -> CapabilityType {
+    /// Accessor for the registers of capability `cap` (as returned by
+    /// [`PciDevice::capabilities`]).
+    pub fn get_cap_region_mut(&mut self, cap: Capability) -> Result<CapabilityType, PciError> {
+        let id = self.header.read_u8(cap.offset as u32);
+        if cap.offset < 0x40 || CapabilityId::from(id) != cap.id {
+            return Err(PciError::CapabilityMismatch { offset: cap.offset });
+        }
         match cap.id {
-            CapabilityId::MsiX => CapabilityType::MsiX(MsiX { header: &mut self.header, offset: cap.offset as u32 }),
-            _ => unimplemented!(),
+            CapabilityId::MsiX => Ok(CapabilityType::MsiX(MsiX { header: &mut self.header, offset: cap.offset as u32 })),
+            id => Ok(CapabilityType::Unknown(id)),
         }
     }
 
@@ -416,7 +822,12 @@ impl PciDevice {
         })
     }
 
-    pub fn get_msix_irq_table_mut(&mut self, paddr_to_vaddr_conversion: &dyn Fn(PAddr) -> VAddr) -> Option<&mut [MsiXTableEntry]> {
+    pub fn get_msix_irq_table_mut(&mut self, paddr_to_vaddr_conversion: &dyn Fn(PAddr) -> VAddr) -> Result<&mut [MsiXTableEntry], PciError> {
+
+        if self.quirks().contains(&Quirk::BrokenMsi) {
+            log::warn!("MSI-X of {} is broken, not using it", self.header.addr);
+            return Err(PciError::MsiBroken);
+        }
 
         if let Some(mut msi) = self.get_msix_config() {
             log::info!("Device has MSI-X capability and it's {}", if msi.enabled() { "enabled" } else { "not enabled" });
@@ -430,112 +841,413 @@ impl PciDevice {
             let table_offset = msi.table_offset();
 
             let entries = msi.table_size() + 1;
-            let bar = self.bar(table_bar).unwrap();
-            let addr = paddr_to_vaddr_conversion(PAddr::from(bar.address + table_offset as u64));
+            let bar = self.bar(table_bar)?;
+            let base = paddr_to_vaddr_conversion(PAddr::from(bar.address));
+
+            // Safety: `paddr_to_vaddr_conversion` maps the whole BAR.
+            let region = unsafe { MmioRegion::new(base, bar.size as usize) };
+            if let Err(e) = region.array::<MsiXTableEntry>(table_offset as usize, entries) {
+                log::error!("MSI-X table of {} is invalid: {}", self.header.addr, e);
+                return Err(PciError::InvalidMsiXTable);
+            }
+            let addr = VAddr::from(base.as_usize() + table_offset as usize);
 
             // Safety:
             // - We're casting the part of the memory to a MSI-X table according to the spec
             // - It's just plain-old-data
             // - We have &mut self when giving out a mut reference to the table
-            // - Sanity check that we're within `bar`'s range (TODO)
-            // - Check that `addr` satisfies alignment for [MsiXTableEntry] (TODO)
+            // - The table is within `bar`'s range and aligned (checked above)
             let msix_table = unsafe { core::slice::from_raw_parts_mut(addr.as_mut_ptr::<MsiXTableEntry>(), entries) };
-            return Some(msix_table);
+            Ok(msix_table)
         } else {
-            return None;
+            Err(PciError::MissingCapability)
+        }
+    }
+
+    /// A window into config space starting at `base` (e.g., the offset of a
+    /// capability) to access it as a register block.
+    ///
+    /// The cached BARs are dropped if the window reaches them.
+    pub fn config_window(&mut self, base: u32) -> ConfigWindow<'_> {
+        if base < 0x28 {
+            self.refresh_bars();
+        }
+        ConfigWindow {
+            header: &self.header,
+            base,
         }
     }
 
+    /// Reads the dword at `offset` in the config space of the device.
+    pub fn read_config(&self, offset: u32) -> u32 {
+        self.header.read(offset)
+    }
+
+    /// Writes `value` to the dword at `offset` in the config space of the
+    /// device.
+    pub fn write_config(&mut self, offset: u32, value: u32) {
+        self.invalidate_bars(offset);
+        self.header.write(offset, value)
+    }
+
+    /// Reads the byte at `offset` in the config space of the device.
+    pub fn read_config_u8(&self, offset: u32) -> u8 {
+        self.header.read_u8(offset)
+    }
+
+    /// Reads the word at `offset` in the config space of the device.
+    pub fn read_config_u16(&self, offset: u32) -> u16 {
+        self.header.read_u16(offset)
+    }
+
+    /// Writes the byte at `offset` in the config space of the device.
+    pub fn write_config_u8(&mut self, offset: u32, value: u8) {
+        self.invalidate_bars(offset);
+        self.header.write_u8(offset, value)
+    }
+
+    /// Writes the word at `offset` in the config space of the device.
+    pub fn write_config_u16(&mut self, offset: u32, value: u16) {
+        self.invalidate_bars(offset);
+        self.header.write_u16(offset, value)
+    }
+
+    /// Drops the cached BARs if `offset` is within the BARs (e.g., when they
+    /// are reassigned).
+    fn invalidate_bars(&mut self, offset: u32) {
+        if (0x10..0x28).contains(&offset) {
+            self.refresh_bars();
+        }
+    }
+
+    /// Drops the cached BARs, they are sized again on the next
+    /// [`PciDevice::bar`]. Needed after the BARs were written through another
+    /// handle to the same config space.
+    pub fn refresh_bars(&mut self) {
+        self.bars = [None; 6];
+    }
+
+    /// Runs `f` with IO and memory space decode disabled in the command
+    /// register, so the device doesn't claim accesses to the bogus addresses
+    /// written to its BARs while sizing them.
+    fn with_decode_disabled<R>(&mut self, f: impl FnOnce(&mut PCIHeader) -> R) -> R {
+        let command = self.header.read_u16(0x04);
+        let decode = command & 0b11;
+        if decode != 0 {
+            self.header.write_u16(0x04, command & !0b11);
+        }
+        let result = f(&mut self.header);
+        if decode != 0 {
+            self.header.write_u16(0x04, command);
+        }
+        result
+    }
+
     pub fn vendor_id(&self) -> VendorId {
-        self.header.0.read(0x00) as VendorId
+        self.identity.vendor_id
     }
 
     pub fn device_id(&self) -> DeviceId {
-        self.header.0.read(0x02) as DeviceId
+        self.identity.device_id
+    }
+
+    /// Reads and decodes the whole (Type 0) header.
+    pub fn read_header(&self) -> Result<PciHeader, PciError> {
+        let mut regs = [0u32; 16];
+        for (i, reg) in regs.iter_mut().enumerate() {
+            *reg = self.header.read(i as u32 * 4);
+        }
+        PciHeader::decode(&regs)
+    }
+
+    /// State of the built-in self test.
+    pub fn bist_status(&self) -> Result<BistStatus, PciError> {
+        BistStatus::from_register(self.header.read_u8(0x0f)).ok_or(PciError::BistNotSupported)
+    }
+
+    /// Starts the built-in self test, poll [`PciDevice::bist_status`] for the
+    /// result.
+    ///
+    /// The device may not respond to other accesses while the test runs.
+    pub fn start_bist(&mut self) -> Result<(), PciError> {
+        let bist = self.header.read_u8(0x0f);
+        if bist & BistStatus::CAPABLE == 0 {
+            return Err(PciError::BistNotSupported);
+        }
+        self.header.write_u8(0x0f, bist | BistStatus::START);
+        Ok(())
     }
 
     pub fn is_bus_master(&self) -> bool {
-        self.header.0.read(0x04).get_bit(2)
+        self.header.read(0x04).get_bit(2)
     }
 
     pub fn enable_bus_mastering(&mut self) {
-        let mut command = self.header.0.read(0x04);
+        let mut command = self.header.read_u16(0x04);
         command.set_bit(2, true);
-        self.header.0.write(0x04, command);
+        self.header.write_u16(0x04, command);
     }
 
-    pub fn bar(&mut self, index: u8) -> Option<Bar> {
-        match self.device_type() {
-            PciDeviceType::Endpoint => assert!(index < 6),
-            PciDeviceType::PciBridge => assert!(index < 2),
-            PciDeviceType::Unknown => return None,
+    /// Sets Interrupt Disable in the command register to mask legacy
+    /// interrupts.
+    ///
+    /// Returns `false` if the device is known to ignore the bit.
+    pub fn disable_intx(&mut self) -> bool {
+        let mut command = self.header.read_u16(0x04);
+        command.set_bit(10, true);
+        self.header.write_u16(0x04, command);
+        !self.quirks().contains(&Quirk::IgnoresIntxDisable)
+    }
+
+    /// Resets the function with a function level reset (FLR).
+    ///
+    /// `sleep_ms` is used to wait for the device to complete the reset (100
+    /// ms plus any [`Quirk::ResetDelay`]). Returns `false` if the device does
+    /// not (or not reliably) support FLR.
+    pub fn function_level_reset(&mut self, sleep_ms: &dyn Fn(u32)) -> bool {
+        let quirks = self.quirks();
+        if quirks.contains(&Quirk::NoFunctionLevelReset) {
+            return false;
+        }
+        let pcie = match self
+            .capabilities()
+            .find(|cap| cap.id == CapabilityId::PCIExpress)
+        {
+            Some(cap) => cap.offset as u32,
+            None => return false,
+        };
+        // Device Capabilities: Function Level Reset Capability
+        if !self.header.read(pcie + 0x04).get_bit(28) {
+            return false;
+        }
+
+        // Device Control: Initiate Function Level Reset
+        let control = self.header.read_u16(pcie + 0x08);
+        self.header.write_u16(pcie + 0x08, control | 1 << 15);
+
+        let extra_delay: u32 = quirks
+            .iter()
+            .map(|quirk| match quirk {
+                Quirk::ResetDelay(ms) => *ms,
+                _ => 0,
+            })
+            .sum();
+        sleep_ms(100 + extra_delay);
+        true
+    }
+
+    /// The identity of the device used to look up its quirks.
+    pub fn identity(&self) -> DeviceIdentity {
+        self.identity
+    }
+
+    /// The quirks registered for the device (see [`quirks::register`]).
+    pub fn quirks(&self) -> Vec<Quirk> {
+        quirks::lookup(&self.identity())
+    }
+
+    /// Decodes and sizes BAR `index`.
+    ///
+    /// For a 64-bit BAR `index` is that of the lower half. BARs are sized
+    /// (with decode disabled) on the first call only, later calls return
+    /// the cached result until the BARs are written with
+    /// [`PciDevice::write_config`] or a [`PciDevice::config_window`] covering
+    /// them, or [`PciDevice::refresh_bars`] is called.
+    pub fn bar(&mut self, index: u8) -> Result<Bar, PciError> {
+        if let Some(Some(bar)) = self.bars.get(index as usize) {
+            return *bar;
+        }
+        let bar = self.size_bar(index);
+        if let Some(cached) = self.bars.get_mut(index as usize) {
+            *cached = Some(bar);
+        }
+        bar
+    }
+
+    fn size_bar(&mut self, index: u8) -> Result<Bar, PciError> {
+        let count = match self.device_type() {
+            PciDeviceType::Endpoint => 6,
+            PciDeviceType::PciBridge => 2,
+            PciDeviceType::Unknown => {
+                let header_type = self.header.read_u8(0x0e) & 0x7f;
+                return Err(PciError::UnknownHeaderType { header_type });
+            }
+        };
+        if index >= count {
+            return Err(PciError::InvalidBar { index });
         }
 
         let offset = 0x10 + (index as u32) * 4;
-        let base = self.header.0.read(offset);
+        let base = self.header.read(offset);
         let bartype_is_io = base.get_bit(0);
+        let locatable = base.get_bits(1..3);
+        let is_64bit = !bartype_is_io && locatable == 2 && index + 1 < count;
+
+        let (size_encoded, next_bar, msb_size_encoded) = self.with_decode_disabled(|header| {
+            header.write(offset, u32::MAX);
+            let size_encoded = header.read(offset);
+            header.write(offset, base);
+
+            // Size for 64-bit Memory Space BARs:
+            let (next_bar, msb_size_encoded) = if is_64bit {
+                let next_bar = header.read(offset + 4);
+                header.write(offset + 4, u32::MAX);
+                let msb_size_encoded = header.read(offset + 4);
+                header.write(offset + 4, next_bar);
+                (next_bar, msb_size_encoded)
+            } else {
+                (0, 0)
+            };
+            (size_encoded, next_bar, msb_size_encoded)
+        });
 
-        if !bartype_is_io {
-            let locatable = base.get_bits(1..3);
-            let prefetchable = base.get_bit(3);
-
-            self.header.0.write(offset, u32::MAX);
-            let size_encoded = self.header.0.read(offset);
-            self.header.0.write(offset, base);
+        if size_encoded == 0x0 {
+            return Err(PciError::MissingBar { index });
+        }
 
-            if size_encoded == 0x0 {
-                return None;
+        if bartype_is_io {
+            // Devices may only decode 16 bits of IO space, the upper half of
+            // the register then reads as zero.
+            let mut mask = size_encoded & !0b11;
+            if mask >> 16 == 0 {
+                mask |= 0xffff_0000;
             }
+            return Ok(Bar {
+                region_type: BarType::IO,
+                prefetchable: false,
+                address: (base & !0b11) as u64,
+                size: (!mask).wrapping_add(1) as u64,
+            });
+        }
 
-            // To get the region size using BARs:
-            // - Clear lower 4 bits
-            // - Invert all all-bits
-            // - Add 1 to the result
-            // Ref: https://wiki.osdev.org/PCI#Base_Address_Registers
-            let (address, size) = {
-                match locatable {
-                    // 32-bit address
-                    0 => {
-                        let size = !(size_encoded & !0xF) + 1;
-                        ((base & 0xFFFF_FFF0) as u64, size as u64)
-                    }
-                    // 64-bit address
-                    2 => {
-                        let next_offset = offset + 4;
-                        let next_bar = self.header.0.read(next_offset);
-                        let address = (base & 0xFFFF_FFF0) as u64
-                            | (next_bar as u64 & (u32::MAX as u64)) << 32;
-
-                        // Size for 64-bit Memory Space BARs:
-                        self.header.0.write(next_offset, u32::MAX);
-                        let msb_size_encoded = self.header.0.read(next_offset);
-                        self.header.0.write(next_offset, next_bar);
-                        let size = (msb_size_encoded as u64) << 32 | size_encoded as u64;
-
-                        (address, (!(size & !0xF) + 1))
-                    }
-                    _ => unimplemented!("Unsupported locatable: {}", locatable),
+        let prefetchable = base.get_bit(3);
+
+        // To get the region size using BARs:
+        // - Clear lower 4 bits
+        // - Invert all all-bits
+        // - Add 1 to the result
+        // Ref: https://wiki.osdev.org/PCI#Base_Address_Registers
+        let (address, size) = {
+            match locatable {
+                // 32-bit address
+                0 => {
+                    let size = (!(size_encoded & !0xF)).wrapping_add(1);
+                    ((base & 0xFFFF_FFF0) as u64, size as u64)
                 }
-            };
+                // 64-bit address
+                2 if is_64bit => {
+                    let address = (base & 0xFFFF_FFF0) as u64 | (next_bar as u64) << 32;
+                    let size = (msb_size_encoded as u64) << 32 | size_encoded as u64;
 
-            Some(Bar {
-                region_type: bartype_is_io.into(),
-                prefetchable,
-                address,
-                size,
+                    (address, (!(size & !0xF)).wrapping_add(1))
+                }
+                // Reserved (or a 64-bit BAR in the last slot)
+                _ => return Err(PciError::ReservedBarType { index }),
+            }
+        };
+
+        let size = self
+            .quirks()
+            .iter()
+            .find_map(|quirk| match quirk {
+                Quirk::BarSize { index: i, size } if *i == index => Some(*size),
+                _ => None,
             })
-        } else {
-            unimplemented!("Unable to handle IO BARs")
+            .unwrap_or(size);
+
+        Ok(Bar {
+            region_type: BarType::Mem,
+            prefetchable,
+            address,
+            size,
+        })
+    }
+
+    /// Offset of the Expansion ROM Base Address register.
+    fn expansion_rom_offset(&self) -> Option<u32> {
+        match self.device_type() {
+            PciDeviceType::Endpoint => Some(0x30),
+            PciDeviceType::PciBridge => Some(0x38),
+            PciDeviceType::Unknown => None,
+        }
+    }
+
+    /// The expansion ROM of the device (`None` if it has none).
+    pub fn expansion_rom(&mut self) -> Option<ExpansionRom> {
+        let offset = self.expansion_rom_offset()?;
+        let base = self.header.read(offset);
+
+        let size_encoded = self.with_decode_disabled(|header| {
+            header.write(offset, ExpansionRom::ADDRESS_MASK);
+            let size_encoded = header.read(offset) & ExpansionRom::ADDRESS_MASK;
+            header.write(offset, base);
+            size_encoded
+        });
+
+        if size_encoded == 0x0 {
+            return None;
+        }
+
+        Some(ExpansionRom {
+            address: (base & ExpansionRom::ADDRESS_MASK) as u64,
+            size: (!size_encoded).wrapping_add(1) as u64,
+            enabled: base.get_bit(0),
+        })
+    }
+
+    /// Enables or disables address decode for the expansion ROM.
+    ///
+    /// The ROM is only accessible if memory space decode is enabled in the
+    /// command register as well.
+    pub fn set_expansion_rom_enabled(&mut self, enabled: bool) {
+        if let Some(offset) = self.expansion_rom_offset() {
+            let mut base = self.header.read(offset);
+            base.set_bit(0, enabled);
+            self.header.write(offset, base);
+        }
+    }
+
+    /// Copies the expansion ROM into memory (see [`rom::parse`] to parse it).
+    ///
+    /// Decode of the ROM (and memory space) is enabled while copying, and
+    /// restored to the previous state afterwards. Returns `None` if the device
+    /// has no ROM or it has not been assigned an address.
+    ///
+    /// # Safety
+    /// - `map` is called with the physical address and size of the ROM and
+    ///   must return a virtual address mapping the whole ROM as device memory.
+    /// - Some devices share the address decoder between the ROM and other
+    ///   BARs, these must not be accessed while copying.
+    pub unsafe fn read_expansion_rom(
+        &mut self,
+        map: &dyn Fn(PAddr, usize) -> VAddr,
+    ) -> Option<Vec<u8>> {
+        let rom = self.expansion_rom()?;
+        if rom.address == 0 {
+            return None;
         }
+
+        let command = self.header.read_u16(0x04);
+        self.header.write_u16(0x04, command | 1 << 1);
+        self.set_expansion_rom_enabled(true);
+
+        let base = map(PAddr::from(rom.address), rom.size as usize);
+        let data = (0..rom.size as usize)
+            .map(|i| core::ptr::read_volatile(base.as_ptr::<u8>().add(i)))
+            .collect();
+
+        self.set_expansion_rom_enabled(rom.enabled);
+        self.header.write_u16(0x04, command);
+        Some(data)
     }
 
     pub fn status(&self) -> u16 {
-        (self.header.0.read(0x4) >> 16)as u16
+        (self.header.read(0x4) >> 16)as u16
     }
 
     /// Offset to capability pointer
     pub fn capabilities_pointer(&self) -> Option<u8> {
-        let cap_ptr = self.header.0.read(0x34).get_bits(0..8) as u8;
+        let cap_ptr = self.header.read(0x34).get_bits(0..8) as u8 & !0b11;
         if self.status().get_bit(4) && cap_ptr != 0x0 {
             Some(cap_ptr)
         } else {
@@ -544,23 +1256,16 @@ impl PciDevice {
     }
 
     pub fn capabilities(&self) -> CapabilitiesIter {
-        self.capabilities_pointer().map_or(CapabilitiesIter {
-            header: &self.header,
-            next: 0x0
-        }, |cap_ptr| CapabilitiesIter {
+        CapabilitiesIter {
             header: &self.header,
-            next: cap_ptr
-        })
+            next: self.capabilities_pointer().unwrap_or(0x0),
+            remaining: CapabilitiesIter::MAX_CAPABILITIES,
+        }
     }
 
     pub fn revision_and_class(&self) -> (DeviceRevision, BaseClass, SubClass, Interface) {
-        let field = { self.header.0.read(0x08) };
-        (
-            field.get_bits(0..8) as DeviceRevision,
-            field.get_bits(24..32) as BaseClass,
-            field.get_bits(16..24) as SubClass,
-            field.get_bits(8..16) as Interface,
-        )
+        let (base_class, sub_class, interface) = self.class;
+        (self.identity.revision, base_class, sub_class, interface)
     }
 
     pub fn device_class(&self) -> ClassCode {
@@ -578,7 +1283,7 @@ impl PciDevice {
 impl fmt::Display for PciDevice {
     // This trait requires `fmt` with this exact signature.
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}: ", self.header.0)?;
+        write!(f, "{}: ", self.header.addr)?;
         if let Some(dev_info) = self.info() {
             write!(f, "{} {}", dev_info.vendor_name, dev_info.device_name)
         } else {
@@ -593,6 +1298,8 @@ impl fmt::Display for PciDevice {
 }
 
 pub struct PciDeviceIterator {
+    access: ConfigAccessRef,
+    segment: u16,
     bus: u8,
     device: u8,
     function: u8,
@@ -607,7 +1314,13 @@ impl Iterator for PciDeviceIterator {
         for bus in self.bus..=255 {
             for device in self.device..=31 {
                 for function in self.function..=7 {
-                    if let Some(pci_device) = PciDevice::new(bus, device, function) {
+                    let addr = PCIAddress {
+                        segment: self.segment,
+                        bus,
+                        dev: device,
+                        fun: function,
+                    };
+                    if let Some(pci_device) = PciDevice::with_access(addr, self.access.clone()) {
                         self.bus = bus;
                         self.device = device;
                         // Start with next function on next iteration
@@ -627,9 +1340,254 @@ impl Iterator for PciDeviceIterator {
 
 /// Scans the PCI bus addresses, returns vector of all
 pub fn scan_bus() -> PciDeviceIterator {
+    scan_bus_with(Arc::new(ArchConfigAccess))
+}
+
+/// Scans the PCI bus addresses using `access` to read config space.
+pub fn scan_bus_with(access: ConfigAccessRef) -> PciDeviceIterator {
+    scan_segment_with(access, 0)
+}
+
+/// Scans the bus addresses of PCI segment `segment` using `access` to read
+/// config space (e.g., for every segment of an [`ecam::EcamAccess`]).
+pub fn scan_segment_with(access: ConfigAccessRef, segment: u16) -> PciDeviceIterator {
     PciDeviceIterator {
+        access,
+        segment,
         bus: 0x0,
         device: 0x0,
         function: 0x0,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address() {
+        let addr: PCIAddress = "0001:3a:1f.7".parse().unwrap();
+        assert_eq!(addr, PCIAddress::with_segment(1, 0x3a, 0x1f, 7).unwrap());
+        assert_eq!(
+            "00:1f.3".parse::<PCIAddress>().unwrap(),
+            PCIAddress::new(0, 0x1f, 3).unwrap()
+        );
+        assert!(matches!(
+            PCIAddress::new(0, 0x20, 0),
+            Err(PciError::InvalidAddress { dev: 0x20, fun: 0 })
+        ));
+
+        assert!(matches!(
+            "00:1f".parse::<PCIAddress>(),
+            Err(AddressParseError::InvalidFormat)
+        ));
+        assert!(matches!(
+            "00:20.0".parse::<PCIAddress>(),
+            Err(AddressParseError::OutOfRange)
+        ));
+        assert!(matches!(
+            "100:00.0".parse::<PCIAddress>(),
+            Err(AddressParseError::InvalidNumber)
+        ));
+        assert!(matches!(
+            "0:0:0:0.0".parse::<PCIAddress>(),
+            Err(AddressParseError::InvalidNumber)
+        ));
+    }
+
+    #[test]
+    fn format_address() {
+        let addr = PCIAddress::with_segment(0x10, 0xab, 0x1f, 3).unwrap();
+        assert_eq!(format!("{}", addr), "0010:ab:1f.3");
+        assert_eq!(format!("{:?}", addr), "0010:ab:1f.3");
+        assert_eq!(format!("{}", addr).parse::<PCIAddress>().unwrap(), addr);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn arch_access_range() {
+        // Rejected before any port IO, which would fault in a test.
+        let addr = PCIAddress::new(0, 1, 0).unwrap();
+        assert!(!ArchConfigAccess::reaches(addr, 0x100));
+        assert!(ArchConfigAccess::reaches(addr, 0xfc));
+        assert_eq!(ArchConfigAccess.read(addr, 0x100), u32::MAX);
+        assert_eq!(ArchConfigAccess.read_u16(addr, 0xffe), u16::MAX);
+        ArchConfigAccess.write_u8(addr, 0x104, 0);
+        let other = PCIAddress::with_segment(1, 0, 0, 0).unwrap();
+        assert_eq!(ArchConfigAccess.read_u8(other, 0), u8::MAX);
+    }
+
+    #[test]
+    fn order_addresses() {
+        let mut addresses = [
+            PCIAddress::with_segment(1, 0, 0, 0).unwrap(),
+            PCIAddress::new(0, 2, 1).unwrap(),
+            PCIAddress::new(1, 0, 0).unwrap(),
+            PCIAddress::new(0, 2, 0).unwrap(),
+        ];
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            [
+                PCIAddress::new(0, 2, 0).unwrap(),
+                PCIAddress::new(0, 2, 1).unwrap(),
+                PCIAddress::new(1, 0, 0).unwrap(),
+                PCIAddress::with_segment(1, 0, 0, 0).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn bar_errors() {
+        let addr = PCIAddress::new(0, 3, 0).unwrap();
+        let mut config = vec![0u8; 64];
+        config[0..4].copy_from_slice(&0x100e_8086u32.to_le_bytes());
+        // BAR0: 32-bit memory, BAR1: IO, BAR3: reserved type, BAR5: 64-bit
+        // in the last slot.
+        for (index, value) in [(0, 0xfebc_0000u32), (1, 0xc001), (3, 0x2), (5, 0x4)] {
+            let offset = 0x10 + index * 4;
+            config[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        let mut dump = dump::ConfigDump::new();
+        dump.insert(addr, &config);
+        let mut dev = PciDevice::with_access(addr, Arc::new(dump)).unwrap();
+
+        // The dump keeps the all-ones written for sizing, so only check
+        // whether decoding succeeds.
+        assert_eq!(dev.bar(0).unwrap().region_type, BarType::Mem);
+        let io = dev.bar(1).unwrap();
+        assert_eq!((io.region_type, io.address, io.size), (BarType::IO, 0xc000, 4));
+        assert!(matches!(dev.bar(3), Err(PciError::ReservedBarType { index: 3 })));
+        assert!(matches!(dev.bar(5), Err(PciError::ReservedBarType { index: 5 })));
+        assert!(matches!(dev.bar(6), Err(PciError::InvalidBar { index: 6 })));
+    }
+
+    /// An endpoint with a 4 KiB 32-bit memory BAR0 that checks that it is
+    /// only sized with memory decode disabled.
+    struct SizedDevice {
+        regs: spin::Mutex<[u32; 16]>,
+        bar_writes: spin::Mutex<usize>,
+    }
+
+    impl ConfigAccess for SizedDevice {
+        fn read(&self, _addr: PCIAddress, offset: u32) -> u32 {
+            self.regs.lock()[offset as usize / 4]
+        }
+
+        fn write(&self, _addr: PCIAddress, offset: u32, value: u32) {
+            let mut regs = self.regs.lock();
+            if offset == 0x10 {
+                if value == u32::MAX {
+                    assert_eq!(regs[1] & 0b10, 0, "BAR sized with decode enabled");
+                }
+                *self.bar_writes.lock() += 1;
+                regs[4] = value & 0xffff_f000;
+            } else if offset == 0x04 {
+                regs[1] = value;
+            }
+        }
+    }
+
+    /// An endpoint with a 2 KiB expansion ROM.
+    struct RomDevice {
+        regs: spin::Mutex<[u32; 16]>,
+    }
+
+    impl ConfigAccess for RomDevice {
+        fn read(&self, _addr: PCIAddress, offset: u32) -> u32 {
+            self.regs.lock()[offset as usize / 4]
+        }
+
+        fn write(&self, _addr: PCIAddress, offset: u32, value: u32) {
+            let mut regs = self.regs.lock();
+            match offset {
+                0x04 => regs[1] = value,
+                0x30 => {
+                    if value & ExpansionRom::ADDRESS_MASK == ExpansionRom::ADDRESS_MASK {
+                        assert_eq!(regs[1] & 0b10, 0, "ROM sized with decode enabled");
+                    }
+                    regs[12] = value & 0xffff_f801;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn expansion_rom() {
+        let addr = PCIAddress::new(0, 4, 0).unwrap();
+        let mut regs = [0u32; 16];
+        regs[0] = 0x1000_1af4;
+        regs[1] = 0x0000_0007;
+        regs[2] = 0x0200_0000;
+        regs[12] = 0xfeb8_0000;
+        let access = Arc::new(RomDevice {
+            regs: spin::Mutex::new(regs),
+        });
+        let mut dev = PciDevice::with_access(addr, access.clone()).unwrap();
+
+        let rom = dev.expansion_rom().unwrap();
+        assert_eq!((rom.address, rom.size, rom.enabled), (0xfeb8_0000, 0x800, false));
+        assert_eq!(dev.read_config(0x30), 0xfeb8_0000);
+        assert_eq!(dev.read_config(0x04), 0x0000_0007);
+
+        let image: &[u8] = include_bytes!("fixtures/synthetic-virtio.rom");
+        let data = unsafe {
+            dev.read_expansion_rom(&|paddr, size| {
+                let regs = access.regs.lock();
+                assert_eq!((paddr.as_u64(), size), (0xfeb8_0000, 0x800));
+                assert!(regs[1] & 0b10 != 0 && regs[12] & 1 != 0, "ROM not decoded");
+                VAddr::from(image.as_ptr() as usize)
+            })
+        }
+        .unwrap();
+        assert_eq!(data, image);
+        assert_eq!(dev.read_config(0x30), 0xfeb8_0000);
+        assert_eq!(rom::parse_for(&data, 0x1af4, 0x1000).unwrap().len(), 2);
+
+        // A dump without the ROM register has no ROM:
+        let config: Vec<u8> = regs.iter().flat_map(|r| r.to_le_bytes()).collect();
+        let mut dump = dump::ConfigDump::new();
+        dump.insert(addr, &config[..0x30]);
+        let mut dev = PciDevice::with_access(addr, Arc::new(dump)).unwrap();
+        assert!(dev.expansion_rom().is_none());
+
+        // A ROM without an address is not read:
+        regs[12] = 0;
+        let access = Arc::new(RomDevice {
+            regs: spin::Mutex::new(regs),
+        });
+        let mut dev = PciDevice::with_access(addr, access).unwrap();
+        assert!(unsafe { dev.read_expansion_rom(&|_, _| unreachable!()) }.is_none());
+    }
+
+    #[test]
+    fn bar_sizing() {
+        let addr = PCIAddress::new(0, 3, 0).unwrap();
+        let mut regs = [0u32; 16];
+        regs[0] = 0x100e_8086;
+        regs[1] = 0x0000_0006;
+        regs[4] = 0xfebc_0000;
+        let access = Arc::new(SizedDevice {
+            regs: spin::Mutex::new(regs),
+            bar_writes: spin::Mutex::new(0),
+        });
+        let mut dev = PciDevice::with_access(addr, access.clone()).unwrap();
+
+        let bar = dev.bar(0).unwrap();
+        assert_eq!((bar.address, bar.size), (0xfebc_0000, 0x1000));
+        assert_eq!(dev.read_config(0x04), 0x0000_0006);
+        assert_eq!(*access.bar_writes.lock(), 2);
+
+        dev.bar(0).unwrap();
+        assert_eq!(*access.bar_writes.lock(), 2);
+        dev.write_config(0x10, 0xfebd_0000);
+        assert_eq!(dev.bar(0).unwrap().address, 0xfebd_0000);
+
+        dev.config_window(0x10).write32(0, 0xfebe_0000);
+        assert_eq!(dev.bar(0).unwrap().address, 0xfebe_0000);
+        access.write(addr, 0x10, 0xfebf_0000);
+        dev.refresh_bars();
+        assert_eq!(dev.bar(0).unwrap().address, 0xfebf_0000);
+    }
+}
@@ -0,0 +1,202 @@
+//! Fixups for devices that don't behave according to the specification.
+//!
+//! Quirks are registered against a [`DeviceMatch`] (vendor, device,
+//! revision, subsystem) and consulted by the `PciDevice` helpers, e.g.,
+//! `bar()` uses [`Quirk::BarSize`] instead of the size reported by the
+//! device, so drivers don't each have to know about every erratum.
+
+use alloc::vec::Vec;
+
+use spin::Mutex;
+
+use super::{DeviceId, DeviceRevision, VendorId};
+
+/// The identity of a function used to look up its quirks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub vendor_id: VendorId,
+    pub device_id: DeviceId,
+    pub revision: DeviceRevision,
+    pub subsystem_vendor_id: VendorId,
+    pub subsystem_id: DeviceId,
+}
+
+/// Which devices a quirk applies to, unset fields match any value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceMatch {
+    pub vendor_id: VendorId,
+    pub device_id: Option<DeviceId>,
+    /// Inclusive range of revisions.
+    pub revisions: Option<(DeviceRevision, DeviceRevision)>,
+    pub subsystem: Option<(VendorId, DeviceId)>,
+}
+
+impl DeviceMatch {
+    /// Matches all devices of a vendor.
+    pub const fn vendor(vendor_id: VendorId) -> DeviceMatch {
+        DeviceMatch {
+            vendor_id,
+            device_id: None,
+            revisions: None,
+            subsystem: None,
+        }
+    }
+
+    /// Matches all revisions of a device.
+    pub const fn device(vendor_id: VendorId, device_id: DeviceId) -> DeviceMatch {
+        DeviceMatch {
+            device_id: Some(device_id),
+            ..DeviceMatch::vendor(vendor_id)
+        }
+    }
+
+    /// Only matches revisions `first..=last`.
+    pub const fn revisions(self, first: DeviceRevision, last: DeviceRevision) -> DeviceMatch {
+        DeviceMatch {
+            revisions: Some((first, last)),
+            ..self
+        }
+    }
+
+    /// Only matches the given subsystem.
+    pub const fn subsystem(self, vendor_id: VendorId, device_id: DeviceId) -> DeviceMatch {
+        DeviceMatch {
+            subsystem: Some((vendor_id, device_id)),
+            ..self
+        }
+    }
+
+    pub fn matches(&self, id: &DeviceIdentity) -> bool {
+        self.vendor_id == id.vendor_id
+            && self.device_id.map_or(true, |d| d == id.device_id)
+            && self.revisions.map_or(true, |(first, last)| {
+                first <= id.revision && id.revision <= last
+            })
+            && self
+                .subsystem
+                .map_or(true, |s| s == (id.subsystem_vendor_id, id.subsystem_id))
+    }
+}
+
+/// A known deviation of a device from the specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quirk {
+    /// MSI and MSI-X don't work, legacy interrupts must be used.
+    BrokenMsi,
+    /// The device needs this many milliseconds in addition to the 100 ms
+    /// required by the specification before it responds after a reset.
+    ResetDelay(u32),
+    /// Function level reset hangs or is not implemented despite being
+    /// advertised.
+    NoFunctionLevelReset,
+    /// BAR `index` reports a wrong size, `size` is the correct one.
+    BarSize { index: u8, size: u64 },
+    /// Setting Interrupt Disable in the command register does not mask legacy
+    /// interrupts.
+    IgnoresIntxDisable,
+}
+
+/// A quirk together with the devices it applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuirkEntry {
+    pub device: DeviceMatch,
+    pub quirk: Quirk,
+}
+
+/// Quirks registered at runtime.
+static QUIRKS: Mutex<Vec<QuirkEntry>> = Mutex::new(Vec::new());
+
+/// Registers `quirk` for all devices matching `device`.
+pub fn register(device: DeviceMatch, quirk: Quirk) {
+    QUIRKS.lock().push(QuirkEntry { device, quirk });
+}
+
+/// Registers a table of quirks (e.g., the errata known to a driver).
+pub fn register_all(entries: &[QuirkEntry]) {
+    QUIRKS.lock().extend_from_slice(entries);
+}
+
+/// The quirks that apply to the device with identity `id`.
+pub fn lookup(id: &DeviceIdentity) -> Vec<Quirk> {
+    QUIRKS
+        .lock()
+        .iter()
+        .filter(|entry| entry.device.matches(id))
+        .map(|entry| entry.quirk)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn identity(device_id: DeviceId, revision: DeviceRevision) -> DeviceIdentity {
+        DeviceIdentity {
+            vendor_id: 0x1d0f,
+            device_id,
+            revision,
+            subsystem_vendor_id: 0x1d0f,
+            subsystem_id: 0x0001,
+        }
+    }
+
+    #[test]
+    fn match_and_lookup() {
+        let any = DeviceMatch::vendor(0x1d0f);
+        let device = DeviceMatch::device(0x1d0f, 0xec20);
+        let revision = device.revisions(0, 2);
+        let subsystem = device.subsystem(0x1d0f, 0x0002);
+
+        assert!(any.matches(&identity(0x1234, 9)));
+        assert!(device.matches(&identity(0xec20, 9)));
+        assert!(!device.matches(&identity(0xec21, 0)));
+        assert!(revision.matches(&identity(0xec20, 2)));
+        assert!(!revision.matches(&identity(0xec20, 3)));
+        assert!(!subsystem.matches(&identity(0xec20, 0)));
+
+        register_all(&[
+            QuirkEntry {
+                device: revision,
+                quirk: Quirk::BrokenMsi,
+            },
+            QuirkEntry {
+                device,
+                quirk: Quirk::ResetDelay(50),
+            },
+        ]);
+        assert_eq!(
+            lookup(&identity(0xec20, 1)),
+            vec![Quirk::BrokenMsi, Quirk::ResetDelay(50)]
+        );
+        assert_eq!(lookup(&identity(0xec20, 5)), vec![Quirk::ResetDelay(50)]);
+        assert!(lookup(&identity(0xec21, 1)).is_empty());
+    }
+
+    #[test]
+    fn consulted_by_device() {
+        use crate::pci::dump::ConfigDump;
+        use crate::pci::{PCIAddress, PciDevice};
+        use alloc::sync::Arc;
+
+        let addr = PCIAddress::new(0, 4, 0).unwrap();
+        let mut config = vec![0u8; 64];
+        config[0..4].copy_from_slice(&0x7e57_1af5u32.to_le_bytes());
+        config[0x10..0x14].copy_from_slice(&0xfebf_0000u32.to_le_bytes());
+        let mut dump = ConfigDump::new();
+        dump.insert(addr, &config);
+        let mut dev = PciDevice::with_access(addr, Arc::new(dump)).unwrap();
+
+        register(
+            DeviceMatch::device(0x1af5, 0x7e57),
+            Quirk::BarSize {
+                index: 0,
+                size: 0x4000,
+            },
+        );
+        register(DeviceMatch::vendor(0x1af5), Quirk::IgnoresIntxDisable);
+        assert_eq!(dev.bar(0).unwrap().size, 0x4000);
+        assert!(!dev.disable_intx());
+        assert!(dev.read_config_u16(0x04) & 1 << 10 != 0);
+    }
+}
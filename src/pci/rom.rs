@@ -0,0 +1,377 @@
+//! Parser for PCI expansion (option) ROM images.
+//!
+//! A ROM consists of a chain of images, each starting with a 0x55AA
+//! signature and pointing to a PCI Data Structure ("PCIR") which describes
+//! the image (code type, length, whether it is the last one etc.).
+//!
+//! # See also
+//! - PCI Firmware Specification, Revision 3.2, Section 5.1
+//! - UEFI Specification, Section "EFI PCI Expansion Option ROMs"
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use custom_error::custom_error;
+
+use super::{DeviceId, VendorId};
+
+custom_error! {pub RomError
+    InvalidSignature{offset: usize} = "no ROM signature in image at offset {offset}",
+    InvalidDataStructure{offset: usize} = "invalid PCI data structure in image at offset {offset}",
+    Truncated{offset: usize} = "image at offset {offset} extends past the end of the ROM",
+    NoImages = "ROM contains no images",
+    IdMismatch{index: usize} = "image {index} is not for this device",
+}
+
+/// Signature at the start of every image.
+const ROM_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+/// Signature of the PCI data structure.
+const PCIR_SIGNATURE: &[u8; 4] = b"PCIR";
+
+/// Signature of the EFI image header.
+const EFI_SIGNATURE: u32 = 0x0ef1;
+
+/// Image lengths are given in units of 512 bytes.
+const IMAGE_UNIT: usize = 512;
+
+/// The type of code in an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeType {
+    /// Legacy x86 (PC-AT compatible) BIOS code, e.g., a VBIOS.
+    X86,
+    OpenFirmware,
+    HpPaRisc,
+    /// An EFI driver.
+    Efi,
+    Unknown(u8),
+}
+
+impl From<u8> for CodeType {
+    fn from(value: u8) -> CodeType {
+        match value {
+            0x00 => CodeType::X86,
+            0x01 => CodeType::OpenFirmware,
+            0x02 => CodeType::HpPaRisc,
+            0x03 => CodeType::Efi,
+            other => CodeType::Unknown(other),
+        }
+    }
+}
+
+/// The header of an EFI image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EfiHeader {
+    /// Size of the initialized part of the image (in bytes).
+    pub initialization_size: usize,
+    /// The PE/COFF subsystem (e.g., 0xb for a boot service driver).
+    pub subsystem: u16,
+    /// The PE/COFF machine type (e.g., 0x8664 for x64, 0xaa64 for aarch64).
+    pub machine_type: u16,
+    /// Non-zero if the driver is compressed.
+    pub compression_type: u16,
+    /// Offset of the PE/COFF image from the start of the ROM image.
+    pub image_offset: usize,
+}
+
+/// An image of an expansion ROM.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomImage {
+    /// Offset of the image in the ROM.
+    pub offset: usize,
+    /// Length of the image in bytes.
+    pub length: usize,
+    pub vendor_id: VendorId,
+    pub device_id: DeviceId,
+    /// Further device IDs supported by the image (PCI data structure revision
+    /// 3 and later).
+    pub device_list: Vec<DeviceId>,
+    /// Base class, sub class and programming interface.
+    pub class_code: (u8, u8, u8),
+    /// Revision of the PCI data structure.
+    pub revision: u8,
+    /// Revision of the code in the image.
+    pub code_revision: u16,
+    pub code_type: CodeType,
+    /// Is this the last image of the ROM?
+    pub last: bool,
+    /// The EFI image header if `code_type` is [`CodeType::Efi`].
+    pub efi: Option<EfiHeader>,
+}
+
+impl RomImage {
+    /// Is the image meant for the device with the given IDs?
+    pub fn matches(&self, vendor_id: VendorId, device_id: DeviceId) -> bool {
+        self.vendor_id == vendor_id
+            && (self.device_id == device_id || self.device_list.contains(&device_id))
+    }
+
+    /// The bytes of the image in `rom`.
+    pub fn data<'a>(&self, rom: &'a [u8]) -> &'a [u8] {
+        &rom[self.offset..self.offset + self.length]
+    }
+
+    /// The PE/COFF driver embedded in an EFI image.
+    pub fn efi_driver<'a>(&self, rom: &'a [u8]) -> Option<&'a [u8]> {
+        let efi = self.efi?;
+        let end = efi.initialization_size.min(self.length);
+        self.data(rom).get(efi.image_offset..end)
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// Parses the image starting at `offset` of `rom`.
+fn parse_image(rom: &[u8], offset: usize) -> Result<RomImage, RomError> {
+    let image = &rom[offset..];
+    if image.get(0..2) != Some(&ROM_SIGNATURE[..]) {
+        return Err(RomError::InvalidSignature { offset });
+    }
+
+    let invalid = RomError::InvalidDataStructure { offset };
+    let pcir_offset = u16_at(image, 0x18).ok_or(RomError::Truncated { offset })? as usize;
+    let pcir = image
+        .get(pcir_offset..pcir_offset + 0x18)
+        .ok_or(RomError::Truncated { offset })?;
+    if &pcir[0..4] != PCIR_SIGNATURE {
+        return Err(invalid);
+    }
+
+    let length = u16_at(pcir, 0x10).unwrap() as usize * IMAGE_UNIT;
+    if length == 0 {
+        return Err(invalid);
+    }
+    if length > image.len() {
+        return Err(RomError::Truncated { offset });
+    }
+    let image = &image[..length];
+
+    let revision = pcir[0x0c];
+    let mut device_list = Vec::new();
+    let list_offset = u16_at(pcir, 0x08).unwrap() as usize;
+    if revision >= 3 && list_offset != 0 {
+        let mut entry = pcir_offset + list_offset;
+        loop {
+            match u16_at(image, entry) {
+                Some(0) => break,
+                Some(id) => device_list.push(id),
+                None => return Err(invalid),
+            }
+            entry += 2;
+        }
+    }
+
+    let code_type = CodeType::from(pcir[0x14]);
+    let efi = match code_type {
+        CodeType::Efi => {
+            if u32_at(image, 0x04) != Some(EFI_SIGNATURE) {
+                return Err(invalid);
+            }
+            Some(EfiHeader {
+                initialization_size: u16_at(image, 0x02).unwrap() as usize * IMAGE_UNIT,
+                subsystem: u16_at(image, 0x08).unwrap(),
+                machine_type: u16_at(image, 0x0a).unwrap(),
+                compression_type: u16_at(image, 0x0c).unwrap(),
+                image_offset: u16_at(image, 0x16).unwrap() as usize,
+            })
+        }
+        _ => None,
+    };
+
+    Ok(RomImage {
+        offset,
+        length,
+        vendor_id: u16_at(pcir, 0x04).unwrap(),
+        device_id: u16_at(pcir, 0x06).unwrap(),
+        device_list,
+        class_code: (pcir[0x0f], pcir[0x0e], pcir[0x0d]),
+        revision,
+        code_revision: u16_at(pcir, 0x12).unwrap(),
+        code_type,
+        last: pcir[0x15] & 0x80 != 0,
+        efi,
+    })
+}
+
+/// Parses the chain of images in an expansion ROM (e.g., as read from
+/// [`super::PciDevice::read_expansion_rom`] or a ROM file).
+pub fn parse(rom: &[u8]) -> Result<Vec<RomImage>, RomError> {
+    let mut images: Vec<RomImage> = Vec::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        let image = parse_image(rom, offset)?;
+        offset += image.length;
+        let last = image.last;
+        images.push(image);
+        if last {
+            break;
+        }
+    }
+
+    if images.is_empty() {
+        return Err(RomError::NoImages);
+    }
+    Ok(images)
+}
+
+/// Like [`parse`], but fails if any image is not meant for the device with
+/// the given IDs.
+pub fn parse_for(
+    rom: &[u8],
+    vendor_id: VendorId,
+    device_id: DeviceId,
+) -> Result<Vec<RomImage>, RomError> {
+    let images = parse(rom)?;
+    match images
+        .iter()
+        .position(|image| !image.matches(vendor_id, device_id))
+    {
+        Some(index) => Err(RomError::IdMismatch { index }),
+        None => Ok(images),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Builds an image of `units` * 512 bytes.
+    fn image(units: u16, code_type: u8, last: bool, device_list: &[u16]) -> Vec<u8> {
+        let mut data = vec![0u8; units as usize * IMAGE_UNIT];
+        data[0..2].copy_from_slice(&ROM_SIGNATURE);
+        let pcir = 0x40;
+        data[0x18..0x1a].copy_from_slice(&(pcir as u16).to_le_bytes());
+        if code_type == 0x03 {
+            data[0x02..0x04].copy_from_slice(&units.to_le_bytes());
+            data[0x04..0x08].copy_from_slice(&EFI_SIGNATURE.to_le_bytes());
+            data[0x08..0x0a].copy_from_slice(&0x0bu16.to_le_bytes());
+            data[0x0a..0x0c].copy_from_slice(&0x8664u16.to_le_bytes());
+            data[0x16..0x18].copy_from_slice(&0x80u16.to_le_bytes());
+            data[0x80..0x82].copy_from_slice(b"MZ");
+        }
+
+        data[pcir..pcir + 4].copy_from_slice(PCIR_SIGNATURE);
+        data[pcir + 0x04..pcir + 0x06].copy_from_slice(&0x8086u16.to_le_bytes());
+        data[pcir + 0x06..pcir + 0x08].copy_from_slice(&0x1533u16.to_le_bytes());
+        if !device_list.is_empty() {
+            data[pcir + 0x08..pcir + 0x0a].copy_from_slice(&0x20u16.to_le_bytes());
+            for (i, id) in device_list.iter().enumerate() {
+                let entry = pcir + 0x20 + 2 * i;
+                data[entry..entry + 2].copy_from_slice(&id.to_le_bytes());
+            }
+        }
+        data[pcir + 0x0a..pcir + 0x0c].copy_from_slice(&0x1cu16.to_le_bytes());
+        data[pcir + 0x0c] = 3;
+        data[pcir + 0x0d..pcir + 0x10].copy_from_slice(&[0x00, 0x00, 0x02]);
+        data[pcir + 0x10..pcir + 0x12].copy_from_slice(&units.to_le_bytes());
+        data[pcir + 0x14] = code_type;
+        data[pcir + 0x15] = if last { 0x80 } else { 0 };
+        data
+    }
+
+    #[test]
+    fn parse_chain() {
+        let mut rom = image(2, 0x00, false, &[]);
+        rom.extend(image(4, 0x03, true, &[0x1536, 0x1537]));
+        // Padding after the last image is ignored:
+        rom.extend(vec![0xff; 1024]);
+
+        let images = parse(&rom).unwrap();
+        assert_eq!(images.len(), 2);
+
+        assert_eq!(images[0].code_type, CodeType::X86);
+        assert_eq!((images[0].offset, images[0].length), (0, 1024));
+        assert_eq!(images[0].class_code, (0x02, 0x00, 0x00));
+        assert!(!images[0].last);
+        assert!(images[0].efi.is_none());
+
+        let efi = &images[1];
+        assert_eq!(efi.code_type, CodeType::Efi);
+        assert_eq!((efi.offset, efi.length), (1024, 2048));
+        assert!(efi.last);
+        assert_eq!(efi.device_list, vec![0x1536, 0x1537]);
+        assert_eq!(efi.efi.unwrap().machine_type, 0x8664);
+        let driver = efi.efi_driver(&rom).unwrap();
+        assert_eq!(driver.len(), 2048 - 0x80);
+        assert_eq!(&driver[0..2], b"MZ");
+
+        assert!(parse_for(&rom, 0x8086, 0x1533).is_ok());
+        assert!(efi.matches(0x8086, 0x1537));
+        assert!(matches!(
+            parse_for(&rom, 0x8086, 0x1537),
+            Err(RomError::IdMismatch { index: 0 })
+        ));
+    }
+
+    #[test]
+    fn synthetic_virtio_fixture() {
+        // A synthetic 2 KiB ROM, not a real option ROM dump: a legacy and
+        // an x64 EFI image for a virtio-net device with the headers a
+        // combined iPXE ROM has, but placeholder code.
+        let rom = include_bytes!("fixtures/synthetic-virtio.rom");
+        let images = parse_for(rom, 0x1af4, 0x1000).unwrap();
+        assert_eq!(images.len(), 2);
+
+        let legacy = &images[0];
+        assert_eq!(legacy.code_type, CodeType::X86);
+        assert_eq!((legacy.offset, legacy.length), (0, 1024));
+        assert_eq!(legacy.class_code, (0x02, 0x00, 0x00));
+        assert!(!legacy.last);
+        // Legacy images are checksummed.
+        let sum = legacy.data(rom).iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        assert_eq!(sum, 0);
+
+        let efi = &images[1];
+        assert_eq!(efi.code_type, CodeType::Efi);
+        assert!(efi.last);
+        let header = efi.efi.unwrap();
+        assert_eq!((header.subsystem, header.machine_type), (0x0b, 0x8664));
+        assert_eq!(header.compression_type, 0);
+        assert_eq!(&efi.efi_driver(rom).unwrap()[0..2], b"MZ");
+
+        assert!(matches!(
+            parse_for(rom, 0x1af4, 0x1041),
+            Err(RomError::IdMismatch { index: 0 })
+        ));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(matches!(parse(&[]), Err(RomError::NoImages)));
+        assert!(matches!(
+            parse(&[0xff; 512]),
+            Err(RomError::InvalidSignature { offset: 0 })
+        ));
+
+        let rom = image(2, 0x00, true, &[]);
+        assert!(matches!(
+            parse(&rom[..600]),
+            Err(RomError::Truncated { offset: 0 })
+        ));
+
+        // Chain continues without a next image:
+        let mut rom = image(1, 0x00, false, &[]);
+        rom.extend(vec![0u8; 512]);
+        assert!(matches!(
+            parse(&rom),
+            Err(RomError::InvalidSignature { offset: 512 })
+        ));
+
+        let mut rom = image(1, 0x00, true, &[]);
+        rom[0x40] = b'X';
+        assert!(matches!(
+            parse(&rom),
+            Err(RomError::InvalidDataStructure { offset: 0 })
+        ));
+    }
+}
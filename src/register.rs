@@ -0,0 +1,553 @@
+//! Declarative definitions of device register blocks.
+//!
+//! A register block is declared with [`register_block!`](crate::register_block)
+//! and accessed through anything implementing [`RegisterIo`], e.g., an
+//! [`MmioRegion`] or a window into PCI config space
+//! ([`crate::pci::ConfigWindow`]):
+//!
+//! ```ignore
+//! driverkit::register_block! {
+//!     /// Registers of a NIC.
+//!     pub struct NicRegisters {
+//!         /// Device control.
+//!         0x00 => ctrl: Ctrl(u32), RW {
+//!             /// Link speed.
+//!             SPEED: 8..10 as Speed { Mbit10 = 0, Mbit100 = 1, Gbit1 = 2 },
+//!             RESET: 26..27 as bool,
+//!         },
+//!         /// Interrupt cause, bits are cleared by writing 1.
+//!         0x08 => icr: Icr(u32), RW1C {
+//!             LINK_STATUS_CHANGE: 2..3 as bool,
+//!         },
+//!     }
+//! }
+//!
+//! let regs = NicRegisters::new(bar.region());
+//! regs.ctrl().modify(|ctrl| ctrl.with(Ctrl::RESET, true));
+//! if regs.ctrl().get(Ctrl::SPEED) == Some(Speed::Gbit1) {
+//!     regs.icr().clear_field(Icr::LINK_STATUS_CHANGE);
+//! }
+//! ```
+//!
+//! For every register a value type (`Ctrl`) is defined which wraps the raw
+//! value and holds the bitfields as associated constants. Fields are either
+//! raw integers of the register width, any other [`FieldValue`] (such as
+//! `bool`), or an enumeration which is defined alongside.
+
+use core::marker::PhantomData;
+
+use crate::mmio::MmioRegion;
+
+/// Reads and writes of registers at byte offsets.
+pub trait RegisterIo {
+    fn read8(&self, offset: usize) -> u8;
+    fn read16(&self, offset: usize) -> u16;
+    fn read32(&self, offset: usize) -> u32;
+
+    /// Reads 64 bits, by default as two 32-bit reads (low dword first).
+    fn read64(&self, offset: usize) -> u64 {
+        self.read32(offset) as u64 | (self.read32(offset + 4) as u64) << 32
+    }
+
+    fn write8(&self, offset: usize, value: u8);
+    fn write16(&self, offset: usize, value: u16);
+    fn write32(&self, offset: usize, value: u32);
+
+    /// Writes 64 bits, by default as two 32-bit writes (low dword first).
+    fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+}
+
+impl<T: RegisterIo + ?Sized> RegisterIo for &T {
+    fn read8(&self, offset: usize) -> u8 {
+        (**self).read8(offset)
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        (**self).read16(offset)
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        (**self).read32(offset)
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        (**self).read64(offset)
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        (**self).write8(offset, value)
+    }
+
+    fn write16(&self, offset: usize, value: u16) {
+        (**self).write16(offset, value)
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        (**self).write32(offset, value)
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        (**self).write64(offset, value)
+    }
+}
+
+/// Accesses are volatile and panic if out of bounds.
+impl RegisterIo for MmioRegion {
+    fn read8(&self, offset: usize) -> u8 {
+        MmioRegion::read8(self, offset)
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        MmioRegion::read16(self, offset)
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        MmioRegion::read32(self, offset)
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        MmioRegion::read64(self, offset)
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        MmioRegion::write8(self, offset, value)
+    }
+
+    fn write16(&self, offset: usize, value: u16) {
+        MmioRegion::write16(self, offset, value)
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        MmioRegion::write32(self, offset, value)
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        MmioRegion::write64(self, offset, value)
+    }
+}
+
+/// The width of a register.
+pub trait Width: Copy {
+    fn read<IO: RegisterIo + ?Sized>(io: &IO, offset: usize) -> Self;
+    fn write<IO: RegisterIo + ?Sized>(io: &IO, offset: usize, value: Self);
+    fn to_u64(self) -> u64;
+    fn from_u64(value: u64) -> Self;
+}
+
+macro_rules! impl_width {
+    ($ty:ty, $read:ident, $write:ident) => {
+        impl Width for $ty {
+            fn read<IO: RegisterIo + ?Sized>(io: &IO, offset: usize) -> Self {
+                io.$read(offset)
+            }
+
+            fn write<IO: RegisterIo + ?Sized>(io: &IO, offset: usize, value: Self) {
+                io.$write(offset, value)
+            }
+
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+
+            fn from_u64(value: u64) -> Self {
+                value as $ty
+            }
+        }
+    };
+}
+
+impl_width!(u8, read8, write8);
+impl_width!(u16, read16, write16);
+impl_width!(u32, read32, write32);
+impl_width!(u64, read64, write64);
+
+/// The value of a register (generated by `register_block!`).
+pub trait RegisterValue: Copy {
+    type Raw: Width;
+
+    fn from_raw(raw: Self::Raw) -> Self;
+    fn raw(self) -> Self::Raw;
+}
+
+/// A type that can be stored in a bitfield.
+pub trait FieldValue: Sized {
+    /// The type a field is read as, this is an `Option` for enumerations
+    /// which don't cover all values of the field.
+    type Output;
+
+    fn from_field(raw: u64) -> Self::Output;
+    fn into_field(self) -> u64;
+}
+
+macro_rules! impl_field_value {
+    ($($ty:ty),*) => {
+        $(
+            impl FieldValue for $ty {
+                type Output = $ty;
+
+                fn from_field(raw: u64) -> $ty {
+                    raw as $ty
+                }
+
+                fn into_field(self) -> u64 {
+                    self as u64
+                }
+            }
+        )*
+    };
+}
+
+impl_field_value!(u8, u16, u32, u64, usize);
+
+impl FieldValue for bool {
+    type Output = bool;
+
+    fn from_field(raw: u64) -> bool {
+        raw != 0
+    }
+
+    fn into_field(self) -> u64 {
+        self as u64
+    }
+}
+
+/// A bitfield of type `V` in registers of type `R`.
+pub struct Field<R, V> {
+    shift: u32,
+    mask: u64,
+    _marker: PhantomData<(R, V)>,
+}
+
+impl<R, V> Clone for Field<R, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<R, V> Copy for Field<R, V> {}
+
+impl<R, V> core::fmt::Debug for Field<R, V> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Field({:#x} << {})", self.mask, self.shift)
+    }
+}
+
+impl<R, V: FieldValue> Field<R, V> {
+    /// A field spanning bits `lo..hi`.
+    pub const fn new(lo: u32, hi: u32) -> Self {
+        assert!(lo < hi && hi <= 64);
+        let width = hi - lo;
+        Field {
+            shift: lo,
+            mask: if width == 64 {
+                u64::MAX
+            } else {
+                (1 << width) - 1
+            },
+            _marker: PhantomData,
+        }
+    }
+
+    /// The bits of the field within the register.
+    pub fn mask(&self) -> u64 {
+        self.mask << self.shift
+    }
+
+    /// Extracts the field from a raw register value.
+    pub fn get(&self, raw: u64) -> V::Output {
+        V::from_field((raw >> self.shift) & self.mask)
+    }
+
+    /// Replaces the field in a raw register value with `value`.
+    pub fn set(&self, raw: u64, value: V) -> u64 {
+        (raw & !self.mask()) | (value.into_field() & self.mask) << self.shift
+    }
+}
+
+/// Register access types.
+pub trait Access {}
+
+/// Registers that can be read.
+pub trait Readable: Access {}
+
+/// Registers that can be written.
+pub trait Writable: Access {}
+
+/// Read-only registers.
+#[derive(Debug)]
+pub enum RO {}
+
+/// Read-write registers.
+#[derive(Debug)]
+pub enum RW {}
+
+/// Write-only registers.
+#[derive(Debug)]
+pub enum WO {}
+
+/// Registers whose bits are cleared by writing 1 to them (e.g., status or
+/// interrupt cause registers).
+#[derive(Debug)]
+pub enum RW1C {}
+
+impl Access for RO {}
+impl Access for RW {}
+impl Access for WO {}
+impl Access for RW1C {}
+
+impl Readable for RO {}
+impl Readable for RW {}
+impl Readable for RW1C {}
+impl Writable for RW {}
+impl Writable for WO {}
+
+/// A register of type `V` with access `A` at `offset` in `io`.
+pub struct Register<'a, IO: ?Sized, V, A> {
+    io: &'a IO,
+    offset: usize,
+    _marker: PhantomData<(V, A)>,
+}
+
+impl<'a, IO: RegisterIo + ?Sized, V: RegisterValue, A: Access> Register<'a, IO, V, A> {
+    pub fn new(io: &'a IO, offset: usize) -> Self {
+        Register {
+            io,
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a, IO: RegisterIo + ?Sized, V: RegisterValue, A: Readable> Register<'a, IO, V, A> {
+    pub fn read(&self) -> V {
+        V::from_raw(V::Raw::read(self.io, self.offset))
+    }
+
+    /// Reads the register and extracts `field`.
+    pub fn get<F: FieldValue>(&self, field: Field<V, F>) -> F::Output {
+        field.get(self.read().raw().to_u64())
+    }
+}
+
+impl<'a, IO: RegisterIo + ?Sized, V: RegisterValue, A: Writable> Register<'a, IO, V, A> {
+    pub fn write(&self, value: V) {
+        V::Raw::write(self.io, self.offset, value.raw())
+    }
+}
+
+impl<'a, IO: RegisterIo + ?Sized, V: RegisterValue> Register<'a, IO, V, RW> {
+    /// Reads the register, applies `f` and writes the result back.
+    pub fn modify<F: FnOnce(V) -> V>(&self, f: F) {
+        self.write(f(self.read()))
+    }
+
+    /// Sets `field` to `value`, leaving the other fields untouched.
+    pub fn set<F: FieldValue>(&self, field: Field<V, F>, value: F) {
+        let raw = field.set(self.read().raw().to_u64(), value);
+        self.write(V::from_raw(V::Raw::from_u64(raw)))
+    }
+}
+
+impl<'a, IO: RegisterIo + ?Sized, V: RegisterValue> Register<'a, IO, V, RW1C> {
+    /// Clears the bits set in `bits`.
+    pub fn clear(&self, bits: V) {
+        V::Raw::write(self.io, self.offset, bits.raw())
+    }
+
+    /// Clears all bits of `field`.
+    pub fn clear_field<F: FieldValue>(&self, field: Field<V, F>) {
+        V::Raw::write(self.io, self.offset, V::Raw::from_u64(field.mask()))
+    }
+}
+
+/// Declares a register block, see the [module documentation](crate::register).
+#[macro_export]
+macro_rules! register_block {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $block:ident {
+            $(
+                $(#[$reg_attr:meta])*
+                $offset:literal => $reg:ident : $value:ident($raw:ty), $access:ident {
+                    $(
+                        $(#[$field_attr:meta])*
+                        $field:ident : $lo:literal .. $hi:literal
+                            $(as $ty:ident $({ $($variant:ident = $discriminant:literal),* $(,)? })?)?
+                    ),* $(,)?
+                }
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $block<IO> {
+            io: IO,
+        }
+
+        // A register map is usually declared completely, even if a driver
+        // only uses parts of it:
+        #[allow(dead_code)]
+        impl<IO: $crate::register::RegisterIo> $block<IO> {
+            pub fn new(io: IO) -> Self {
+                $block { io }
+            }
+
+            pub fn io(&self) -> &IO {
+                &self.io
+            }
+
+            $(
+                $(#[$reg_attr])*
+                pub fn $reg(&self) -> $crate::register::Register<'_, IO, $value, $crate::register::$access> {
+                    $crate::register::Register::new(&self.io, $offset)
+                }
+            )*
+        }
+
+        $(
+            #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+            $vis struct $value(pub $raw);
+
+            impl $crate::register::RegisterValue for $value {
+                type Raw = $raw;
+
+                fn from_raw(raw: $raw) -> Self {
+                    $value(raw)
+                }
+
+                fn raw(self) -> $raw {
+                    self.0
+                }
+            }
+
+            #[allow(dead_code)]
+            impl $value {
+                $(
+                    $(#[$field_attr])*
+                    pub const $field: $crate::register::Field<$value, $crate::register_block!(@type $raw $(, $ty)?)> =
+                        $crate::register::Field::new($lo, $hi);
+                )*
+
+                /// Extracts `field` from the value.
+                pub fn get<V: $crate::register::FieldValue>(self, field: $crate::register::Field<Self, V>) -> V::Output {
+                    field.get(self.0 as u64)
+                }
+
+                /// The value with `field` replaced by `value`.
+                pub fn with<V: $crate::register::FieldValue>(self, field: $crate::register::Field<Self, V>, value: V) -> Self {
+                    $value(field.set(self.0 as u64, value) as $raw)
+                }
+            }
+
+            $(
+                $($(
+                    $crate::register_block!(@enum $vis $ty { $($variant = $discriminant),* });
+                )?)?
+            )*
+        )*
+    };
+
+    (@type $raw:ty) => { $raw };
+    (@type $raw:ty, $ty:ident) => { $ty };
+
+    (@enum $vis:vis $ty:ident { $($variant:ident = $discriminant:literal),* }) => {
+        #[allow(dead_code)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        $vis enum $ty {
+            $($variant = $discriminant),*
+        }
+
+        impl $crate::register::FieldValue for $ty {
+            type Output = Option<$ty>;
+
+            fn from_field(raw: u64) -> Option<$ty> {
+                match raw {
+                    $($discriminant => Some($ty::$variant),)*
+                    _ => None,
+                }
+            }
+
+            fn into_field(self) -> u64 {
+                self as u64
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::VAddr;
+    use alloc::vec;
+
+    crate::register_block! {
+        /// A made up device.
+        struct TestRegisters {
+            0x00 => ctrl: Ctrl(u32), RW {
+                SPEED: 8..10 as Speed { Mbit10 = 0, Mbit100 = 1, Gbit1 = 2 },
+                RESET: 26..27 as bool,
+                /// Raw field of the register width.
+                DELAY: 16..24,
+            },
+            0x04 => id: Id(u16), RO {
+                REVISION: 0..4 as u8,
+            },
+            0x08 => icr: Icr(u32), RW1C {
+                LINK: 2..3 as bool,
+                RX: 7..8 as bool,
+            },
+            0x10 => doorbell: Doorbell(u64), WO {},
+        }
+    }
+
+    #[test]
+    fn register_block_over_mmio() {
+        let mut mem = vec![0u64; 4];
+        let region = unsafe { MmioRegion::new(VAddr::from(mem.as_mut_ptr() as usize), 32) };
+        let regs = TestRegisters::new(region);
+
+        regs.ctrl()
+            .write(Ctrl::default().with(Ctrl::SPEED, Speed::Gbit1));
+        regs.ctrl().set(Ctrl::DELAY, 0x1ff);
+        regs.ctrl().modify(|ctrl| ctrl.with(Ctrl::RESET, true));
+        assert_eq!(region.read32(0x0), 1 << 26 | 0xff << 16 | 2 << 8);
+        assert_eq!(regs.ctrl().get(Ctrl::SPEED), Some(Speed::Gbit1));
+        assert!(regs.ctrl().get(Ctrl::RESET));
+        assert_eq!(regs.ctrl().read().get(Ctrl::DELAY), 0xff);
+
+        region.write32(0x0, 3 << 8);
+        assert_eq!(regs.ctrl().get(Ctrl::SPEED), None);
+
+        region.write16(0x4, 0x1234);
+        assert_eq!(regs.id().read(), Id(0x1234));
+        assert_eq!(regs.id().get(Id::REVISION), 4);
+
+        // Write-1-to-clear only writes the bits to clear:
+        region.write32(0x8, 0x84);
+        regs.icr().clear_field(Icr::RX);
+        assert_eq!(region.read32(0x8), 0x80);
+        regs.icr().clear(Icr::default().with(Icr::LINK, true));
+        assert_eq!(region.read32(0x8), 0x4);
+
+        regs.doorbell().write(Doorbell(0x1_0000_0002));
+        assert_eq!(mem[2], 0x1_0000_0002);
+        assert_eq!(regs.doorbell().offset(), 0x10);
+    }
+
+    #[test]
+    fn fields() {
+        let field: Field<Ctrl, u32> = Field::new(4, 8);
+        assert_eq!(field.mask(), 0xf0);
+        assert_eq!(field.get(0xabcd), 0xc);
+        assert_eq!(field.set(0xabcd, 0x1f), 0xabfd);
+
+        let full: Field<Ctrl, u64> = Field::new(0, 64);
+        assert_eq!(full.get(u64::MAX), u64::MAX);
+    }
+}
@@ -0,0 +1,674 @@
+//! Tracing of config space and MMIO accesses with record/replay.
+//!
+//! [`TracingConfigAccess`] and [`TracingRegisterIo`] wrap a config space
+//! backend or register IO and record every access to a [`Tracer`]. The
+//! recorded trace can be encoded to a compact binary log (see
+//! [`encode_log`]), e.g., to capture the init sequence of a driver on real
+//! hardware.
+//!
+//! [`ReplayConfigAccess`] and [`ReplayRegisterIo`] serve reads from such a
+//! log and panic if the driver deviates from it (different access or a
+//! different value written), which turns a captured trace into a regression
+//! test that does not need the device.
+
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::fmt;
+
+use custom_error::custom_error;
+use spin::Mutex;
+
+use crate::pci::{ConfigAccess, ConfigAccessRef, PCIAddress};
+use crate::register::RegisterIo;
+
+custom_error! {pub TraceError
+    InvalidLength{len: usize} = "log of {len} bytes is not a multiple of the record size",
+    InvalidRecord{index: usize} = "record {index} is malformed",
+}
+
+/// Source of timestamps for trace records.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// A clock that always returns 0 (for deterministic traces).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullClock;
+
+impl Clock for NullClock {
+    fn now(&self) -> u64 {
+        0
+    }
+}
+
+/// Nanoseconds since the clock was created.
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock(std::time::Instant);
+
+#[cfg(unix)]
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        MonotonicClock(std::time::Instant::now())
+    }
+}
+
+#[cfg(unix)]
+impl Clock for MonotonicClock {
+    fn now(&self) -> u64 {
+        self.0.elapsed().as_nanos() as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceOp {
+    Read,
+    Write,
+}
+
+/// What was accessed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceTarget {
+    /// The config space of a function.
+    Config(PCIAddress),
+    /// An MMIO region (identified by the id given to [`TracingRegisterIo`]).
+    Mmio(u32),
+}
+
+/// A recorded access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub op: TraceOp,
+    pub target: TraceTarget,
+    pub offset: u64,
+    /// Width of the access in bytes.
+    pub width: u8,
+    /// The value read or written.
+    pub value: u64,
+    pub timestamp: u64,
+}
+
+impl TraceRecord {
+    /// Size of an encoded record.
+    pub const SIZE: usize = 32;
+
+    const OP_WRITE: u8 = 1 << 0;
+    const TARGET_MMIO: u8 = 1 << 1;
+
+    /// Encodes the record in little endian:
+    ///
+    /// | Bytes  | Content                                               |
+    /// |--------|-------------------------------------------------------|
+    /// | 0      | bit 0: write, bit 1: MMIO (otherwise config space)    |
+    /// | 1      | width in bytes                                        |
+    /// | 2..4   | segment                                               |
+    /// | 4..8   | `bus << 8 \| dev << 3 \| fun`, or the MMIO region id  |
+    /// | 8..16  | offset                                                |
+    /// | 16..24 | value                                                 |
+    /// | 24..32 | timestamp                                             |
+    pub fn encode(&self) -> [u8; TraceRecord::SIZE] {
+        let mut flags = 0;
+        if self.op == TraceOp::Write {
+            flags |= TraceRecord::OP_WRITE;
+        }
+        let (segment, target) = match self.target {
+            TraceTarget::Config(addr) => (
+                addr.segment,
+                (addr.bus as u32) << 8 | (addr.dev as u32) << 3 | addr.fun as u32,
+            ),
+            TraceTarget::Mmio(id) => {
+                flags |= TraceRecord::TARGET_MMIO;
+                (0, id)
+            }
+        };
+
+        let mut data = [0u8; TraceRecord::SIZE];
+        data[0] = flags;
+        data[1] = self.width;
+        data[2..4].copy_from_slice(&segment.to_le_bytes());
+        data[4..8].copy_from_slice(&target.to_le_bytes());
+        data[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        data[16..24].copy_from_slice(&self.value.to_le_bytes());
+        data[24..32].copy_from_slice(&self.timestamp.to_le_bytes());
+        data
+    }
+
+    /// Decodes a record produced by [`TraceRecord::encode`].
+    pub fn decode(data: &[u8; TraceRecord::SIZE]) -> Option<TraceRecord> {
+        let flags = data[0];
+        let width = data[1];
+        if flags & !(TraceRecord::OP_WRITE | TraceRecord::TARGET_MMIO) != 0
+            || !matches!(width, 1 | 2 | 4 | 8)
+        {
+            return None;
+        }
+
+        let segment = u16::from_le_bytes(data[2..4].try_into().unwrap());
+        let target = u32::from_le_bytes(data[4..8].try_into().unwrap());
+        let target = if flags & TraceRecord::TARGET_MMIO != 0 {
+            TraceTarget::Mmio(target)
+        } else {
+            if target > 0xffff {
+                return None;
+            }
+            TraceTarget::Config(PCIAddress {
+                segment,
+                bus: (target >> 8) as u8,
+                dev: ((target >> 3) & 0x1f) as u8,
+                fun: (target & 0x7) as u8,
+            })
+        };
+
+        Some(TraceRecord {
+            op: if flags & TraceRecord::OP_WRITE != 0 {
+                TraceOp::Write
+            } else {
+                TraceOp::Read
+            },
+            target,
+            offset: u64::from_le_bytes(data[8..16].try_into().unwrap()),
+            width,
+            value: u64::from_le_bytes(data[16..24].try_into().unwrap()),
+            timestamp: u64::from_le_bytes(data[24..32].try_into().unwrap()),
+        })
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = match self.op {
+            TraceOp::Read => "read",
+            TraceOp::Write => "write",
+        };
+        match self.target {
+            TraceTarget::Config(addr) => write!(f, "[{}] {} config {}", self.timestamp, op, addr)?,
+            TraceTarget::Mmio(id) => write!(f, "[{}] {} mmio {}", self.timestamp, op, id)?,
+        }
+        write!(
+            f,
+            " +{:#x} ({} bytes) = {:#x}",
+            self.offset, self.width, self.value
+        )
+    }
+}
+
+/// Encodes records into a log (see [`TraceRecord::encode`]).
+pub fn encode_log(records: &[TraceRecord]) -> Vec<u8> {
+    records.iter().flat_map(|r| r.encode().to_vec()).collect()
+}
+
+/// Decodes a log produced by [`encode_log`].
+pub fn decode_log(data: &[u8]) -> Result<Vec<TraceRecord>, TraceError> {
+    if data.len() % TraceRecord::SIZE != 0 {
+        return Err(TraceError::InvalidLength { len: data.len() });
+    }
+    data.chunks_exact(TraceRecord::SIZE)
+        .enumerate()
+        .map(|(index, chunk)| {
+            TraceRecord::decode(chunk.try_into().unwrap())
+                .ok_or(TraceError::InvalidRecord { index })
+        })
+        .collect()
+}
+
+/// Collects the records of traced accesses.
+pub struct Tracer {
+    clock: Arc<dyn Clock + Send + Sync>,
+    records: Mutex<Vec<TraceRecord>>,
+}
+
+impl Tracer {
+    pub fn new(clock: Arc<dyn Clock + Send + Sync>) -> Tracer {
+        Tracer {
+            clock,
+            records: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn record(&self, op: TraceOp, target: TraceTarget, offset: u64, width: u8, value: u64) {
+        let timestamp = self.clock.now();
+        self.records.lock().push(TraceRecord {
+            op,
+            target,
+            offset,
+            width,
+            value,
+            timestamp,
+        });
+    }
+
+    /// The records so far.
+    pub fn records(&self) -> Vec<TraceRecord> {
+        self.records.lock().clone()
+    }
+
+    /// Removes and returns the records so far.
+    pub fn take(&self) -> Vec<TraceRecord> {
+        core::mem::take(&mut *self.records.lock())
+    }
+
+    /// The records so far as a log (see [`encode_log`]).
+    pub fn encode(&self) -> Vec<u8> {
+        encode_log(&self.records.lock())
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Tracer::new(Arc::new(NullClock))
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tracer({} records)", self.records.lock().len())
+    }
+}
+
+/// Config space access that records all accesses to a [`Tracer`].
+pub struct TracingConfigAccess {
+    inner: ConfigAccessRef,
+    tracer: Arc<Tracer>,
+}
+
+impl TracingConfigAccess {
+    pub fn new(inner: ConfigAccessRef, tracer: Arc<Tracer>) -> TracingConfigAccess {
+        TracingConfigAccess { inner, tracer }
+    }
+
+    fn record(&self, op: TraceOp, addr: PCIAddress, offset: u32, width: u8, value: u64) {
+        self.tracer
+            .record(op, TraceTarget::Config(addr), offset as u64, width, value)
+    }
+}
+
+impl ConfigAccess for TracingConfigAccess {
+    fn read(&self, addr: PCIAddress, offset: u32) -> u32 {
+        let value = self.inner.read(addr, offset);
+        self.record(TraceOp::Read, addr, offset, 4, value as u64);
+        value
+    }
+
+    fn write(&self, addr: PCIAddress, offset: u32, value: u32) {
+        self.record(TraceOp::Write, addr, offset, 4, value as u64);
+        self.inner.write(addr, offset, value)
+    }
+
+    fn read_u8(&self, addr: PCIAddress, offset: u32) -> u8 {
+        let value = self.inner.read_u8(addr, offset);
+        self.record(TraceOp::Read, addr, offset, 1, value as u64);
+        value
+    }
+
+    fn read_u16(&self, addr: PCIAddress, offset: u32) -> u16 {
+        let value = self.inner.read_u16(addr, offset);
+        self.record(TraceOp::Read, addr, offset, 2, value as u64);
+        value
+    }
+
+    fn write_u8(&self, addr: PCIAddress, offset: u32, value: u8) {
+        self.record(TraceOp::Write, addr, offset, 1, value as u64);
+        self.inner.write_u8(addr, offset, value)
+    }
+
+    fn write_u16(&self, addr: PCIAddress, offset: u32, value: u16) {
+        self.record(TraceOp::Write, addr, offset, 2, value as u64);
+        self.inner.write_u16(addr, offset, value)
+    }
+}
+
+/// Register IO (e.g., an MMIO region) that records all accesses to a
+/// [`Tracer`] under the region id `region`.
+pub struct TracingRegisterIo<IO> {
+    inner: IO,
+    region: u32,
+    tracer: Arc<Tracer>,
+}
+
+impl<IO: RegisterIo> TracingRegisterIo<IO> {
+    pub fn new(inner: IO, region: u32, tracer: Arc<Tracer>) -> TracingRegisterIo<IO> {
+        TracingRegisterIo {
+            inner,
+            region,
+            tracer,
+        }
+    }
+
+    fn record(&self, op: TraceOp, offset: usize, width: u8, value: u64) {
+        self.tracer.record(
+            op,
+            TraceTarget::Mmio(self.region),
+            offset as u64,
+            width,
+            value,
+        )
+    }
+}
+
+impl<IO: RegisterIo> RegisterIo for TracingRegisterIo<IO> {
+    fn read8(&self, offset: usize) -> u8 {
+        let value = self.inner.read8(offset);
+        self.record(TraceOp::Read, offset, 1, value as u64);
+        value
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        let value = self.inner.read16(offset);
+        self.record(TraceOp::Read, offset, 2, value as u64);
+        value
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        let value = self.inner.read32(offset);
+        self.record(TraceOp::Read, offset, 4, value as u64);
+        value
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        let value = self.inner.read64(offset);
+        self.record(TraceOp::Read, offset, 8, value);
+        value
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        self.record(TraceOp::Write, offset, 1, value as u64);
+        self.inner.write8(offset, value)
+    }
+
+    fn write16(&self, offset: usize, value: u16) {
+        self.record(TraceOp::Write, offset, 2, value as u64);
+        self.inner.write16(offset, value)
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        self.record(TraceOp::Write, offset, 4, value as u64);
+        self.inner.write32(offset, value)
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        self.record(TraceOp::Write, offset, 8, value);
+        self.inner.write64(offset, value)
+    }
+}
+
+/// Replays a recorded trace, accesses must happen in the recorded order.
+#[derive(Debug)]
+pub struct Replay {
+    records: Vec<TraceRecord>,
+    next: Mutex<usize>,
+}
+
+impl Replay {
+    pub fn new(records: Vec<TraceRecord>) -> Replay {
+        Replay {
+            records,
+            next: Mutex::new(0),
+        }
+    }
+
+    /// Creates a replay from a log produced by [`encode_log`].
+    pub fn from_log(data: &[u8]) -> Result<Replay, TraceError> {
+        decode_log(data).map(Replay::new)
+    }
+
+    /// Number of records that have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.records.len() - *self.next.lock()
+    }
+
+    /// Panics if not all records have been replayed.
+    pub fn finish(&self) {
+        let next = *self.next.lock();
+        if next < self.records.len() {
+            panic!(
+                "replay incomplete, {} accesses left, next: {}",
+                self.records.len() - next,
+                self.records[next]
+            );
+        }
+    }
+
+    /// Replays an access, panics if it does not match the next record.
+    ///
+    /// Returns the recorded value (the value read for reads).
+    fn replay(&self, op: TraceOp, target: TraceTarget, offset: u64, width: u8, value: u64) -> u64 {
+        let mut next = self.next.lock();
+        let index = *next;
+        let record = match self.records.get(index) {
+            Some(record) => record,
+            None => panic!(
+                "replay exhausted after {} accesses, unexpected {:?} of {:?} +{:#x}",
+                index, op, target, offset
+            ),
+        };
+
+        if record.op != op
+            || record.target != target
+            || record.offset != offset
+            || record.width != width
+            || (op == TraceOp::Write && record.value != value)
+        {
+            panic!(
+                "access {} does not match the trace: expected {}, got {:?} of {:?} +{:#x} ({} bytes) = {:#x}",
+                index, record, op, target, offset, width, value
+            );
+        }
+        *next += 1;
+        record.value
+    }
+}
+
+/// Config space access served from a [`Replay`].
+#[derive(Debug, Clone)]
+pub struct ReplayConfigAccess {
+    replay: Arc<Replay>,
+}
+
+impl ReplayConfigAccess {
+    pub fn new(replay: Arc<Replay>) -> ReplayConfigAccess {
+        ReplayConfigAccess { replay }
+    }
+
+    fn replay(&self, op: TraceOp, addr: PCIAddress, offset: u32, width: u8, value: u64) -> u64 {
+        self.replay
+            .replay(op, TraceTarget::Config(addr), offset as u64, width, value)
+    }
+}
+
+impl ConfigAccess for ReplayConfigAccess {
+    fn read(&self, addr: PCIAddress, offset: u32) -> u32 {
+        self.replay(TraceOp::Read, addr, offset, 4, 0) as u32
+    }
+
+    fn write(&self, addr: PCIAddress, offset: u32, value: u32) {
+        self.replay(TraceOp::Write, addr, offset, 4, value as u64);
+    }
+
+    fn read_u8(&self, addr: PCIAddress, offset: u32) -> u8 {
+        self.replay(TraceOp::Read, addr, offset, 1, 0) as u8
+    }
+
+    fn read_u16(&self, addr: PCIAddress, offset: u32) -> u16 {
+        self.replay(TraceOp::Read, addr, offset, 2, 0) as u16
+    }
+
+    fn write_u8(&self, addr: PCIAddress, offset: u32, value: u8) {
+        self.replay(TraceOp::Write, addr, offset, 1, value as u64);
+    }
+
+    fn write_u16(&self, addr: PCIAddress, offset: u32, value: u16) {
+        self.replay(TraceOp::Write, addr, offset, 2, value as u64);
+    }
+}
+
+/// Register IO of the MMIO region `region` served from a [`Replay`].
+#[derive(Debug, Clone)]
+pub struct ReplayRegisterIo {
+    replay: Arc<Replay>,
+    region: u32,
+}
+
+impl ReplayRegisterIo {
+    pub fn new(replay: Arc<Replay>, region: u32) -> ReplayRegisterIo {
+        ReplayRegisterIo { replay, region }
+    }
+
+    fn replay(&self, op: TraceOp, offset: usize, width: u8, value: u64) -> u64 {
+        self.replay.replay(
+            op,
+            TraceTarget::Mmio(self.region),
+            offset as u64,
+            width,
+            value,
+        )
+    }
+}
+
+impl RegisterIo for ReplayRegisterIo {
+    fn read8(&self, offset: usize) -> u8 {
+        self.replay(TraceOp::Read, offset, 1, 0) as u8
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        self.replay(TraceOp::Read, offset, 2, 0) as u16
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        self.replay(TraceOp::Read, offset, 4, 0) as u32
+    }
+
+    fn read64(&self, offset: usize) -> u64 {
+        self.replay(TraceOp::Read, offset, 8, 0)
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        self.replay(TraceOp::Write, offset, 1, value as u64);
+    }
+
+    fn write16(&self, offset: usize, value: u16) {
+        self.replay(TraceOp::Write, offset, 2, value as u64);
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        self.replay(TraceOp::Write, offset, 4, value as u64);
+    }
+
+    fn write64(&self, offset: usize, value: u64) {
+        self.replay(TraceOp::Write, offset, 8, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmio::MmioRegion;
+    use crate::pci::dump::ConfigDump;
+    use crate::pci::PciDevice;
+    use crate::VAddr;
+    use alloc::vec;
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    /// A clock that ticks on every access.
+    #[derive(Default)]
+    struct Ticks(AtomicU64);
+
+    impl Clock for Ticks {
+        fn now(&self) -> u64 {
+            self.0.fetch_add(1, Ordering::Relaxed)
+        }
+    }
+
+    /// A made up init sequence touching config space and MMIO.
+    fn init<IO: RegisterIo>(dev: &mut PciDevice, bar: &IO) {
+        assert_eq!(dev.vendor_id(), 0x8086);
+        dev.enable_bus_mastering();
+        bar.write32(0x0, 1 << 26);
+        while bar.read32(0x8) & 0x2 == 0 {
+            bar.write32(0x8, 0x2);
+        }
+        bar.write64(0x10, 0xdead_beef_0000);
+    }
+
+    fn record() -> Vec<u8> {
+        let addr = PCIAddress::new(0, 3, 0).unwrap();
+        let mut dump = ConfigDump::new();
+        dump.insert(addr, &[0x86, 0x80, 0x0e, 0x10, 0x00, 0x00, 0x00, 0x00]);
+
+        let tracer = Arc::new(Tracer::new(Arc::new(Ticks::default())));
+        let access = TracingConfigAccess::new(Arc::new(dump), tracer.clone());
+        let mut dev = PciDevice::with_access(addr, Arc::new(access)).unwrap();
+
+        let mut mem = vec![0u64; 4];
+        let region = unsafe { MmioRegion::new(VAddr::from(mem.as_mut_ptr() as usize), 32) };
+        init(&mut dev, &TracingRegisterIo::new(region, 0, tracer.clone()));
+
+        let records = tracer.records();
+        assert_eq!(
+            records[0],
+            TraceRecord {
+                op: TraceOp::Read,
+                target: TraceTarget::Config(addr),
+                offset: 0,
+                width: 4,
+                value: 0x100e_8086,
+                timestamp: 0,
+            }
+        );
+        assert!(records.iter().any(|r| r.target == TraceTarget::Mmio(0)
+            && r.op == TraceOp::Write
+            && r.width == 8
+            && r.value == 0xdead_beef_0000));
+        assert!(records.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+
+        tracer.encode()
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let log = record();
+        assert_eq!(log.len() % TraceRecord::SIZE, 0);
+
+        let replay = Arc::new(Replay::from_log(&log).unwrap());
+        let access = ReplayConfigAccess::new(replay.clone());
+        let mut dev = PciDevice::with_access(PCIAddress::new(0, 3, 0).unwrap(), Arc::new(access)).unwrap();
+        init(&mut dev, &ReplayRegisterIo::new(replay.clone(), 0));
+        replay.finish();
+    }
+
+    #[test]
+    #[should_panic(expected = "does not match the trace")]
+    fn replay_detects_wrong_write() {
+        let replay = Arc::new(Replay::from_log(&record()).unwrap());
+        let access = ReplayConfigAccess::new(replay.clone());
+        let mut dev = PciDevice::with_access(PCIAddress::new(0, 3, 0).unwrap(), Arc::new(access)).unwrap();
+        init(&mut dev, &ReplayRegisterIo::new(replay, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "got Write of Mmio(0) +0x0 (4 bytes) = 0x2000000")]
+    fn replay_detects_wrong_value() {
+        let replay = Arc::new(Replay::from_log(&record()).unwrap());
+        let access = ReplayConfigAccess::new(replay.clone());
+        let mut dev = PciDevice::with_access(PCIAddress::new(0, 3, 0).unwrap(), Arc::new(access)).unwrap();
+        assert_eq!(dev.vendor_id(), 0x8086);
+        dev.enable_bus_mastering();
+        // Same register as recorded, different value:
+        ReplayRegisterIo::new(replay, 0).write32(0x0, 1 << 25);
+    }
+
+    #[test]
+    fn log_errors() {
+        assert!(matches!(
+            decode_log(&[0; 33]),
+            Err(TraceError::InvalidLength { len: 33 })
+        ));
+        let mut log = record();
+        log[TraceRecord::SIZE + 1] = 3;
+        assert!(matches!(
+            decode_log(&log),
+            Err(TraceError::InvalidRecord { index: 1 })
+        ));
+    }
+}